@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::faults::FaultConfig;
+use crate::stream::{Listener, Stream};
+use crate::{request, response, routes, send_response, static_files, ProxyState};
+
+/// Serves the admin interface, which lets operators look at and adjust balancebeam while it is
+/// running. It speaks plain HTTP/1.1 and should only be bound to a trusted address:
+///
+/// * `GET /metrics` returns counters in the Prometheus text format
+/// * `POST /reload` re-reads the --config file
+/// * `GET /canary` returns the percentage of traffic going to the --canary upstreams, and
+///   `PUT /canary` (with the new percentage as the body) changes it
+/// * `GET /faults` returns the faults being injected into each route, as JSON.
+///   `PUT /faults?route=<name>` (with the route's new faults as the body, in the same form as in
+///   the config file) replaces them, and `DELETE /faults?route=<name>` goes back to the ones in the
+///   config file
+pub async fn serve(bind: String, state: ProxyState) {
+    let listener = match Listener::bind(&bind).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("Could not bind admin interface to {}: {}", bind, err);
+            std::process::exit(1);
+        }
+    };
+    log::info!("Admin interface listening on {}", bind);
+    loop {
+        if let Ok((stream, client_addrs)) = listener.accept().await {
+            let state = state.clone();
+            let client_ip = crate::stream::client_ip(client_addrs.as_ref());
+            tokio::spawn(async move {
+                handle_connection(stream, &client_ip, &state).await;
+            });
+        }
+    }
+}
+
+async fn handle_connection(mut client_conn: Stream, client_ip: &str, state: &ProxyState) {
+    loop {
+        let request = match request::read_from_stream(&mut client_conn, &state.limits).await {
+            Ok(request) => request,
+            Err(request::Error::IncompleteRequest(0)) => return,
+            Err(error) => {
+                log::debug!("Error reading admin request: {}", error);
+                let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+                send_response(&mut client_conn, client_ip, &response).await;
+                return;
+            }
+        };
+        log::info!(
+            "{} -> admin: {}",
+            client_ip,
+            request::format_request_line(&request)
+        );
+        let response = handle_request(&request, state).await;
+        send_response(&mut client_conn, client_ip, &response).await;
+    }
+}
+
+async fn handle_request(
+    request: &http::Request<Vec<u8>>,
+    state: &ProxyState,
+) -> http::Response<Vec<u8>> {
+    match (request.method(), request.uri().path()) {
+        (&http::Method::GET, "/metrics") => {
+            text_response(http::StatusCode::OK, state.metrics.render())
+        }
+        (&http::Method::POST, "/reload") => match routes::reload(state).await {
+            Ok(()) => text_response(http::StatusCode::OK, "Reloaded\n".to_string()),
+            Err(err) => text_response(
+                http::StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Could not reload config: {}\n", err),
+            ),
+        },
+        (&http::Method::GET, "/canary") => match &state.canary {
+            Some(split) => text_response(http::StatusCode::OK, format!("{}\n", split.percent())),
+            None => no_canary(),
+        },
+        (&http::Method::PUT, "/canary") => match &state.canary {
+            Some(split) => match std::str::from_utf8(request.body())
+                .ok()
+                .and_then(|body| body.trim().parse::<f64>().ok())
+                .filter(|percent| (0.0..=100.0).contains(percent))
+            {
+                Some(percent) => {
+                    split.set_percent(percent);
+                    log::info!("Now sending {}% of traffic to the canary pool", percent);
+                    text_response(http::StatusCode::OK, format!("{}\n", split.percent()))
+                }
+                None => text_response(
+                    http::StatusCode::BAD_REQUEST,
+                    "Expected a percentage between 0 and 100\n".to_string(),
+                ),
+            },
+            None => no_canary(),
+        },
+        (&http::Method::GET, "/faults") => {
+            let mut faults = BTreeMap::new();
+            for route in state.routes.read().await.iter() {
+                if let Some(route_faults) =
+                    state.faults.for_route(route.name(), route.faults.as_ref())
+                {
+                    faults.insert(route.name().to_string(), route_faults);
+                }
+            }
+            json_response(&faults)
+        }
+        (&http::Method::PUT, "/faults") => {
+            let route = match route_param(request, state).await {
+                Ok(route) => route,
+                Err(response) => return response,
+            };
+            match serde_json::from_slice::<FaultConfig>(request.body())
+                .map_err(|err| err.to_string())
+                .and_then(|faults| faults.validate().map(|_| faults))
+            {
+                Ok(faults) => {
+                    log::info!("Now injecting {:?} into route {}", faults, route);
+                    state.faults.set_override(&route, faults.clone());
+                    json_response(&faults)
+                }
+                Err(err) => text_response(
+                    http::StatusCode::BAD_REQUEST,
+                    format!("Invalid faults: {}\n", err),
+                ),
+            }
+        }
+        (&http::Method::DELETE, "/faults") => {
+            let route = match route_param(request, state).await {
+                Ok(route) => route,
+                Err(response) => return response,
+            };
+            if state.faults.clear_override(&route) {
+                log::info!("Back to the configured faults for route {}", route);
+            }
+            text_response(
+                http::StatusCode::OK,
+                format!("Using the configured faults for route {}\n", route),
+            )
+        }
+        (_, "/metrics" | "/reload" | "/canary" | "/faults") => {
+            response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED)
+        }
+        _ => response::make_http_error(http::StatusCode::NOT_FOUND),
+    }
+}
+
+/// Returns the name of the route given in the `route` query parameter, or the response to send if
+/// there is no such route.
+async fn route_param(
+    request: &http::Request<Vec<u8>>,
+    state: &ProxyState,
+) -> Result<String, http::Response<Vec<u8>>> {
+    let name = request
+        .uri()
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .find_map(|param| param.strip_prefix("route="))
+        .and_then(static_files::percent_decode)
+        .ok_or_else(|| {
+            text_response(
+                http::StatusCode::BAD_REQUEST,
+                "Expected a ?route= parameter\n".to_string(),
+            )
+        })?;
+    if !state
+        .routes
+        .read()
+        .await
+        .iter()
+        .any(|route| route.name() == name)
+    {
+        return Err(text_response(
+            http::StatusCode::NOT_FOUND,
+            format!("No route is named {}\n", name),
+        ));
+    }
+    Ok(name)
+}
+
+fn no_canary() -> http::Response<Vec<u8>> {
+    text_response(
+        http::StatusCode::NOT_FOUND,
+        "No --canary upstreams are configured\n".to_string(),
+    )
+}
+
+fn text_response(status: http::StatusCode, body: String) -> http::Response<Vec<u8>> {
+    http::Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain")
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body.into_bytes())
+        .unwrap()
+}
+
+fn json_response(value: &impl Serialize) -> http::Response<Vec<u8>> {
+    let mut response = text_response(
+        http::StatusCode::OK,
+        format!("{}\n", serde_json::to_string(value).unwrap()),
+    );
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/json"),
+    );
+    response
+}
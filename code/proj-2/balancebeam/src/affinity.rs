@@ -0,0 +1,161 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How requests are pinned to an upstream, so that stateful upstreams keep seeing the same
+/// clients.
+#[derive(Clone)]
+pub enum Affinity {
+    /// balancebeam hands out a cookie naming the upstream that served the client. The cookie is
+    /// signed, so clients can't use it to pick an arbitrary upstream.
+    Cookie { name: String, key: Vec<u8> },
+    /// Requests carrying the same value for this header are always sent to the same upstream.
+    Header { name: http::HeaderName },
+}
+
+impl Affinity {
+    /// Cookie affinity using the given signing secret, or a random one if none was configured (in
+    /// which case cookies stop being honored when balancebeam restarts). Returns None if the
+    /// cookie name is not valid.
+    pub fn cookie(name: String, secret: Option<String>) -> Option<Affinity> {
+        // Cookie names are tokens (RFC 6265, section 4.1.1): visible ASCII other than separators
+        let is_token_char =
+            |byte: u8| byte.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&byte);
+        if name.is_empty() || !name.bytes().all(is_token_char) {
+            return None;
+        }
+        let key = match secret {
+            Some(secret) => secret.into_bytes(),
+            None => {
+                let mut key = vec![0_u8; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                key
+            }
+        };
+        Some(Affinity::Cookie { name, key })
+    }
+
+    /// Header affinity, or None if the header name is not valid.
+    pub fn header(name: &str) -> Option<Affinity> {
+        let name = http::HeaderName::from_bytes(name.as_bytes()).ok()?;
+        Some(Affinity::Header { name })
+    }
+
+    /// Returns the upstream this request is pinned to, if it is pinned to one that is still alive.
+    /// Otherwise the caller should choose an upstream the usual way.
+    pub fn pinned_upstream<T>(
+        &self,
+        request: &http::Request<T>,
+        alive_upstreams: &HashSet<String>,
+    ) -> Option<String> {
+        match self {
+            Affinity::Cookie { name, key } => {
+                let upstream_ip = find_cookie(request, name)
+                    .and_then(|value| verify_cookie_value(key, &value))?;
+                if alive_upstreams.contains(&upstream_ip) {
+                    Some(upstream_ip)
+                } else {
+                    log::info!(
+                        "Session is pinned to {}, which is not alive; choosing another",
+                        upstream_ip
+                    );
+                    None
+                }
+            }
+            Affinity::Header { name } => {
+                let value = request.headers().get(name)?;
+                // Rendezvous hashing: when an upstream dies, only the keys that were pinned to it
+                // move elsewhere, and they move back once it recovers
+                alive_upstreams
+                    .iter()
+                    .max_by_key(|upstream_ip| {
+                        let mut hasher = DefaultHasher::new();
+                        value.as_bytes().hash(&mut hasher);
+                        upstream_ip.hash(&mut hasher);
+                        hasher.finish()
+                    })
+                    .cloned()
+            }
+        }
+    }
+
+    /// Pins the client to the upstream that served this request by adding a Set-Cookie header to
+    /// the response, unless the client already holds a cookie for that upstream.
+    pub fn pin_response<T, U>(
+        &self,
+        request: &http::Request<T>,
+        response: &mut http::Response<U>,
+        upstream_ip: &str,
+    ) {
+        if let Affinity::Cookie { name, key } = self {
+            let current =
+                find_cookie(request, name).and_then(|value| verify_cookie_value(key, &value));
+            if current.as_deref() == Some(upstream_ip) {
+                return;
+            }
+            let cookie = format!(
+                "{}={}; Path=/; HttpOnly",
+                name,
+                sign_cookie_value(key, upstream_ip)
+            );
+            response.headers_mut().append(
+                http::header::SET_COOKIE,
+                http::HeaderValue::from_str(&cookie).unwrap(),
+            );
+        }
+    }
+}
+
+/// Looks through the request's Cookie headers for a cookie with the given name.
+pub fn find_cookie<T>(request: &http::Request<T>, name: &str) -> Option<String> {
+    request
+        .headers()
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|header_value| header_value.to_str().ok())
+        .flat_map(|header_value| header_value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value.to_string())
+}
+
+/// Cookie values take the form "<upstream>.<hex HMAC-SHA256 of upstream>".
+fn sign_cookie_value(key: &[u8], upstream_ip: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(upstream_ip.as_bytes());
+    let signature: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("{}.{}", upstream_ip, signature)
+}
+
+/// Returns the upstream named by a cookie value, or None if the signature doesn't check out.
+fn verify_cookie_value(key: &[u8], value: &str) -> Option<String> {
+    let (upstream_ip, signature) = value.rsplit_once('.')?;
+    if signature.len() % 2 != 0 || !signature.is_ascii() {
+        return None;
+    }
+    let signature = (0..signature.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&signature[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .ok()?;
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(upstream_ip.as_bytes());
+    match mac.verify_slice(&signature) {
+        Ok(()) => Some(upstream_ip.to_string()),
+        Err(_) => {
+            log::debug!("Ignoring session cookie with a bad signature");
+            None
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+
+fn default_realm() -> String {
+    "balancebeam".to_string()
+}
+
+/// Credentials a route accepts. A request gets through if it satisfies any one of the configured
+/// schemes; the credentials are then removed so the upstream never sees them.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Realm named in WWW-Authenticate challenges
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    /// htpasswd file of users allowed in with basic auth. bcrypt and {SHA} hashes are supported.
+    #[serde(default)]
+    pub htpasswd: Option<String>,
+    /// Static bearer tokens that are allowed in
+    #[serde(default)]
+    pub bearer_tokens: Vec<String>,
+    /// Accept bearer tokens that are JWTs signed with this key
+    #[serde(default)]
+    pub jwt: Option<JwtConfig>,
+    /// Users loaded from the htpasswd file, mapped to their password hashes
+    #[serde(skip)]
+    users: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JwtConfig {
+    /// Shared secret for HS256, HS384 and HS512 signatures
+    pub secret: String,
+    /// If set, the token's "iss" claim must match
+    #[serde(default)]
+    pub issuer: Option<String>,
+    /// If set, the token's "aud" claim must include this
+    #[serde(default)]
+    pub audience: Option<String>,
+}
+
+/// Why a request was turned away. Both cases are answered with 401 Unauthorized; the distinction
+/// is only for logs, metrics and the error hint in the Bearer challenge.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// The request didn't carry credentials for any scheme the route accepts
+    MissingCredentials,
+    /// The request carried credentials, but they were wrong
+    InvalidCredentials,
+}
+
+impl AuthConfig {
+    /// Checks the realm and loads the htpasswd file, if there is one. Called whenever the config is
+    /// (re)loaded.
+    pub fn prepare(&mut self) -> Result<(), String> {
+        // The realm is sent as a quoted string in WWW-Authenticate, so it can only hold characters
+        // that are allowed in a header and don't need escaping there
+        let quotable =
+            |byte: u8| byte == b' ' || (byte.is_ascii_graphic() && !b"\"\\".contains(&byte));
+        if !self.realm.bytes().all(quotable) {
+            return Err(format!(
+                "realm {:?} may only contain printable ASCII other than '\"' and '\\'",
+                self.realm
+            ));
+        }
+        if let Some(path) = &self.htpasswd {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|err| format!("could not read {}: {}", path, err))?;
+            for line in contents.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let (user, hash) = line
+                    .split_once(':')
+                    .ok_or_else(|| format!("malformed line in {}", path))?;
+                if !(hash.starts_with("$2") || hash.starts_with("{SHA}")) {
+                    return Err(format!(
+                        "unsupported hash for user {:?} in {} (use bcrypt or {{SHA}})",
+                        user, path
+                    ));
+                }
+                self.users.insert(user.to_string(), hash.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the request's Authorization header against the configured schemes.
+    pub async fn check<T>(&self, request: &http::Request<T>) -> Result<(), Rejection> {
+        let authorization = match request
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+        {
+            Some(authorization) => authorization,
+            None => return Err(Rejection::MissingCredentials),
+        };
+        let (scheme, credentials) = authorization
+            .split_once(' ')
+            .ok_or(Rejection::InvalidCredentials)?;
+        let credentials = credentials.trim();
+        let accepted = if scheme.eq_ignore_ascii_case("basic") && self.htpasswd.is_some() {
+            self.check_basic(credentials).await
+        } else if scheme.eq_ignore_ascii_case("bearer")
+            && (!self.bearer_tokens.is_empty() || self.jwt.is_some())
+        {
+            self.bearer_tokens
+                .iter()
+                .any(|token| constant_time_eq(token.as_bytes(), credentials.as_bytes()))
+                || self
+                    .jwt
+                    .as_ref()
+                    .is_some_and(|jwt| verify_jwt(jwt, credentials))
+        } else {
+            // A scheme this route doesn't use counts as not having tried
+            return Err(Rejection::MissingCredentials);
+        };
+        if accepted {
+            Ok(())
+        } else {
+            Err(Rejection::InvalidCredentials)
+        }
+    }
+
+    async fn check_basic(&self, credentials: &str) -> bool {
+        let decoded = match STANDARD
+            .decode(credentials)
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+        {
+            Some(decoded) => decoded,
+            None => return false,
+        };
+        let (user, password) = match decoded.split_once(':') {
+            Some(split) => split,
+            None => return false,
+        };
+        match self.users.get(user) {
+            Some(hash) => match hash.strip_prefix("{SHA}") {
+                Some(expected) => {
+                    let actual = STANDARD.encode(Sha1::digest(password.as_bytes()));
+                    constant_time_eq(expected.as_bytes(), actual.as_bytes())
+                }
+                None => {
+                    // bcrypt is slow on purpose, so run it where it won't hold up the threads
+                    // serving everyone else's requests
+                    let (password, hash) = (password.to_string(), hash.clone());
+                    tokio::task::spawn_blocking(move || {
+                        bcrypt::verify(password, &hash).unwrap_or(false)
+                    })
+                    .await
+                    .unwrap_or(false)
+                }
+            },
+            None => false,
+        }
+    }
+
+    /// Builds the WWW-Authenticate challenges to send with a 401 response: one for each scheme
+    /// the route accepts.
+    pub fn challenges(&self, rejection: &Rejection) -> Vec<String> {
+        let mut challenges = Vec::new();
+        if self.htpasswd.is_some() {
+            challenges.push(format!("Basic realm=\"{}\"", self.realm));
+        }
+        if !self.bearer_tokens.is_empty() || self.jwt.is_some() {
+            challenges.push(match rejection {
+                Rejection::MissingCredentials => format!("Bearer realm=\"{}\"", self.realm),
+                Rejection::InvalidCredentials => {
+                    format!("Bearer realm=\"{}\", error=\"invalid_token\"", self.realm)
+                }
+            });
+        }
+        challenges
+    }
+}
+
+/// Compares two secrets without leaking how much of them matched through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Returns true if the token is a JWT with a valid HMAC signature whose claims are currently
+/// valid.
+fn verify_jwt(config: &JwtConfig, token: &str) -> bool {
+    let parts: Vec<&str> = token.split('.').collect();
+    let (header, claims, signature) = match parts[..] {
+        [header, claims, signature] => (header, claims, signature),
+        _ => return false,
+    };
+    let decode_json = |part: &str| -> Option<serde_json::Value> {
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part).ok()?).ok()
+    };
+    let (header_json, claims_json, signature) = match (
+        decode_json(header),
+        decode_json(claims),
+        URL_SAFE_NO_PAD.decode(signature),
+    ) {
+        (Some(header_json), Some(claims_json), Ok(signature)) => {
+            (header_json, claims_json, signature)
+        }
+        _ => return false,
+    };
+
+    // Only accept the algorithms we can check with a shared secret. Anything else (including
+    // "none") is rejected outright.
+    let signed = format!("{}.{}", header, claims);
+    let key = config.secret.as_bytes();
+    let signature_ok = match header_json.get("alg").and_then(|alg| alg.as_str()) {
+        Some("HS256") => verify_hmac::<Hmac<sha2::Sha256>>(key, &signed, &signature),
+        Some("HS384") => verify_hmac::<Hmac<sha2::Sha384>>(key, &signed, &signature),
+        Some("HS512") => verify_hmac::<Hmac<sha2::Sha512>>(key, &signed, &signature),
+        _ => false,
+    };
+    if !signature_ok {
+        log::debug!("Rejecting JWT with a bad signature or unsupported algorithm");
+        return false;
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64();
+    let claim_f64 = |name: &str| claims_json.get(name).and_then(|value| value.as_f64());
+    if claim_f64("exp").is_some_and(|exp| exp <= now) {
+        log::debug!("Rejecting expired JWT");
+        return false;
+    }
+    if claim_f64("nbf").is_some_and(|nbf| nbf > now) {
+        log::debug!("Rejecting JWT that is not yet valid");
+        return false;
+    }
+    if let Some(issuer) = &config.issuer {
+        if claims_json.get("iss").and_then(|iss| iss.as_str()) != Some(issuer.as_str()) {
+            log::debug!("Rejecting JWT from the wrong issuer");
+            return false;
+        }
+    }
+    if let Some(audience) = &config.audience {
+        let matches = match claims_json.get("aud") {
+            Some(serde_json::Value::String(aud)) => aud == audience,
+            Some(serde_json::Value::Array(auds)) => {
+                auds.iter().any(|aud| aud.as_str() == Some(audience))
+            }
+            _ => false,
+        };
+        if !matches {
+            log::debug!("Rejecting JWT for the wrong audience");
+            return false;
+        }
+    }
+    true
+}
+
+fn verify_hmac<M: Mac + hmac::digest::KeyInit>(key: &[u8], signed: &str, signature: &[u8]) -> bool {
+    let mut mac = match <M as Mac>::new_from_slice(key) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(signed.as_bytes());
+    mac.verify_slice(signature).is_ok()
+}
@@ -0,0 +1,400 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "testing")]
+use balancebeam::testing::Upstream;
+use clap::Args;
+#[cfg(feature = "testing")]
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+#[cfg(feature = "testing")]
+use tokio::process::{Child, Command};
+
+use crate::request::{self, Limits};
+use crate::response;
+
+/// Parses a (possibly fractional) number of seconds, which has to be positive and not so big that
+/// the bench would never end.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let seconds: f64 = value
+        .parse()
+        .map_err(|_| format!("{:?} is not a number of seconds", value))?;
+    if !(seconds > 0.0 && seconds <= 365.0 * 24.0 * 60.0 * 60.0) {
+        return Err(format!(
+            "{} is not a positive number of seconds up to a year",
+            value
+        ));
+    }
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+/// Options for `balancebeam bench`, which sends HTTP requests at a target as fast as it will take
+/// them and reports how it did.
+#[derive(Args, Debug)]
+pub struct BenchOptions {
+    #[arg(long)]
+    #[cfg_attr(
+        feature = "testing",
+        arg(
+            help = "host:port to send requests to (e.g. a running balancebeam)",
+            required_unless_present = "echo_upstreams",
+            conflicts_with = "echo_upstreams"
+        )
+    )]
+    #[cfg_attr(
+        not(feature = "testing"),
+        arg(
+            help = "host:port to send requests to (e.g. a running balancebeam). Builds with the \
+                    testing feature can start their own with --echo-upstreams instead",
+            required = true
+        )
+    )]
+    target: Option<String>,
+
+    #[cfg(feature = "testing")]
+    #[arg(
+        long,
+        help = "Instead of --target, start this many echo servers in-process and a balancebeam in \
+                front of them, and send requests to that"
+    )]
+    echo_upstreams: Option<usize>,
+
+    #[cfg(feature = "testing")]
+    #[arg(
+        long,
+        help = "Extra argument for the balancebeam started with --echo-upstreams (may be repeated)",
+        allow_hyphen_values = true
+    )]
+    proxy_arg: Vec<String>,
+
+    #[arg(
+        short,
+        long,
+        help = "Number of connections sending requests at once",
+        default_value = "10"
+    )]
+    concurrency: usize,
+
+    #[arg(
+        long,
+        help = "How long (in seconds) to send requests for",
+        default_value = "10",
+        value_parser = parse_duration
+    )]
+    duration: Duration,
+
+    #[arg(
+        long,
+        help = "Stop after this many requests, rather than after --duration"
+    )]
+    requests: Option<usize>,
+
+    #[arg(long, help = "HTTP method to use", default_value = "GET")]
+    method: String,
+
+    #[arg(long, help = "Path (and query) to request", default_value = "/")]
+    path: String,
+
+    #[arg(
+        short = 'H',
+        long,
+        help = "Header to add to each request, as \"Name: value\" (may be repeated)"
+    )]
+    header: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Size of the body to send with each request, in bytes",
+        default_value = "0"
+    )]
+    body_size: usize,
+}
+
+/// What one connection's worth of requests came to.
+#[derive(Default)]
+struct Results {
+    latencies: Vec<Duration>,
+    statuses: BTreeMap<u16, usize>,
+    errors: usize,
+    connections: usize,
+}
+
+impl Results {
+    fn merge(&mut self, other: Results) {
+        self.latencies.extend(other.latencies);
+        for (status, count) in other.statuses {
+            *self.statuses.entry(status).or_insert(0) += count;
+        }
+        self.errors += other.errors;
+        self.connections += other.connections;
+    }
+}
+
+/// Echo upstreams started for the bench, and a copy of this balancebeam in front of them. They are
+/// stopped when this is dropped.
+#[cfg(feature = "testing")]
+struct EchoSetup {
+    address: String,
+    _proxy: Child,
+    _upstreams: Vec<Upstream>,
+}
+
+/// Runs a copy of this balancebeam in front of the given upstreams, returning the process (which
+/// is killed when dropped) and the address it listens on.
+#[cfg(feature = "testing")]
+async fn start_proxy(
+    upstreams: &[String],
+    extra_args: &[String],
+) -> std::io::Result<(Child, String)> {
+    // Find a free port for it by binding to one and letting it go again
+    let address = TcpListener::bind("127.0.0.1:0")
+        .await?
+        .local_addr()?
+        .to_string();
+    let mut command = Command::new(std::env::current_exe()?);
+    command.arg("--bind").arg(&address);
+    for upstream in upstreams {
+        command.arg("--upstream").arg(upstream);
+    }
+    command
+        .args(extra_args)
+        .env("RUST_LOG", "warn")
+        .stdout(std::process::Stdio::null())
+        .kill_on_drop(true);
+    let mut child = command.spawn()?;
+
+    // Wait for it to start listening
+    let started = Instant::now();
+    while TcpStream::connect(&address).await.is_err() {
+        if let Some(status) = child.try_wait()? {
+            return Err(std::io::Error::other(format!(
+                "balancebeam exited with {}",
+                status
+            )));
+        }
+        if started.elapsed() > Duration::from_secs(10) {
+            return Err(std::io::Error::other("balancebeam didn't start listening"));
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    Ok((child, address))
+}
+
+/// Sends requests over one connection at a time (opening a new one whenever the target closes
+/// the last one) until time is up or enough requests have been sent.
+async fn run_connection(
+    target: String,
+    request: Arc<http::Request<Vec<u8>>>,
+    deadline: Instant,
+    remaining: Option<Arc<AtomicUsize>>,
+) -> Results {
+    let limits = Limits::default();
+    let mut results = Results::default();
+    let mut conn: Option<TcpStream> = None;
+    while Instant::now() < deadline {
+        if let Some(remaining) = &remaining {
+            let claimed = remaining.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |remaining| {
+                remaining.checked_sub(1)
+            });
+            if claimed.is_err() {
+                break;
+            }
+        }
+
+        let started = Instant::now();
+        let stream = match &mut conn {
+            Some(stream) => stream,
+            None => match TcpStream::connect(&target).await {
+                Ok(stream) => {
+                    let _ = stream.set_nodelay(true);
+                    results.connections += 1;
+                    conn.insert(stream)
+                }
+                Err(err) => {
+                    log::debug!("Could not connect to {}: {}", target, err);
+                    results.errors += 1;
+                    // Don't spin on a target that isn't there
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    continue;
+                }
+            },
+        };
+        if let Err(err) = request::write_to_stream(&request, stream).await {
+            log::debug!("Could not send request: {}", err);
+            results.errors += 1;
+            conn = None;
+            continue;
+        }
+        match response::read_from_stream(stream, request.method(), &limits).await {
+            Ok(response) => {
+                results.latencies.push(started.elapsed());
+                *results
+                    .statuses
+                    .entry(response.status().as_u16())
+                    .or_insert(0) += 1;
+                if request::has_connection_option(response.headers(), "close") {
+                    conn = None;
+                }
+            }
+            Err(err) => {
+                log::debug!("Could not read response: {}", err);
+                results.errors += 1;
+                conn = None;
+            }
+        }
+    }
+    results
+}
+
+/// Returns the latency below which the given fraction of requests were answered. The latencies
+/// must be sorted.
+fn percentile(sorted: &[Duration], fraction: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let index = ((sorted.len() as f64 * fraction).ceil() as usize).max(1) - 1;
+    sorted[index]
+}
+
+fn format_latency(latency: Duration) -> String {
+    format!("{:.2}ms", latency.as_secs_f64() * 1000.0)
+}
+
+fn build_request(options: &BenchOptions, target: &str) -> Result<http::Request<Vec<u8>>, String> {
+    let mut request = http::Request::builder()
+        .method(options.method.as_str())
+        .uri(options.path.as_str())
+        .version(http::Version::HTTP_11)
+        .header(http::header::HOST, target);
+    for header in &options.header {
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| format!("{:?} isn't in the form \"Name: value\"", header))?;
+        request = request.header(name.trim(), value.trim());
+    }
+    if options.body_size > 0 {
+        request = request.header(http::header::CONTENT_LENGTH, options.body_size);
+    }
+    request
+        .body(vec![b'x'; options.body_size])
+        .map_err(|err| err.to_string())
+}
+
+/// Starts the given number of echo upstreams and a balancebeam in front of them, exiting with an
+/// error if balancebeam doesn't start.
+#[cfg(feature = "testing")]
+async fn start_echo_setup(count: usize, proxy_args: &[String]) -> EchoSetup {
+    let mut upstreams = Vec::new();
+    for _ in 0..count {
+        upstreams.push(Upstream::new().await);
+    }
+    let addresses: Vec<String> = upstreams
+        .iter()
+        .map(|upstream| upstream.address.clone())
+        .collect();
+    match start_proxy(&addresses, proxy_args).await {
+        Ok((child, address)) => {
+            println!(
+                "Started balancebeam on {} in front of echo upstreams {}",
+                address,
+                addresses.join(", ")
+            );
+            EchoSetup {
+                address,
+                _proxy: child,
+                _upstreams: upstreams,
+            }
+        }
+        Err(err) => {
+            log::error!("Could not start balancebeam: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Runs the benchmark and prints a report, exiting with an error if it couldn't be run.
+pub async fn run(options: BenchOptions) {
+    // Hold on to the proxy process and its upstreams, if we start them, so that they are stopped
+    // once we are done
+    #[cfg(feature = "testing")]
+    let echo_setup = match options.echo_upstreams {
+        Some(count) => Some(start_echo_setup(count, &options.proxy_arg).await),
+        None => None,
+    };
+    let target = match &options.target {
+        Some(target) => target.clone(),
+        #[cfg(feature = "testing")]
+        None => echo_setup
+            .as_ref()
+            .expect("clap requires one of --target and --echo-upstreams")
+            .address
+            .clone(),
+        #[cfg(not(feature = "testing"))]
+        None => unreachable!("clap requires --target"),
+    };
+    let request = match build_request(&options, &target) {
+        Ok(request) => Arc::new(request),
+        Err(err) => {
+            log::error!("Invalid request: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let remaining = options
+        .requests
+        .map(|requests| Arc::new(AtomicUsize::new(requests)));
+    let started = Instant::now();
+    let deadline = match options.requests {
+        // Just a backstop in case the target stops answering
+        Some(_) => started + Duration::from_secs(24 * 60 * 60),
+        None => started + options.duration,
+    };
+    let connections: Vec<_> = (0..options.concurrency)
+        .map(|_| {
+            tokio::spawn(run_connection(
+                target.clone(),
+                request.clone(),
+                deadline,
+                remaining.clone(),
+            ))
+        })
+        .collect();
+    let mut results = Results::default();
+    for connection in connections {
+        results.merge(connection.await.unwrap());
+    }
+    let elapsed = started.elapsed();
+
+    results.latencies.sort();
+    let answered = results.latencies.len();
+    let mean = if answered == 0 {
+        Duration::ZERO
+    } else {
+        results.latencies.iter().sum::<Duration>() / answered as u32
+    };
+    println!("Target:       {}", target);
+    println!("Concurrency:  {}", options.concurrency);
+    println!("Duration:     {:.2}s", elapsed.as_secs_f64());
+    println!("Requests:     {} ({} errors)", answered, results.errors);
+    println!(
+        "Throughput:   {:.1} requests/s",
+        answered as f64 / elapsed.as_secs_f64()
+    );
+    println!("Connections:  {}", results.connections);
+    println!(
+        "Latency:      p50 {}  p90 {}  p99 {}  max {}  mean {}",
+        format_latency(percentile(&results.latencies, 0.5)),
+        format_latency(percentile(&results.latencies, 0.9)),
+        format_latency(percentile(&results.latencies, 0.99)),
+        format_latency(percentile(&results.latencies, 1.0)),
+        format_latency(mean)
+    );
+    let statuses: Vec<String> = results
+        .statuses
+        .iter()
+        .map(|(status, count)| format!("{}: {}", status, count))
+        .collect();
+    println!("Statuses:     {}", statuses.join(", "));
+}
@@ -0,0 +1,113 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use parking_lot::Mutex;
+
+use crate::affinity;
+
+/// The two groups of upstreams traffic is split between.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pool {
+    Stable,
+    Canary,
+}
+
+impl Pool {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pool::Stable => "stable",
+            Pool::Canary => "canary",
+        }
+    }
+}
+
+/// What decides which pool a request goes to.
+pub enum Selector {
+    /// Every request is assigned in turn, so that exactly the configured percentage of them goes
+    /// to the canary pool
+    Counter,
+    /// Requests are bucketed by this header's value, so that the same caller always lands in the
+    /// same pool for a given percentage
+    Header(http::HeaderName),
+    /// As above, but bucketed by the value of this cookie
+    Cookie(String),
+}
+
+/// Splits traffic between the stable upstreams and a pool of canary upstreams. The percentage of
+/// traffic the canary gets can be changed while balancebeam is running.
+pub struct Split {
+    canary: HashSet<String>,
+    /// Share of traffic that goes to the canary pool, in hundredths of a percent
+    basis_points: Mutex<u64>,
+    selector: Selector,
+    /// Number of requests assigned by the counter selector so far
+    assigned: AtomicU64,
+}
+
+impl Split {
+    pub fn new(canary: Vec<String>, percent: f64, selector: Selector) -> Split {
+        let split = Split {
+            canary: canary.into_iter().collect(),
+            basis_points: Mutex::new(0),
+            selector,
+            assigned: AtomicU64::new(0),
+        };
+        split.set_percent(percent);
+        split
+    }
+
+    pub fn percent(&self) -> f64 {
+        *self.basis_points.lock() as f64 / 100.0
+    }
+
+    /// Changes the share of traffic that goes to the canary pool. The percentage must be between
+    /// 0 and 100.
+    pub fn set_percent(&self, percent: f64) {
+        *self.basis_points.lock() = (percent * 100.0).round() as u64;
+    }
+
+    /// Returns which pool an upstream belongs to.
+    pub fn pool_of(&self, upstream_ip: &str) -> Pool {
+        if self.canary.contains(upstream_ip) {
+            Pool::Canary
+        } else {
+            Pool::Stable
+        }
+    }
+
+    /// Decides which pool a request (or, in tcp mode, a connection) should go to. Requests
+    /// without the selecting header or cookie are assigned by the counter instead.
+    pub fn choose_pool<T>(&self, request: Option<&http::Request<T>>) -> Pool {
+        let basis_points = *self.basis_points.lock();
+        let key = request.and_then(|request| match &self.selector {
+            Selector::Counter => None,
+            Selector::Header(name) => request
+                .headers()
+                .get(name)
+                .map(|value| value.as_bytes().to_vec()),
+            Selector::Cookie(name) => {
+                affinity::find_cookie(request, name).map(|value| value.into_bytes())
+            }
+        });
+        let to_canary = match key {
+            Some(key) => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                hasher.finish() % 10000 < basis_points
+            }
+            None => {
+                // Send request n to the canary whenever doing so keeps the canary's share of the
+                // first n requests at the configured percentage
+                let n = self.assigned.fetch_add(1, Ordering::Relaxed);
+                (n + 1) * basis_points / 10000 > n * basis_points / 10000
+            }
+        };
+        if to_canary {
+            Pool::Canary
+        } else {
+            Pool::Stable
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A block of IP addresses written in CIDR notation (e.g. "10.0.0.0/8"). A bare address is
+/// treated as a block containing only that address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Returns true if the address falls within this block. IPv4 addresses that arrive mapped into
+    /// IPv6 (::ffff:a.b.c.d) are compared as IPv4.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6
+                .to_ipv4_mapped()
+                .map(IpAddr::V4)
+                .unwrap_or(IpAddr::V6(*v6)),
+            IpAddr::V4(_) => *addr,
+        };
+        match (self.network, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (network, prefix_len) = match s.split_once('/') {
+            Some((network, prefix_len)) => (network, Some(prefix_len)),
+            None => (s, None),
+        };
+        let network: IpAddr = network
+            .parse()
+            .map_err(|_| format!("{:?} is not a valid IP address", network))?;
+        let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(|| format!("{:?} is not a valid prefix length", prefix_len))?,
+            None => max_prefix_len,
+        };
+        Ok(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+/// Returns true if the address falls within any of the given blocks.
+pub fn any_contains(blocks: &[Cidr], addr: &IpAddr) -> bool {
+    blocks.iter().any(|block| block.contains(addr))
+}
+
+impl<'de> serde::Deserialize<'de> for Cidr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Cidr, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::metrics::Metrics;
+
+/// An adaptive limit never goes below this, so that an upstream can always show it has recovered
+const MIN_LIMIT: f64 = 1.0;
+/// How much an adaptive limit is cut by when the upstream shows signs of overload
+const BACKOFF: f64 = 0.8;
+/// Responses taking more than this many times the upstream's unloaded response time are taken as
+/// a sign it is queueing requests rather than working on them
+const LATENCY_TOLERANCE: f64 = 2.0;
+/// How much the estimate of an upstream's unloaded response time creeps up with each response, so
+/// that one unusually fast response doesn't make every later one look slow
+const BASELINE_DRIFT: f64 = 1.001;
+
+/// Why a request couldn't be given a slot on its upstream
+#[derive(Debug)]
+pub enum Rejection {
+    /// The upstream's queue already holds as many requests as it may
+    QueueFull,
+    /// The request waited in the queue for longer than the queue timeout
+    TimedOut,
+}
+
+/// How many requests an upstream is sent at once, and how the limit has been doing.
+struct SlotState {
+    queued: usize,
+    in_flight: usize,
+    /// The current limit. It is fractional so that additive increases of less than one request
+    /// can build up.
+    limit: f64,
+    /// How many permits the semaphore holds, handed out or not. This can be above the limit for a
+    /// while after it is cut, until enough permits in use come back to be thrown away.
+    capacity: usize,
+    /// The lowest recent response time, taken to be how fast the upstream answers when it isn't
+    /// loaded
+    baseline: Option<Duration>,
+    /// When the limit was last cut
+    last_backoff: Option<Instant>,
+}
+
+impl SlotState {
+    /// Adjusts the limit for one response, AIMD style: the limit grows by about one request for
+    /// every limit's worth of good responses, and is cut by a fraction (at most once per response
+    /// time, so a burst of bad responses counts once) when the upstream fails or is slow.
+    fn adjust(&mut self, latency: Duration, overloaded: bool, ceiling: f64) {
+        let baseline = match self.baseline {
+            Some(baseline) => baseline.mul_f64(BASELINE_DRIFT).min(latency),
+            None => latency,
+        };
+        self.baseline = Some(baseline);
+        if overloaded || latency > baseline.mul_f64(LATENCY_TOLERANCE) {
+            self.back_off(latency);
+        } else {
+            self.limit = (self.limit + 1.0 / self.limit).min(ceiling);
+        }
+    }
+
+    /// Cuts the limit, unless it was already cut within the last `interval`.
+    fn back_off(&mut self, interval: Duration) {
+        let now = Instant::now();
+        if self
+            .last_backoff
+            .is_none_or(|last_backoff| now - last_backoff >= interval)
+        {
+            self.limit = (self.limit * BACKOFF).max(MIN_LIMIT);
+            self.last_backoff = Some(now);
+        }
+    }
+}
+
+/// The slots of one upstream. The semaphore hands out permits in the order they were asked for,
+/// which makes its waiters a FIFO queue.
+struct Slots {
+    semaphore: Arc<Semaphore>,
+    state: Mutex<SlotState>,
+}
+
+/// Caps how many requests each upstream is sent at once. Requests over the cap wait in a bounded
+/// queue, first come first served, until a slot frees up or they have waited too long.
+///
+/// The cap is either fixed, or adapts to how each upstream is coping: it is raised while the
+/// upstream answers about as fast as it does when idle, and cut when its responses slow down or it
+/// reports being overloaded, so that excess requests are turned away here before the upstream
+/// collapses under them.
+pub struct Limiter {
+    max_in_flight: usize,
+    adaptive: bool,
+    max_queue: usize,
+    queue_timeout: Duration,
+    upstreams: Mutex<HashMap<String, Arc<Slots>>>,
+}
+
+/// A request's slot on an upstream, given back when the permit is dropped.
+pub struct Permit {
+    permit: Option<OwnedSemaphorePermit>,
+    slots: Arc<Slots>,
+    /// How long the upstream took to respond, and whether it said it was overloaded, if it did
+    /// respond
+    outcome: Option<(Duration, bool)>,
+    max_in_flight: usize,
+    adaptive: bool,
+    upstream_ip: String,
+    metrics: Metrics,
+}
+
+impl Permit {
+    /// Notes the upstream's response, for adjusting an adaptive limit once the slot is given back.
+    /// Slots given back without a response count against the upstream.
+    pub fn record_response(&mut self, latency: Duration, status: http::StatusCode) {
+        let overloaded = status == http::StatusCode::SERVICE_UNAVAILABLE
+            || status == http::StatusCode::GATEWAY_TIMEOUT;
+        self.outcome = Some((latency, overloaded));
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.slots.state.lock();
+        state.in_flight -= 1;
+        let labels = [("upstream", self.upstream_ip.as_str())];
+        self.metrics.set(
+            "balancebeam_upstream_in_flight",
+            &labels,
+            state.in_flight as f64,
+        );
+        if !self.adaptive {
+            return;
+        }
+
+        match self.outcome {
+            Some((latency, overloaded)) => {
+                state.adjust(latency, overloaded, self.max_in_flight as f64)
+            }
+            // There is no response time to learn from, so only the failure counts
+            None => {
+                let interval = state.baseline.unwrap_or_default();
+                state.back_off(interval);
+            }
+        }
+        self.metrics.set(
+            "balancebeam_upstream_concurrency_limit",
+            &labels,
+            state.limit,
+        );
+
+        // Bring the semaphore in line with the new limit. Permits in use can't be taken back, so
+        // a cut that goes beyond the free permits is caught up on as they are given back.
+        let target = state.limit.floor() as usize;
+        if target > state.capacity {
+            self.slots.semaphore.add_permits(target - state.capacity);
+            state.capacity = target;
+        } else if target < state.capacity {
+            if let Some(permit) = self.permit.take() {
+                permit.forget();
+                state.capacity -= 1;
+            }
+            state.capacity -= self.slots.semaphore.forget_permits(state.capacity - target);
+        }
+    }
+}
+
+/// Keeps an upstream's queue length (and the gauge reporting it) up to date while a request
+/// waits, including if the wait is abandoned.
+struct QueuePosition<'a> {
+    slots: &'a Slots,
+    upstream_ip: &'a str,
+    metrics: &'a Metrics,
+}
+
+impl<'a> QueuePosition<'a> {
+    /// Joins the back of the upstream's queue, unless it is already full.
+    fn join(
+        slots: &'a Slots,
+        max_queue: usize,
+        upstream_ip: &'a str,
+        metrics: &'a Metrics,
+    ) -> Option<QueuePosition<'a>> {
+        let mut state = slots.state.lock();
+        if state.queued >= max_queue {
+            return None;
+        }
+        state.queued += 1;
+        metrics.set(
+            "balancebeam_upstream_queue_depth",
+            &[("upstream", upstream_ip)],
+            state.queued as f64,
+        );
+        Some(QueuePosition {
+            slots,
+            upstream_ip,
+            metrics,
+        })
+    }
+}
+
+impl Drop for QueuePosition<'_> {
+    fn drop(&mut self) {
+        let mut state = self.slots.state.lock();
+        state.queued -= 1;
+        self.metrics.set(
+            "balancebeam_upstream_queue_depth",
+            &[("upstream", self.upstream_ip)],
+            state.queued as f64,
+        );
+    }
+}
+
+impl Limiter {
+    /// Creates a limiter allowing each upstream `max_in_flight` requests at once. If the limit is
+    /// adaptive, this is where it starts and the most it can grow to.
+    pub fn new(
+        max_in_flight: usize,
+        adaptive: bool,
+        max_queue: usize,
+        queue_timeout: Duration,
+    ) -> Limiter {
+        Limiter {
+            max_in_flight,
+            adaptive,
+            max_queue,
+            queue_timeout,
+            upstreams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn slots(&self, upstream_ip: &str) -> Arc<Slots> {
+        self.upstreams
+            .lock()
+            .entry(upstream_ip.to_string())
+            .or_insert_with(|| {
+                Arc::new(Slots {
+                    semaphore: Arc::new(Semaphore::new(self.max_in_flight)),
+                    state: Mutex::new(SlotState {
+                        queued: 0,
+                        in_flight: 0,
+                        limit: self.max_in_flight as f64,
+                        capacity: self.max_in_flight,
+                        baseline: None,
+                        last_backoff: None,
+                    }),
+                })
+            })
+            .clone()
+    }
+
+    /// Waits for a slot on the upstream, queueing behind any requests already waiting for one.
+    pub async fn acquire(&self, upstream_ip: &str, metrics: &Metrics) -> Result<Permit, Rejection> {
+        let slots = self.slots(upstream_ip);
+        let permit = match slots.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let position =
+                    match QueuePosition::join(&slots, self.max_queue, upstream_ip, metrics) {
+                        Some(position) => position,
+                        None => {
+                            metrics.increment(
+                                "balancebeam_upstream_queue_rejections_total",
+                                &[("upstream", upstream_ip), ("reason", "full")],
+                            );
+                            return Err(Rejection::QueueFull);
+                        }
+                    };
+                let acquired = tokio::time::timeout(
+                    self.queue_timeout,
+                    slots.semaphore.clone().acquire_owned(),
+                )
+                .await;
+                drop(position);
+                match acquired {
+                    // The semaphore is never closed
+                    Ok(permit) => permit.unwrap(),
+                    Err(_) => {
+                        metrics.increment(
+                            "balancebeam_upstream_queue_rejections_total",
+                            &[("upstream", upstream_ip), ("reason", "timeout")],
+                        );
+                        return Err(Rejection::TimedOut);
+                    }
+                }
+            }
+        };
+        let in_flight = {
+            let mut state = slots.state.lock();
+            state.in_flight += 1;
+            state.in_flight
+        };
+        metrics.set(
+            "balancebeam_upstream_in_flight",
+            &[("upstream", upstream_ip)],
+            in_flight as f64,
+        );
+        Ok(Permit {
+            permit: Some(permit),
+            slots,
+            outcome: None,
+            max_in_flight: self.max_in_flight,
+            adaptive: self.adaptive,
+            upstream_ip: upstream_ip.to_string(),
+            metrics: metrics.clone(),
+        })
+    }
+}
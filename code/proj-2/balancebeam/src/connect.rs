@@ -0,0 +1,129 @@
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+use crate::stream::Stream;
+
+use crate::{
+    is_rate_limited, lingering_close, request, response, send_response, tunnel, ProxyState,
+};
+
+/// Returns true if the allowlist permits tunneling to the given host:port authority. Allowlist
+/// entries are either an exact host:port pair or host:* to allow every port on that host. Host
+/// names are compared case-insensitively.
+fn is_allowed(allowlist: &[String], authority: &http::uri::Authority) -> bool {
+    let port = match authority.port_u16() {
+        Some(port) => port.to_string(),
+        // CONNECT requests must always name a port
+        None => return false,
+    };
+    allowlist.iter().any(|entry| match entry.rsplit_once(':') {
+        Some((host, allowed_port)) => {
+            host.eq_ignore_ascii_case(authority.host())
+                && (allowed_port == "*" || allowed_port == port)
+        }
+        None => false,
+    })
+}
+
+/// Handles a client connection when balancebeam is running as a forward proxy. The client may send
+/// any number of requests; each one must be a CONNECT request naming an allowlisted destination.
+/// Once a tunnel has been established, the connection carries raw bytes until either side hangs up.
+pub async fn handle_connection(mut client_conn: Stream, client_ip: String, state: &ProxyState) {
+    log::info!("Connection received from {}", client_ip);
+
+    loop {
+        // Read a request from the client
+        let request = match request::read_from_stream(&mut client_conn, &state.limits).await {
+            Ok(request) => request,
+            // Handle case where client closed connection and is no longer sending requests
+            Err(request::Error::IncompleteRequest(0)) => {
+                log::debug!("Client finished sending requests. Shutting down connection");
+                return;
+            }
+            // Handle I/O error in reading from the client
+            Err(request::Error::Io(io_err)) => {
+                log::info!("Error reading request from client stream: {}", io_err);
+                return;
+            }
+            Err(error) => {
+                log::debug!("Error parsing request: {}", error);
+                let response = response::make_http_error(error.status_code());
+                send_response(&mut client_conn, &client_ip, &response).await;
+                if error.leaves_unread_data() {
+                    lingering_close(&mut client_conn).await;
+                    return;
+                }
+                continue;
+            }
+        };
+        log::info!(
+            "{} -> {}",
+            client_ip,
+            request::format_request_line(&request)
+        );
+
+        if is_rate_limited(state, &client_ip).await {
+            let response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
+            send_response(&mut client_conn, &client_ip, &response).await;
+            continue;
+        }
+
+        if request.method() != http::Method::CONNECT {
+            let response = response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED);
+            send_response(&mut client_conn, &client_ip, &response).await;
+            continue;
+        }
+
+        let destination = match request.uri().authority() {
+            Some(authority) if is_allowed(&state.connect_allowlist, authority) => {
+                authority.to_string()
+            }
+            _ => {
+                log::info!(
+                    "Refusing to tunnel {} to {}: destination is not allowlisted",
+                    client_ip,
+                    request.uri()
+                );
+                let response = response::make_http_error(http::StatusCode::FORBIDDEN);
+                send_response(&mut client_conn, &client_ip, &response).await;
+                continue;
+            }
+        };
+
+        let mut destination_conn = match TcpStream::connect(&destination).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::error!("Failed to connect to {}: {}", destination, err);
+                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
+                send_response(&mut client_conn, &client_ip, &response).await;
+                continue;
+            }
+        };
+
+        // A successful response to CONNECT has no body (and must not claim to have one), so this
+        // can't use make_http_error.
+        let established = http::Response::builder()
+            .status(http::StatusCode::OK)
+            .version(http::Version::HTTP_11)
+            .body(Vec::new())
+            .unwrap();
+        send_response(&mut client_conn, &client_ip, &established).await;
+
+        // The client may have started sending tunneled bytes right behind the CONNECT headers
+        // (e.g. a TLS ClientHello). read_from_stream leaves those in the request body.
+        if !request.body().is_empty() {
+            if let Err(err) = destination_conn.write_all(request.body()).await {
+                log::error!("Failed to send data to {}: {}", destination, err);
+                return;
+            }
+        }
+        tunnel(
+            &mut client_conn,
+            &mut destination_conn,
+            &client_ip,
+            &destination,
+        )
+        .await;
+        return;
+    }
+}
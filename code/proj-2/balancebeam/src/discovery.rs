@@ -0,0 +1,124 @@
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+use tokio::time::sleep;
+
+use crate::ProxyState;
+
+/// Where upstream addresses can be discovered from, in addition to the static --upstream list.
+pub enum Provider {
+    /// Resolve a host:port to every A/AAAA record it has, using the system resolver
+    Dns(String),
+    /// Read a JSON array of host:port strings from a file, re-reading it whenever it changes
+    File(String),
+}
+
+/// Tracks what a provider last reported, so a failed lookup (or a half-written file) doesn't make
+/// its upstreams disappear.
+struct ProviderState {
+    provider: Provider,
+    upstreams: Vec<String>,
+    file_modified: Option<SystemTime>,
+}
+
+/// Re-runs every provider on the given interval and reconciles the upstream list with what they
+/// found. Static upstreams are always kept.
+pub async fn run(
+    providers: Vec<Provider>,
+    static_upstreams: Vec<String>,
+    interval: Duration,
+    state: ProxyState,
+) {
+    let mut providers: Vec<ProviderState> = providers
+        .into_iter()
+        .map(|provider| ProviderState {
+            provider,
+            upstreams: Vec::new(),
+            file_modified: None,
+        })
+        .collect();
+    loop {
+        for provider in providers.iter_mut() {
+            refresh(provider).await;
+        }
+
+        let mut discovered = static_upstreams.clone();
+        for provider in &providers {
+            for upstream_ip in &provider.upstreams {
+                if !discovered.contains(upstream_ip) {
+                    discovered.push(upstream_ip.clone());
+                }
+            }
+        }
+        reconcile(&state, discovered).await;
+
+        sleep(interval).await;
+    }
+}
+
+/// Asks a provider for its current upstreams, keeping the previous answer if that fails.
+async fn refresh(provider: &mut ProviderState) {
+    match &provider.provider {
+        Provider::Dns(host) => match tokio::net::lookup_host(host.as_str()).await {
+            Ok(addrs) => {
+                let mut upstreams: Vec<String> = Vec::new();
+                for addr in addrs.map(|addr| addr.to_string()) {
+                    if !upstreams.contains(&addr) {
+                        upstreams.push(addr);
+                    }
+                }
+                provider.upstreams = upstreams;
+            }
+            Err(err) => log::warn!("Could not resolve {}: {}", host, err),
+        },
+        Provider::File(path) => {
+            let modified = match std::fs::metadata(path).and_then(|metadata| metadata.modified()) {
+                Ok(modified) => modified,
+                Err(err) => {
+                    log::warn!("Could not read upstreams file {}: {}", path, err);
+                    return;
+                }
+            };
+            if provider.file_modified == Some(modified) {
+                return;
+            }
+            let parsed = std::fs::read_to_string(path)
+                .map_err(|err| err.to_string())
+                .and_then(|contents| {
+                    serde_json::from_str::<Vec<String>>(&contents).map_err(|err| err.to_string())
+                });
+            match parsed {
+                Ok(upstreams) => {
+                    provider.upstreams = upstreams;
+                    provider.file_modified = Some(modified);
+                }
+                Err(err) => log::warn!("Could not load upstreams file {}: {}", path, err),
+            }
+        }
+    }
+}
+
+/// Replaces the upstream list. Upstreams that are new are assumed alive until a health check says
+/// otherwise (just like upstreams given on the command line), upstreams that went away stop
+/// receiving traffic, and the rest keep whatever health status they had.
+async fn reconcile(state: &ProxyState, discovered: Vec<String>) {
+    let mut upstream_addresses = state.upstream_addresses.write().await;
+    if *upstream_addresses == discovered {
+        return;
+    }
+    let previous: HashSet<String> = upstream_addresses.iter().cloned().collect();
+    let current: HashSet<String> = discovered.iter().cloned().collect();
+
+    let mut alive_upstreams = state.alive_upstreams.write().await;
+    for upstream_ip in current.difference(&previous) {
+        log::info!("Discovered upstream {}", upstream_ip);
+        alive_upstreams.insert(upstream_ip.clone());
+    }
+    for upstream_ip in previous.difference(&current) {
+        log::info!("Upstream {} is no longer listed; removing it", upstream_ip);
+        alive_upstreams.remove(upstream_ip);
+    }
+    drop(alive_upstreams);
+
+    *upstream_addresses = discovered;
+}
@@ -0,0 +1,89 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::{response, static_files, ProxyState};
+
+/// A custom body for the error responses balancebeam sends with one status code, loaded from a
+/// template file named in the config, e.g.
+///
+/// ```json
+/// {"error_pages": {
+///     "502": "/etc/balancebeam/maintenance.html",
+///     "429": "/etc/balancebeam/429.json"
+/// }}
+/// ```
+///
+/// The file's extension decides the Content-Type. `{{status}}`, `{{reason}}` and `{{request_id}}`
+/// in the template are replaced with the response's status code, its reason phrase and the ID of
+/// the request being answered.
+#[derive(Debug, Deserialize)]
+#[serde(from = "String")]
+pub struct ErrorPage {
+    pub path: String,
+    /// The template, loaded from `path`
+    template: String,
+}
+
+impl From<String> for ErrorPage {
+    fn from(path: String) -> ErrorPage {
+        ErrorPage {
+            path,
+            template: String::new(),
+        }
+    }
+}
+
+impl ErrorPage {
+    /// Loads the template file. Called whenever the config is (re)loaded.
+    pub fn prepare(&mut self) -> Result<(), String> {
+        self.template = std::fs::read_to_string(&self.path)
+            .map_err(|err| format!("could not read {}: {}", self.path, err))?;
+        Ok(())
+    }
+
+    fn render(&self, status: http::StatusCode, request_id: &str) -> Vec<u8> {
+        self.template
+            .replace("{{status}}", status.as_str())
+            .replace("{{reason}}", status.canonical_reason().unwrap_or(""))
+            .replace("{{request_id}}", request_id)
+            .into_bytes()
+    }
+}
+
+/// Tags an error response balancebeam generated with the ID of the request it answers, and swaps
+/// in the custom page for its status, if one is configured. Other headers (e.g. WWW-Authenticate)
+/// are left alone.
+pub async fn customize(
+    state: &ProxyState,
+    mut response: http::Response<Vec<u8>>,
+    request_id: &str,
+) -> http::Response<Vec<u8>> {
+    if let Ok(value) = http::HeaderValue::from_str(request_id) {
+        response.headers_mut().insert("x-request-id", value);
+    }
+    let status = response.status();
+    if let Some(page) = state.error_pages.read().await.get(&status.as_u16()) {
+        *response.body_mut() = page.render(status, request_id);
+        let body_len = response.body().len();
+        let headers = response.headers_mut();
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static(static_files::content_type(Path::new(&page.path))),
+        );
+        headers.insert(
+            http::header::CONTENT_LENGTH,
+            http::HeaderValue::from(body_len),
+        );
+    }
+    response
+}
+
+/// Like response::make_http_error, but with the custom page for the status if there is one.
+pub async fn make_error(
+    state: &ProxyState,
+    status: http::StatusCode,
+    request_id: &str,
+) -> http::Response<Vec<u8>> {
+    customize(state, response::make_http_error(status), request_id).await
+}
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::cidr::{self, Cidr};
+
+/// Header an allowed client can send to have its request held up for this many milliseconds
+pub const DELAY_HEADER: &str = "x-balancebeam-fault-delay";
+/// Header an allowed client can send to have its request aborted, with this error status code or
+/// (if it says `reset`) by resetting the connection
+pub const ABORT_HEADER: &str = "x-balancebeam-fault-abort";
+
+/// Faults to inject into some share of a route's requests, for seeing how clients cope with a
+/// slow or failing upstream. Each is rolled for separately; a request that is both delayed and
+/// aborted is aborted once the delay is over.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaultConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay: Option<Delay>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub abort: Option<Abort>,
+}
+
+/// Holds requests up before they are forwarded.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Delay {
+    pub percent: f64,
+    pub ms: u64,
+}
+
+/// Answers requests without forwarding them, either with an error status or by resetting the
+/// client's connection (or, over HTTP/2, its stream).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Abort {
+    pub percent: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reset: bool,
+}
+
+/// How an aborted request is answered.
+#[derive(Clone, Copy, Debug)]
+pub enum Action {
+    Status(http::StatusCode),
+    Reset,
+}
+
+/// Marks a response as standing in for a reset. The connection (or stream) it would have been
+/// sent on is reset instead.
+#[derive(Clone, Copy, Debug)]
+pub struct Reset;
+
+fn check_percent(percent: f64) -> Result<(), String> {
+    if (0.0..=100.0).contains(&percent) {
+        Ok(())
+    } else {
+        Err(format!("{} is not a percentage between 0 and 100", percent))
+    }
+}
+
+/// Aborts answer with an error status. Anything else would be framed or handled differently (1xx
+/// and 204 responses have no body, and 101 would switch the client's connection over to the
+/// upstream), so it isn't allowed.
+fn check_abort_status(status: u16) -> Result<http::StatusCode, String> {
+    match http::StatusCode::from_u16(status) {
+        Ok(status) if status.is_client_error() || status.is_server_error() => Ok(status),
+        _ => Err(format!("{} is not an error status code (400-599)", status)),
+    }
+}
+
+fn rolls(percent: f64) -> bool {
+    rand::thread_rng().gen::<f64>() * 100.0 < percent
+}
+
+impl FaultConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(delay) = &self.delay {
+            check_percent(delay.percent)?;
+        }
+        if let Some(abort) = &self.abort {
+            check_percent(abort.percent)?;
+            match (abort.status, abort.reset) {
+                (Some(status), false) => {
+                    check_abort_status(status)?;
+                }
+                (None, true) => {}
+                _ => return Err("an abort needs either a status or \"reset\": true".to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Decides which of the faults a request gets.
+    pub fn roll(&self) -> (Option<Duration>, Option<Action>) {
+        let delay = self
+            .delay
+            .as_ref()
+            .filter(|delay| rolls(delay.percent))
+            .map(|delay| Duration::from_millis(delay.ms));
+        let action = self
+            .abort
+            .as_ref()
+            .filter(|abort| rolls(abort.percent))
+            .map(|abort| match abort.status {
+                // Checked when the config was loaded
+                Some(status) => Action::Status(http::StatusCode::from_u16(status).unwrap()),
+                None => Action::Reset,
+            });
+        (delay, action)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.delay.is_none() && self.abort.is_none()
+    }
+}
+
+/// Keeps track of the faults to inject. Routes get the faults in the config file unless they have
+/// been replaced through the admin interface, which lasts until they are cleared again (config
+/// reloads don't undo them). Clients in the header allowlist can also ask for faults on their
+/// own requests.
+pub struct Injector {
+    /// Faults set through the admin interface, by route name
+    overrides: Mutex<HashMap<String, FaultConfig>>,
+    header_allow: Vec<Cidr>,
+}
+
+impl Injector {
+    pub fn new(header_allow: Vec<Cidr>) -> Injector {
+        Injector {
+            overrides: Mutex::new(HashMap::new()),
+            header_allow,
+        }
+    }
+
+    /// Returns the faults in effect for a route, given those in its config.
+    pub fn for_route(&self, name: &str, configured: Option<&FaultConfig>) -> Option<FaultConfig> {
+        match self.overrides.lock().get(name) {
+            Some(faults) => Some(faults.clone()),
+            None => configured.cloned(),
+        }
+        .filter(|faults| !faults.is_empty())
+    }
+
+    pub fn set_override(&self, name: &str, faults: FaultConfig) {
+        self.overrides.lock().insert(name.to_string(), faults);
+    }
+
+    /// Goes back to the configured faults for a route. Returns false if they weren't overridden.
+    pub fn clear_override(&self, name: &str) -> bool {
+        self.overrides.lock().remove(name).is_some()
+    }
+
+    /// Removes the fault headers from a request, returning the faults they ask for if the client
+    /// is allowed to ask. Headers from an allowed client that ask for something we can't do are
+    /// an error.
+    pub fn take_requested<T>(
+        &self,
+        request: &mut http::Request<T>,
+        client_ip: &str,
+    ) -> Result<Option<FaultConfig>, String> {
+        let delay = request.headers_mut().remove(DELAY_HEADER);
+        let abort = request.headers_mut().remove(ABORT_HEADER);
+        if delay.is_none() && abort.is_none() {
+            return Ok(None);
+        }
+        let allowed = client_ip
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| cidr::any_contains(&self.header_allow, &ip));
+        if !allowed {
+            log::debug!("Ignoring fault headers from {}", client_ip);
+            return Ok(None);
+        }
+        let delay = match delay {
+            Some(value) => {
+                let ms = value
+                    .to_str()
+                    .ok()
+                    .and_then(|value| value.trim().parse().ok())
+                    .ok_or_else(|| format!("invalid {} header", DELAY_HEADER))?;
+                Some(Delay { percent: 100.0, ms })
+            }
+            None => None,
+        };
+        let abort = match abort {
+            Some(value) => {
+                let value = value.to_str().unwrap_or("").trim();
+                if value.eq_ignore_ascii_case("reset") {
+                    Some(Abort {
+                        percent: 100.0,
+                        status: None,
+                        reset: true,
+                    })
+                } else {
+                    let status = value
+                        .parse()
+                        .map_err(|_| format!("invalid {} header", ABORT_HEADER))?;
+                    check_abort_status(status)?;
+                    Some(Abort {
+                        percent: 100.0,
+                        status: Some(status),
+                        reset: false,
+                    })
+                }
+            }
+            None => None,
+        };
+        Ok(Some(FaultConfig { delay, abort }))
+    }
+}
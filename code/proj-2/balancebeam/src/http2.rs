@@ -0,0 +1,317 @@
+use std::time::Instant;
+
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+use crate::stream::{ConnectionAddrs, PrefixedStream};
+use crate::{
+    admit_request, choose_pool, connect_with_slot, error_pages, faults, request, response, routes,
+    ProxyState,
+};
+
+/// Every HTTP/2 connection opens with this preface (RFC 9113, section 3.4). A client using prior
+/// knowledge over cleartext sends it immediately; an HTTP/1.1 client never will.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Headers that only describe a single HTTP/1.1 hop. HTTP/2 forbids them, so they are dropped
+/// from upstream responses.
+const HOP_BY_HOP_HEADERS: [&str; 6] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+    "te",
+];
+
+/// Reads just enough of a new connection to tell whether the client is opening an HTTP/2
+/// connection with prior knowledge. Returns whether it is, along with a stream that will replay
+/// the bytes that were read so that the appropriate handler sees the connection from the start.
+pub async fn detect_prior_knowledge<S: AsyncRead + Unpin>(
+    mut stream: S,
+) -> Result<(bool, PrefixedStream<S>), std::io::Error> {
+    let mut buffer = Vec::with_capacity(PREFACE.len());
+    while PREFACE.starts_with(&buffer) && buffer.len() < PREFACE.len() {
+        let mut chunk = [0_u8; PREFACE.len()];
+        let bytes_read = stream
+            .read(&mut chunk[..PREFACE.len() - buffer.len()])
+            .await?;
+        if bytes_read == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..bytes_read]);
+    }
+    let is_http2 = buffer == PREFACE;
+    Ok((is_http2, PrefixedStream::new(buffer, stream)))
+}
+
+/// Serves an HTTP/2 client connection. Each stream the client opens is handled concurrently and
+/// forwarded to an upstream as an ordinary HTTP/1.1 request.
+pub async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    client_conn: S,
+    client_ip: String,
+    client_addrs: Option<ConnectionAddrs>,
+    state: &ProxyState,
+) {
+    log::info!("HTTP/2 connection received from {}", client_ip);
+    let mut connection = match h2::server::Builder::new()
+        .max_concurrent_streams(state.max_concurrent_streams)
+        .max_header_list_size(state.limits.max_headers_size.try_into().unwrap_or(u32::MAX))
+        .handshake(client_conn)
+        .await
+    {
+        Ok(connection) => connection,
+        Err(err) => {
+            log::info!("HTTP/2 handshake with {} failed: {}", client_ip, err);
+            return;
+        }
+    };
+
+    // Accepting streams is also what drives the connection, so keep doing it until the client
+    // goes away, even while earlier streams are still being handled
+    while let Some(result) = connection.accept().await {
+        match result {
+            Ok((request, respond)) => {
+                let client_ip = client_ip.clone();
+                let state = state.clone();
+                tokio::spawn(async move {
+                    handle_stream(request, respond, &client_ip, client_addrs, &state).await;
+                });
+            }
+            Err(err) => {
+                log::info!("HTTP/2 connection with {} failed: {}", client_ip, err);
+                return;
+            }
+        }
+    }
+    log::debug!("Client finished sending requests. Shutting down connection");
+}
+
+/// Proxies a single HTTP/2 stream and sends the result (or an error) back on that stream.
+async fn handle_stream(
+    request: http::Request<h2::RecvStream>,
+    mut respond: h2::server::SendResponse<Bytes>,
+    client_ip: &str,
+    client_addrs: Option<ConnectionAddrs>,
+    state: &ProxyState,
+) {
+    let response = match forward_request(request, client_ip, client_addrs, state).await {
+        Ok(response) => response,
+        Err(response) => response,
+    };
+    if response.extensions().get::<faults::Reset>().is_some() {
+        log::info!("{} <- stream reset", client_ip);
+        respond.send_reset(h2::Reason::INTERNAL_ERROR);
+        return;
+    }
+    log::info!(
+        "{} <- {}",
+        client_ip,
+        response::format_response_line(&response)
+    );
+
+    let (parts, body) = response.into_parts();
+    let mut head = http::Response::from_parts(parts, ());
+    for header_name in HOP_BY_HOP_HEADERS {
+        head.headers_mut().remove(header_name);
+    }
+    let mut send_stream = match respond.send_response(head, body.is_empty()) {
+        Ok(send_stream) => send_stream,
+        Err(err) => {
+            log::warn!("Failed to send response to client: {}", err);
+            return;
+        }
+    };
+    if !body.is_empty() {
+        if let Err(err) = send_stream.send_data(Bytes::from(body), true) {
+            log::warn!("Failed to send response to client: {}", err);
+        }
+    }
+}
+
+/// Translates an HTTP/2 request into an HTTP/1.1 request, forwards it to an upstream, and returns
+/// the upstream's response. If that fails, or the request is refused, returns the response to
+/// send the client instead.
+async fn forward_request(
+    request: http::Request<h2::RecvStream>,
+    client_ip: &str,
+    client_addrs: Option<ConnectionAddrs>,
+    state: &ProxyState,
+) -> Result<http::Response<Vec<u8>>, http::Response<Vec<u8>>> {
+    let (parts, mut body_stream) = request.into_parts();
+
+    // HTTP/2 carries the target as :scheme/:authority/:path pseudo-headers. An HTTP/1.1 upstream
+    // expects origin-form on the request line and the authority in the Host header.
+    let mut upstream_request = http::Request::builder()
+        .method(parts.method.clone())
+        .uri(
+            parts
+                .uri
+                .path_and_query()
+                .map(|path| path.as_str())
+                .unwrap_or("/"),
+        )
+        .version(http::Version::HTTP_11)
+        .body(Vec::new())
+        .unwrap();
+    *upstream_request.headers_mut() = parts.headers;
+    // HTTP/2 clients may split cookies into separate fields to compress them better, but HTTP/1.1
+    // only allows one Cookie header, so put them back together (RFC 9113, section 8.2.3)
+    let cookies: Vec<&[u8]> = upstream_request
+        .headers()
+        .get_all(http::header::COOKIE)
+        .iter()
+        .map(|value| value.as_bytes())
+        .collect();
+    if cookies.len() > 1 {
+        let cookie = http::HeaderValue::from_bytes(&cookies.join(&b"; "[..])).unwrap();
+        upstream_request
+            .headers_mut()
+            .insert(http::header::COOKIE, cookie);
+    }
+    if !upstream_request.headers().contains_key(http::header::HOST) {
+        if let Some(authority) = parts.uri.authority() {
+            upstream_request.headers_mut().insert(
+                http::header::HOST,
+                http::HeaderValue::from_str(authority.as_str()).unwrap(),
+            );
+        }
+    }
+    let request_id = request::request_id(&mut upstream_request);
+    // Refuse requests we won't serve before buffering anything the client sends
+    admit_request(state, &mut upstream_request, &request_id, client_ip).await?;
+    let limits = routes::limits(state, &upstream_request).await;
+    // HTTP/2 has no way to send 100 Continue here, but a client waiting for one will go ahead
+    // without it after a moment. An expectation we can't meet, or a body that is too big, can at
+    // least be refused before the client sends it.
+    if let Err(error) = request::check_headers(&upstream_request, &limits)
+        .and_then(|_| request::expects_continue(&upstream_request))
+        .and_then(|_| request::check_body_size(&upstream_request, &limits))
+    {
+        return Err(error_pages::make_error(state, error.status_code(), &request_id).await);
+    }
+    upstream_request.headers_mut().remove(http::header::EXPECT);
+
+    // Read the whole request body, giving flow control capacity back to the client as we go
+    while let Some(chunk) = body_stream.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                log::info!("Error reading request body from client stream: {}", err);
+                return Err(error_pages::make_error(
+                    state,
+                    http::StatusCode::BAD_REQUEST,
+                    &request_id,
+                )
+                .await);
+            }
+        };
+        if upstream_request.body().len() + chunk.len() > limits.max_body_size {
+            return Err(error_pages::make_error(
+                state,
+                http::StatusCode::PAYLOAD_TOO_LARGE,
+                &request_id,
+            )
+            .await);
+        }
+        upstream_request.body_mut().extend_from_slice(&chunk);
+        let _ = body_stream.flow_control().release_capacity(chunk.len());
+    }
+
+    // HTTP/2 frames the body itself, so the client may not have said how long it is
+    let body_len = upstream_request.body().len();
+    if body_len > 0 {
+        upstream_request.headers_mut().insert(
+            http::header::CONTENT_LENGTH,
+            http::HeaderValue::from(body_len),
+        );
+    }
+
+    if let Some(response) = routes::serve_static(state, &upstream_request, &request_id).await {
+        log::info!(
+            "{} -> static: {} (HTTP/2)",
+            client_ip,
+            request::format_request_line(&upstream_request)
+        );
+        return Ok(response);
+    }
+
+    if let Some(response) =
+        routes::inject_faults(state, &mut upstream_request, &request_id, client_ip).await
+    {
+        return Err(response);
+    }
+
+    let pool = choose_pool(state, Some(&upstream_request));
+    let (mut upstream_conn, upstream_ip, mut slot) = connect_with_slot(
+        state,
+        &upstream_request,
+        pool,
+        client_addrs.as_ref(),
+        &request_id,
+    )
+    .await?;
+    log::info!(
+        "{} -> {}: {} (HTTP/2)",
+        client_ip,
+        upstream_ip,
+        request::format_request_line(&upstream_request)
+    );
+
+    request::extend_header_value(&mut upstream_request, "x-forwarded-for", client_ip);
+    let shadow = state
+        .mirror
+        .as_ref()
+        .and_then(|mirror| mirror.start(&upstream_request, state));
+    let started = Instant::now();
+    if let Err(error) = request::write_to_stream(&upstream_request, &mut upstream_conn).await {
+        log::error!(
+            "Failed to send request to upstream {}: {}",
+            upstream_ip,
+            error
+        );
+        return Err(
+            error_pages::make_error(state, http::StatusCode::BAD_GATEWAY, &request_id).await,
+        );
+    }
+    let mut response = match response::read_from_stream(
+        &mut upstream_conn,
+        upstream_request.method(),
+        &state.response_limits,
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(error) => {
+            log::error!("Error reading response from server: {}", error);
+            return Err(
+                error_pages::make_error(state, http::StatusCode::BAD_GATEWAY, &request_id).await,
+            );
+        }
+    };
+    // Interim responses have been skipped, but HTTP/2 has no way to switch a stream to another
+    // protocol, so a 101 can't be passed on either
+    if response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+        log::error!(
+            "Upstream {} switched protocols on a request from an HTTP/2 stream",
+            upstream_ip
+        );
+        return Err(
+            error_pages::make_error(state, http::StatusCode::BAD_GATEWAY, &request_id).await,
+        );
+    }
+    let latency = started.elapsed();
+    if let Some(slot) = &mut slot {
+        slot.record_response(latency, response.status());
+    }
+    if let Some(outliers) = &state.outliers {
+        outliers.record(&upstream_ip, latency);
+    }
+    if let Some(shadow) = shadow {
+        shadow.finish(response.status(), latency);
+    }
+    if let Some(affinity) = &state.affinity {
+        affinity.pin_response(&upstream_request, &mut response, &upstream_ip);
+    }
+    Ok(response)
+}
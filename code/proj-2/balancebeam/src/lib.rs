@@ -0,0 +1,6 @@
+//! The parts of balancebeam that are of use outside the binary. For now, that is just the test
+//! doubles in `testing`, for projects that want to see how their clients fare behind balancebeam
+//! when upstreams misbehave. They are only built with the `testing` feature.
+
+#[cfg(feature = "testing")]
+pub mod testing;
@@ -1,26 +1,71 @@
+mod admin;
+mod affinity;
+mod auth;
+mod bench;
+mod canary;
+mod cidr;
+mod concurrency;
+mod connect;
+mod discovery;
+mod error_pages;
+mod faults;
+mod http2;
+mod metrics;
+mod mirror;
+mod outlier;
+mod proxy_protocol;
 mod request;
 mod response;
+mod routes;
+mod shedding;
+mod static_files;
+mod stream;
+mod tcp;
+mod tls;
+mod udp;
 
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::io::Error;
-use std::io::ErrorKind;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use rand::seq::IteratorRandom;
 use rand::SeedableRng;
-use tokio::net::{TcpListener, TcpStream};
+use stream::{ConnectionAddrs, Listener, PrefixedStream, ResetOnClose, Stream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::Mutex;
 use tokio::sync::RwLock;
 use tokio::time::sleep;
 
+/// What kind of traffic balancebeam accepts on its listening socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Mode {
+    /// Reverse-proxy HTTP/1.1 and HTTP/2 requests to the upstream servers
+    Http,
+    /// Act as a forward proxy, tunneling CONNECT requests to allowlisted destinations
+    Connect,
+    /// Balance whole TCP connections across the upstream servers without parsing them
+    Tcp,
+    /// Forward UDP datagrams to the upstream servers, keeping each client on one upstream
+    Udp,
+}
+
+/// Things balancebeam can do other than proxying.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Send HTTP load at a target and report throughput and latency percentiles
+    Bench(bench::BenchOptions),
+}
+
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
 /// provide a fancy way to automatically construct a command-line argument parser.
 #[derive(Parser, Debug)]
 #[command(about = "Fun with load balancing")]
 struct CmdOptions {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(
         short,
         long,
@@ -29,12 +74,42 @@ struct CmdOptions {
     )]
     bind: String,
 
+    #[arg(
+        long,
+        help = "Kind of traffic to proxy",
+        value_enum,
+        default_value = "http"
+    )]
+    mode: Mode,
+
     #[arg(short, long, help = "Upstream host to forward requests to")]
     upstream: Vec<String>,
 
     #[arg(
         long,
-        help = "Perform active health checks on this interval (in seconds)",
+        help = "Discover upstreams by resolving this host:port to all of its A/AAAA records"
+    )]
+    discover_dns: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Discover upstreams from a JSON file containing an array of host:port strings, \
+                reloading it when it changes"
+    )]
+    discover_file: Vec<String>,
+
+    #[arg(
+        long,
+        help = "How often (in seconds) to re-run upstream discovery",
+        default_value = "5"
+    )]
+    discovery_interval: u64,
+
+    #[arg(
+        long,
+        help = "Perform active health checks on this interval (in seconds). In tcp mode, an \
+                upstream is healthy if it accepts a connection; in udp mode, if it doesn't \
+                refuse a datagram",
         default_value = "10"
     )]
     active_health_check_interval: usize,
@@ -52,6 +127,285 @@ struct CmdOptions {
         default_value = "0"
     )]
     max_requests_per_minute: usize,
+
+    #[arg(
+        long,
+        help = "Destination (host:port, or host:* for any port) that clients may CONNECT to in \
+                connect mode"
+    )]
+    connect_allow: Vec<String>,
+
+    #[arg(
+        long,
+        help = "In udp mode, forget a client's upstream after this many seconds without traffic",
+        default_value = "30"
+    )]
+    udp_session_timeout: u64,
+
+    #[arg(
+        long,
+        help = "In udp mode, the most clients to keep sessions for at once. Datagrams from new \
+                clients are dropped while this many sessions are open",
+        default_value = "10000"
+    )]
+    udp_max_sessions: usize,
+
+    #[arg(
+        long,
+        help = "Largest request body to accept, in bytes",
+        default_value = "10000000"
+    )]
+    max_body_size: usize,
+
+    #[arg(
+        long,
+        help = "Largest response body to accept from an upstream or serve from a static directory, \
+                in bytes",
+        default_value = "10000000"
+    )]
+    max_response_body_size: usize,
+
+    #[arg(
+        long,
+        help = "Largest request or response line plus headers to accept, in bytes",
+        default_value = "8000"
+    )]
+    max_headers_size: usize,
+
+    #[arg(
+        long,
+        help = "Maximum number of headers to accept in a request or response",
+        default_value = "32"
+    )]
+    max_num_headers: usize,
+
+    #[arg(
+        long,
+        help = "Longest request target (path and query) to accept, in bytes",
+        default_value = "8000"
+    )]
+    max_uri_length: usize,
+
+    #[arg(
+        long,
+        help = "Close client connections after this many requests (0 means no limit)",
+        default_value = "0"
+    )]
+    max_requests_per_connection: usize,
+
+    #[arg(
+        long,
+        help = "Most requests an HTTP/2 client may have in flight at once on one connection",
+        default_value = "100"
+    )]
+    max_concurrent_streams: u32,
+
+    #[arg(
+        long,
+        help = "PEM certificate chain for terminating TLS from clients in http mode",
+        requires = "tls_key"
+    )]
+    tls_cert: Option<String>,
+
+    #[arg(
+        long,
+        help = "PEM private key matching --tls-cert",
+        requires = "tls_cert"
+    )]
+    tls_key: Option<String>,
+
+    #[arg(
+        long,
+        help = "In http mode, pin each client to one upstream using a signed cookie with this name",
+        conflicts_with = "sticky_header"
+    )]
+    sticky_cookie: Option<String>,
+
+    #[arg(
+        long,
+        help = "Secret for signing --sticky-cookie cookies (random if not given, so cookies don't \
+                survive a restart)",
+        requires = "sticky_cookie"
+    )]
+    sticky_secret: Option<String>,
+
+    #[arg(
+        long,
+        help = "In http mode, send all requests with the same value of this header to the same \
+                upstream"
+    )]
+    sticky_header: Option<String>,
+
+    #[arg(
+        long,
+        help = "Expect connections from this IP address or CIDR block to start with a PROXY \
+                protocol header naming the real client"
+    )]
+    proxy_protocol_from: Vec<cidr::Cidr>,
+
+    #[arg(
+        long,
+        help = "How long (in milliseconds) to wait for a PROXY protocol header before dropping \
+                the connection",
+        default_value = "5000"
+    )]
+    proxy_protocol_timeout: u64,
+
+    #[arg(
+        long,
+        help = "In http and tcp mode, send a PROXY protocol header of this version when \
+                connecting to upstreams",
+        value_enum
+    )]
+    proxy_protocol_to: Option<proxy_protocol::Version>,
+
+    #[arg(
+        long,
+        help = "JSON file of per-route settings for http mode. Reloaded on SIGHUP or through the \
+                admin interface"
+    )]
+    config: Option<String>,
+
+    #[arg(
+        long,
+        help = "IP/port (or unix:/path) to serve the admin interface on, which exposes metrics \
+                and lets the config be reloaded"
+    )]
+    admin_bind: Option<String>,
+
+    #[arg(
+        long,
+        help = "In http mode, send copies of requests to this shadow upstream, discarding its \
+                responses but comparing them with the real ones in the metrics"
+    )]
+    mirror: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Percentage of requests to copy to the --mirror upstreams",
+        default_value = "100"
+    )]
+    mirror_percent: f64,
+
+    #[arg(
+        long,
+        help = "Canary upstream to send a share of traffic to (see --canary-percent). Canary \
+                upstreams are health checked along with the others"
+    )]
+    canary: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Percentage of requests (or connections, in tcp mode) to send to the --canary \
+                upstreams. Can be changed through the admin interface",
+        default_value = "0"
+    )]
+    canary_percent: f64,
+
+    #[arg(
+        long,
+        help = "Pick requests for the canary by hashing this header, so each caller consistently \
+                gets the same pool",
+        conflicts_with = "canary_cookie"
+    )]
+    canary_header: Option<String>,
+
+    #[arg(
+        long,
+        help = "Pick requests for the canary by hashing this cookie, so each caller consistently \
+                gets the same pool"
+    )]
+    canary_cookie: Option<String>,
+
+    #[arg(
+        long,
+        help = "In http mode, temporarily eject upstreams whose p99 response time is more than \
+                this many times the median p99 of all upstreams"
+    )]
+    outlier_latency_multiple: Option<f64>,
+
+    #[arg(
+        long,
+        help = "Most upstreams (as a percentage of all of them) that may be ejected as outliers \
+                at once. At least one upstream is always left in",
+        default_value = "50"
+    )]
+    outlier_max_ejection_percent: f64,
+
+    #[arg(
+        long,
+        help = "How long (in seconds) an outlier stays ejected",
+        default_value = "30"
+    )]
+    outlier_ejection_time: u64,
+
+    #[arg(
+        long,
+        help = "In http mode, most requests to send each upstream at once. Requests over the \
+                limit wait in a queue (0 means no limit)",
+        default_value = "0"
+    )]
+    max_in_flight_per_upstream: usize,
+
+    #[arg(
+        long,
+        help = "Adjust each upstream's limit on requests in flight to how quickly it is answering, \
+                starting from (and never going above) --max-in-flight-per-upstream"
+    )]
+    adaptive_concurrency: bool,
+
+    #[arg(
+        long,
+        help = "In http mode, shed requests (with a 503) once this many client connections are \
+                open, low priority ones first. Critical requests are never shed"
+    )]
+    shed_max_connections: Option<usize>,
+
+    #[arg(
+        long,
+        help = "In http mode, shed requests (with a 503) once the event loop falls this many \
+                milliseconds behind, low priority ones first. Critical requests are never shed"
+    )]
+    shed_max_loop_lag: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Header giving a request's priority (low, normal or critical) for load shedding, \
+                in place of its route's priority. Only clients in --priority-header-allow can use \
+                it to raise a request's priority"
+    )]
+    priority_header: Option<String>,
+
+    #[arg(
+        long,
+        help = "Let clients from this IP address or CIDR block raise their requests' priority \
+                above their route's with --priority-header"
+    )]
+    priority_header_allow: Vec<cidr::Cidr>,
+
+    #[arg(
+        long,
+        help = "In http mode, let clients from this IP address or CIDR block inject faults into \
+                their own requests with the x-balancebeam-fault-delay (milliseconds) and \
+                x-balancebeam-fault-abort (a status code, or \"reset\") headers"
+    )]
+    fault_header_allow: Vec<cidr::Cidr>,
+
+    #[arg(
+        long,
+        help = "Most requests that may wait for each upstream when it is at \
+                --max-in-flight-per-upstream. Requests beyond that get a 503",
+        default_value = "100"
+    )]
+    max_queue_per_upstream: usize,
+
+    #[arg(
+        long,
+        help = "How long (in milliseconds) a request may wait in an upstream's queue before it \
+                gets a 503",
+        default_value = "1000"
+    )]
+    queue_timeout: u64,
 }
 
 /// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
@@ -60,6 +414,8 @@ struct CmdOptions {
 /// You should add fields to this struct in later milestones.
 #[derive(Clone)]
 struct ProxyState {
+    /// Kind of traffic we are proxying
+    mode: Mode,
     /// How frequently we check whether upstream servers are alive (Milestone 4)
     #[allow(dead_code)]
     active_health_check_interval: usize,
@@ -69,12 +425,53 @@ struct ProxyState {
     /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
     #[allow(dead_code)]
     max_requests_per_minute: usize,
-    /// Addresses of servers that we are proxying to
-    upstream_addresses: Vec<String>,
+    /// Addresses of servers that we are proxying to. This changes over time if upstream discovery
+    /// is enabled.
+    upstream_addresses: Arc<RwLock<Vec<String>>>,
     // Alive of upstream
     alive_upstreams: Arc<RwLock<HashSet<String>>>,
     // Rate limit
     rate_limit_map: Arc<Mutex<HashMap<String, u32>>>,
+    /// Destinations that clients may open tunnels to in connect mode
+    connect_allowlist: Vec<String>,
+    /// Terminates TLS from clients, if a certificate was configured
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+    /// How requests are pinned to upstreams, if sticky sessions are enabled
+    affinity: Option<affinity::Affinity>,
+    /// Sources that are trusted to tell us the real client's address with the PROXY protocol
+    proxy_protocol_trusted: Vec<cidr::Cidr>,
+    /// How long a trusted source has to send its PROXY protocol header
+    proxy_protocol_timeout: Duration,
+    /// Version of the PROXY protocol header to introduce upstream connections with, if any
+    proxy_protocol_version: Option<proxy_protocol::Version>,
+    /// Where per-route settings are loaded from, if anywhere
+    config_path: Option<String>,
+    /// Per-route settings, replaced whenever the config is reloaded
+    routes: Arc<RwLock<Vec<Arc<routes::Route>>>>,
+    /// Custom pages for errors, by status code, replaced whenever the config is reloaded
+    error_pages: Arc<RwLock<HashMap<u16, error_pages::ErrorPage>>>,
+    /// Counters exposed on the admin interface
+    metrics: metrics::Metrics,
+    /// Size limits for requests, unless a route overrides them
+    limits: request::Limits,
+    /// Size limits for responses from upstreams
+    response_limits: request::Limits,
+    /// How many requests a client may send over one connection before we close it (0 for no limit)
+    max_requests_per_connection: usize,
+    /// How many streams an HTTP/2 client may have open at once on one connection
+    max_concurrent_streams: u32,
+    /// Where copies of requests are sent, if traffic mirroring is enabled
+    mirror: Option<mirror::Mirror>,
+    /// How traffic is split between the stable and canary upstreams, if there are canaries
+    canary: Option<Arc<canary::Split>>,
+    /// Tracks upstream response times and ejects slow upstreams, if enabled
+    outliers: Option<Arc<outlier::Detector>>,
+    /// Caps how many requests each upstream gets at once, if enabled
+    concurrency: Option<Arc<concurrency::Limiter>>,
+    /// Turns requests away when we are overloaded, if enabled
+    shedder: Option<Arc<shedding::Shedder>>,
+    /// Delays and errors injected into requests for testing clients
+    faults: Arc<faults::Injector>,
 }
 
 #[tokio::main]
@@ -82,60 +479,366 @@ async fn main() {
     // Initialize the logging library. You can print log messages using the `log` macros:
     // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
     // just looks a little prettier.
-    if let Err(_) = std::env::var("RUST_LOG") {
+    if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "debug");
     }
     pretty_env_logger::init();
 
     // Parse the command line arguments passed to this program
     let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
+    if let Some(Command::Bench(bench_options)) = options.command {
+        bench::run(bench_options).await;
+        return;
+    }
+    let mode = options.mode;
+    let mut discovery_providers = Vec::new();
+    for host in options.discover_dns {
+        discovery_providers.push(discovery::Provider::Dns(host));
+    }
+    for path in options.discover_file {
+        discovery_providers.push(discovery::Provider::File(path));
+    }
+    match mode {
+        Mode::Http | Mode::Tcp | Mode::Udp
+            if options.upstream.is_empty() && discovery_providers.is_empty() =>
+        {
+            log::error!(
+                "At least one upstream server must be specified using the --upstream option, or \
+                 discovered using --discover-dns or --discover-file."
+            );
+            std::process::exit(1);
+        }
+        Mode::Connect if options.connect_allow.is_empty() => {
+            log::error!(
+                "At least one destination must be allowed using the --connect-allow option."
+            );
+            std::process::exit(1);
+        }
+        Mode::Udp
+            if stream::unix_path(&options.bind).is_some()
+                || options
+                    .upstream
+                    .iter()
+                    .any(|upstream| stream::unix_path(upstream).is_some()) =>
+        {
+            log::error!("Unix domain sockets are not supported in udp mode.");
+            std::process::exit(1);
+        }
+        _ => {}
+    }
+
+    if options.discovery_interval == 0 {
+        log::error!("--discovery-interval must be above 0.");
         std::process::exit(1);
     }
 
-    // Start listening for connections
-    let listener = match TcpListener::bind(&options.bind).await {
-        Ok(listener) => listener,
-        Err(err) => {
-            log::error!("Could not bind to {}: {}", options.bind, err);
-            std::process::exit(1);
+    if mode == Mode::Udp && (options.udp_session_timeout == 0 || options.udp_max_sessions == 0) {
+        log::error!("--udp-session-timeout and --udp-max-sessions must be above 0.");
+        std::process::exit(1);
+    }
+
+    let tls_acceptor = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert_path), Some(key_path)) => match tls::load_acceptor(cert_path, key_path) {
+            Ok(acceptor) => Some(acceptor),
+            Err(err) => {
+                log::error!("Could not load TLS certificate and key: {}", err);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+
+    let affinity = if let Some(name) = options.sticky_cookie {
+        match affinity::Affinity::cookie(name.clone(), options.sticky_secret) {
+            Some(affinity) => Some(affinity),
+            None => {
+                log::error!("{:?} is not a valid cookie name", name);
+                std::process::exit(1);
+            }
         }
+    } else if let Some(name) = options.sticky_header {
+        match affinity::Affinity::header(&name) {
+            Some(affinity) => Some(affinity),
+            None => {
+                log::error!("{:?} is not a valid header name", name);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
     };
-    log::info!("Listening for requests on {}", options.bind);
 
-    // Handle incoming connections
-    let hashd_upstreams = options.upstream.clone().into_iter().collect();
+    if !(0.0..=100.0).contains(&options.mirror_percent) {
+        log::error!("--mirror-percent must be between 0 and 100.");
+        std::process::exit(1);
+    }
+    if !(0.0..=100.0).contains(&options.canary_percent) {
+        log::error!("--canary-percent must be between 0 and 100.");
+        std::process::exit(1);
+    }
+    let canary = if options.canary.is_empty() {
+        None
+    } else {
+        let selector = if let Some(name) = &options.canary_header {
+            match http::HeaderName::from_bytes(name.as_bytes()) {
+                Ok(name) => canary::Selector::Header(name),
+                Err(_) => {
+                    log::error!("{:?} is not a valid header name", name);
+                    std::process::exit(1);
+                }
+            }
+        } else if let Some(name) = options.canary_cookie {
+            canary::Selector::Cookie(name)
+        } else {
+            canary::Selector::Counter
+        };
+        Some(Arc::new(canary::Split::new(
+            options.canary.clone(),
+            options.canary_percent,
+            selector,
+        )))
+    };
+    if !(0.0..=100.0).contains(&options.outlier_max_ejection_percent) {
+        log::error!("--outlier-max-ejection-percent must be between 0 and 100.");
+        std::process::exit(1);
+    }
+    let outliers = options.outlier_latency_multiple.map(|multiple| {
+        Arc::new(outlier::Detector::new(
+            multiple,
+            options.outlier_max_ejection_percent,
+            Duration::from_secs(options.outlier_ejection_time),
+        ))
+    });
+
+    if options.adaptive_concurrency && options.max_in_flight_per_upstream == 0 {
+        log::error!("--adaptive-concurrency needs a --max-in-flight-per-upstream to start from.");
+        std::process::exit(1);
+    }
+    let concurrency = if options.max_in_flight_per_upstream == 0 {
+        None
+    } else {
+        Some(Arc::new(concurrency::Limiter::new(
+            options.max_in_flight_per_upstream,
+            options.adaptive_concurrency,
+            options.max_queue_per_upstream,
+            Duration::from_millis(options.queue_timeout),
+        )))
+    };
+
+    if options.shed_max_connections == Some(0) || options.shed_max_loop_lag == Some(0) {
+        log::error!("--shed-max-connections and --shed-max-loop-lag must be above 0.");
+        std::process::exit(1);
+    }
+    let priority_header = options.priority_header.as_ref().map(|name| {
+        http::HeaderName::from_bytes(name.as_bytes()).unwrap_or_else(|_| {
+            log::error!("{:?} is not a valid header name", name);
+            std::process::exit(1);
+        })
+    });
+    let shedder = if options.shed_max_connections.is_none() && options.shed_max_loop_lag.is_none() {
+        None
+    } else {
+        Some(Arc::new(shedding::Shedder::new(
+            options.shed_max_connections,
+            options.shed_max_loop_lag.map(Duration::from_millis),
+            priority_header,
+            options.priority_header_allow,
+        )))
+    };
+
+    // Canaries are upstreams like any other, and are only told apart when choosing one
+    let mut static_upstreams = options.upstream;
+    static_upstreams.extend(options.canary);
+
+    let mirror = if options.mirror.is_empty() {
+        None
+    } else {
+        Some(mirror::Mirror::new(options.mirror, options.mirror_percent))
+    };
+
+    let config = match &options.config {
+        Some(path) => match routes::load(path) {
+            Ok(config) => config,
+            Err(err) => {
+                log::error!("Could not load config from {}: {}", path, err);
+                std::process::exit(1);
+            }
+        },
+        None => routes::Config::default(),
+    };
+
+    let hashd_upstreams = static_upstreams.clone().into_iter().collect();
     let state = ProxyState {
-        upstream_addresses: options.upstream,
+        mode,
+        upstream_addresses: Arc::new(RwLock::new(static_upstreams.clone())),
         active_health_check_interval: options.active_health_check_interval,
         active_health_check_path: options.active_health_check_path,
         max_requests_per_minute: options.max_requests_per_minute,
         alive_upstreams: Arc::new(RwLock::new(hashd_upstreams)),
         rate_limit_map: Arc::new(Mutex::new(HashMap::new())),
+        connect_allowlist: options.connect_allow,
+        tls_acceptor,
+        affinity,
+        proxy_protocol_trusted: options.proxy_protocol_from,
+        proxy_protocol_timeout: Duration::from_millis(options.proxy_protocol_timeout),
+        proxy_protocol_version: options.proxy_protocol_to,
+        config_path: options.config,
+        routes: Arc::new(RwLock::new(
+            config.routes.into_iter().map(Arc::new).collect(),
+        )),
+        error_pages: Arc::new(RwLock::new(config.error_pages)),
+        metrics: metrics::Metrics::default(),
+        limits: request::Limits {
+            max_headers_size: options.max_headers_size,
+            max_num_headers: options.max_num_headers,
+            max_uri_length: options.max_uri_length,
+            max_body_size: options.max_body_size,
+        },
+        response_limits: request::Limits {
+            max_headers_size: options.max_headers_size,
+            max_num_headers: options.max_num_headers,
+            max_uri_length: options.max_uri_length,
+            max_body_size: options.max_response_body_size,
+        },
+        max_requests_per_connection: options.max_requests_per_connection,
+        max_concurrent_streams: options.max_concurrent_streams,
+        mirror,
+        canary,
+        outliers,
+        concurrency,
+        shedder,
+        faults: Arc::new(faults::Injector::new(options.fault_header_allow)),
     };
 
-    let tmp_state = state.clone();
-    tokio::spawn(async move {
-        health_check(&tmp_state).await;
-    });
+    if let Some(admin_bind) = options.admin_bind {
+        tokio::spawn(admin::serve(admin_bind, state.clone()));
+    }
+    if state.config_path.is_some() {
+        let tmp_state = state.clone();
+        tokio::spawn(async move {
+            reload_on_sighup(&tmp_state).await;
+        });
+    }
+
+    if let Some(shedder) = &state.shedder {
+        tokio::spawn(shedding::measure_loop_lag(shedder.clone()));
+    }
+
+    if state.outliers.is_some() {
+        tokio::spawn(outlier::run(state.clone()));
+    }
+
+    if !discovery_providers.is_empty() {
+        tokio::spawn(discovery::run(
+            discovery_providers,
+            static_upstreams,
+            Duration::from_secs(options.discovery_interval),
+            state.clone(),
+        ));
+    }
+
+    if mode != Mode::Connect {
+        let tmp_state = state.clone();
+        tokio::spawn(async move {
+            health_check(&tmp_state).await;
+        });
+    }
 
     let tmp_state = state.clone();
     tokio::spawn(async move {
         ramte_limit_map_clear(&tmp_state).await;
     });
 
+    // Datagrams don't arrive over connections, so UDP mode has its own receive loop
+    if mode == Mode::Udp {
+        let session_timeout = Duration::from_secs(options.udp_session_timeout);
+        udp::serve(
+            &options.bind,
+            session_timeout,
+            options.udp_max_sessions,
+            state,
+        )
+        .await;
+        return;
+    }
+
+    // Start listening for connections
+    let listener = match Listener::bind(&options.bind).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("Could not bind to {}: {}", options.bind, err);
+            std::process::exit(1);
+        }
+    };
+    log::info!("Listening for requests on {}", options.bind);
+
+    // Handle incoming connections
     loop {
-        if let Ok((stream, _)) = listener.accept().await {
+        if let Ok((mut stream, mut client_addrs)) = listener.accept().await {
             let state = state.clone();
             // Handle the connection!
             tokio::spawn(async move {
-                handle_connection(stream, &state).await;
+                let _connection = state
+                    .shedder
+                    .as_ref()
+                    .map(|shedder| shedder.connection_opened());
+                // A trusted proxy in front of us starts each connection by telling us who the real
+                // client is. Don't let a connection that never does hold its slot forever.
+                if let Some(addrs) = client_addrs {
+                    if cidr::any_contains(&state.proxy_protocol_trusted, &addrs.source.ip()) {
+                        let header = tokio::time::timeout(
+                            state.proxy_protocol_timeout,
+                            proxy_protocol::read_header(&mut stream),
+                        )
+                        .await
+                        .unwrap_or_else(|_| {
+                            Err(std::io::Error::new(
+                                std::io::ErrorKind::TimedOut,
+                                "timed out waiting for a PROXY protocol header",
+                            ))
+                        });
+                        match header {
+                            Ok(Some(real_addrs)) => client_addrs = Some(real_addrs),
+                            Ok(None) => {}
+                            Err(err) => {
+                                log::info!(
+                                    "Dropping connection from {}: {}",
+                                    addrs.source.ip(),
+                                    err
+                                );
+                                return;
+                            }
+                        }
+                    }
+                }
+                let client_ip = stream::client_ip(client_addrs.as_ref());
+                match mode {
+                    Mode::Http => serve_http(stream, client_ip, client_addrs, &state).await,
+                    Mode::Connect => connect::handle_connection(stream, client_ip, &state).await,
+                    Mode::Tcp => {
+                        tcp::handle_connection(stream, client_ip, client_addrs, &state).await
+                    }
+                    Mode::Udp => unreachable!("UDP mode does not accept connections"),
+                }
             });
         }
     }
 }
 
+/// Reloads the config file every time balancebeam receives SIGHUP.
+async fn reload_on_sighup(state: &ProxyState) {
+    let mut hangups = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            log::error!("Could not listen for SIGHUP: {}", err);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        let _ = routes::reload(state).await;
+    }
+}
+
 async fn ramte_limit_map_clear(state: &ProxyState) {
     loop {
         sleep(Duration::from_secs(60)).await;
@@ -151,198 +854,692 @@ async fn health_check(state: &ProxyState) {
         ))
         .await;
 
-        let mut alive_upstreams = state.alive_upstreams.write().await;
-        alive_upstreams.clear();
-
-        for upstream_ip in &state.upstream_addresses {
-            let req = http::Request::builder()
-                .method(http::Method::GET)
-                .uri(&state.active_health_check_path)
-                .header("Host", upstream_ip)
-                .body(Vec::new())
-                .unwrap();
-
-            match TcpStream::connect(upstream_ip).await {
-                Ok(mut stream) => {
-                    if let Err(err) = request::write_to_stream(&req, &mut stream).await {
-                        log::error!(
-                            "Failed to send request to upstream {}: {}",
-                            upstream_ip,
-                            err
-                        );
-                        continue;
-                    }
+        // Probe every upstream before touching alive_upstreams, so that slow probes (e.g. UDP
+        // probes waiting out their timeout) don't hold up requests in the meantime
+        let probed_upstreams = state.upstream_addresses.read().await.clone();
+        let mut healthy_upstreams = HashSet::new();
+        for upstream_ip in &probed_upstreams {
+            let healthy = match state.mode {
+                Mode::Tcp => tcp::probe_upstream(state, upstream_ip).await,
+                Mode::Udp => udp::probe_upstream(upstream_ip).await,
+                _ => probe_upstream_http(state, upstream_ip).await,
+            };
+            if healthy {
+                healthy_upstreams.insert(upstream_ip.to_string());
+            }
+        }
 
-                    match response::read_from_stream(&mut stream, &req.method()).await {
-                        Ok(response) => match response.status().as_u16() {
-                            200 => {
-                                alive_upstreams.insert(upstream_ip.to_string());
-                            }
-                            status @ _ => {
-                                log::error!(
-                                    "health check upstream server: {} : {}",
-                                    upstream_ip,
-                                    status
-                                );
-                            }
-                        },
-                        Err(error) => {
-                            log::error!("Error read from stream {:?}", error);
-                            continue;
-                        }
-                    }
-                }
-                Err(err) => {
-                    log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
-                    continue;
-                }
+        // Discovery may have changed the upstream list while we were probing. Forget upstreams
+        // that were removed, and leave ones that were added alive until the next check.
+        let upstream_addresses = state.upstream_addresses.read().await;
+        healthy_upstreams.retain(|upstream_ip| upstream_addresses.contains(upstream_ip));
+        for upstream_ip in upstream_addresses.iter() {
+            if !probed_upstreams.contains(upstream_ip) {
+                healthy_upstreams.insert(upstream_ip.clone());
+            }
+        }
+        *state.alive_upstreams.write().await = healthy_upstreams;
+    }
+}
+
+/// Sends a request for active_health_check_path to the given upstream, returning true if it
+/// responds with 200 OK.
+async fn probe_upstream_http(state: &ProxyState, upstream_ip: &str) -> bool {
+    // A Unix domain socket address means nothing to the upstream as a Host header
+    let host = match stream::unix_path(upstream_ip) {
+        Some(_) => "localhost",
+        None => upstream_ip,
+    };
+    let req = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(&state.active_health_check_path)
+        .header("Host", host)
+        .body(Vec::new())
+        .unwrap();
+
+    let mut stream = match open_upstream_conn(state, upstream_ip, None).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
+            return false;
+        }
+    };
+    if let Err(err) = request::write_to_stream(&req, &mut stream).await {
+        log::error!(
+            "Failed to send request to upstream {}: {}",
+            upstream_ip,
+            err
+        );
+        return false;
+    }
+
+    match response::read_from_stream(&mut stream, req.method(), &state.response_limits).await {
+        Ok(response) => match response.status().as_u16() {
+            200 => true,
+            status => {
+                log::error!("health check upstream server: {} : {}", upstream_ip, status);
+                false
             }
+        },
+        Err(error) => {
+            log::error!("Error read from stream {}", error);
+            false
         }
     }
 }
 
-async fn connect_to_upstream(state: &ProxyState) -> Result<TcpStream, std::io::Error> {
+/// Picks a random upstream from the ones that are currently believed to be alive, or None if they
+/// have all failed. If a pool is given, the upstream is picked from that pool unless none of its
+/// upstreams are alive. Upstreams ejected as outliers are only picked if there is nothing else.
+async fn choose_upstream(state: &ProxyState, pool: Option<canary::Pool>) -> Option<String> {
     let mut rng = rand::rngs::StdRng::from_entropy();
-    loop {
-        let alive_upstreams = state.alive_upstreams.read().await;
+    let alive_upstreams = state.alive_upstreams.read().await;
+    let mut candidates: Vec<&String> = alive_upstreams
+        .iter()
+        .filter(|upstream_ip| {
+            !state
+                .outliers
+                .as_ref()
+                .is_some_and(|outliers| outliers.is_ejected(upstream_ip))
+        })
+        .collect();
+    if candidates.is_empty() {
+        candidates = alive_upstreams.iter().collect();
+    }
+    if let (Some(pool), Some(split)) = (pool, &state.canary) {
+        let in_pool = candidates
+            .iter()
+            .filter(|upstream_ip| split.pool_of(upstream_ip) == pool)
+            .choose(&mut rng);
+        if in_pool.is_some() {
+            return in_pool.map(|upstream_ip| upstream_ip.to_string());
+        }
+        log::warn!(
+            "No {} upstreams are alive; using the other pool",
+            pool.name()
+        );
+    }
+    candidates.into_iter().choose(&mut rng).cloned()
+}
 
-        if let Some(upstream_ip) = alive_upstreams.clone().iter().choose(&mut rng) {
-            drop(alive_upstreams);
+/// Decides which pool the request (or connection, if there is no request) should go to, if
+/// traffic is being split between stable and canary upstreams.
+fn choose_pool<T>(state: &ProxyState, request: Option<&http::Request<T>>) -> Option<canary::Pool> {
+    let pool = state.canary.as_ref()?.choose_pool(request);
+    state.metrics.increment(
+        "balancebeam_canary_requests_total",
+        &[("pool", pool.name())],
+    );
+    Some(pool)
+}
 
-            match TcpStream::connect(upstream_ip).await {
-                Ok(stream) => return Ok(stream),
-                Err(err) => {
-                    log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
+/// Opens a connection to the given upstream, introducing it with a PROXY protocol header (naming
+/// the client, if we know its address) if configured to.
+async fn open_upstream_conn(
+    state: &ProxyState,
+    upstream_ip: &str,
+    client_addrs: Option<&ConnectionAddrs>,
+) -> Result<Stream, std::io::Error> {
+    let mut stream = Stream::connect(upstream_ip).await?;
+    if let Some(version) = state.proxy_protocol_version {
+        stream
+            .write_all(&proxy_protocol::encode_header(version, client_addrs))
+            .await?;
+    }
+    Ok(stream)
+}
 
-                    let mut alive_upstreams = state.alive_upstreams.write().await;
-                    alive_upstreams.remove(upstream_ip);
+/// Returns the upstream this request is pinned to by sticky sessions, if there is one and it is
+/// still alive.
+async fn pinned_upstream<T>(state: &ProxyState, request: &http::Request<T>) -> Option<String> {
+    let affinity = state.affinity.as_ref()?;
+    let alive_upstreams = state.alive_upstreams.read().await;
+    affinity.pinned_upstream(request, &alive_upstreams)
+}
 
-                    if alive_upstreams.len() == 0 {
-                        log::error!("Failed to connect to upstream: empty alive_upstreams");
-                        return Err(err);
-                    }
+/// Returns the upstream the given request is pinned to, or a random alive upstream (in the given
+/// pool, if any) if it isn't pinned (or there is no request to go by).
+async fn target_upstream<T>(
+    state: &ProxyState,
+    request: Option<&http::Request<T>>,
+    pool: Option<canary::Pool>,
+) -> Option<String> {
+    let pinned = match request {
+        Some(request) => pinned_upstream(state, request).await,
+        None => None,
+    };
+    match pinned {
+        Some(upstream_ip) => Some(upstream_ip),
+        None => choose_upstream(state, pool).await,
+    }
+}
+
+/// Marks an upstream that couldn't be reached as dead. Returns false if no upstreams are left.
+async fn mark_unreachable(state: &ProxyState, upstream_ip: &str) -> bool {
+    let mut alive_upstreams = state.alive_upstreams.write().await;
+    alive_upstreams.remove(upstream_ip);
+    if alive_upstreams.is_empty() {
+        log::error!("Failed to connect to upstream: empty alive_upstreams");
+        return false;
+    }
+    true
+}
+
+/// Connects to the upstream the given request is pinned to, or to a random alive upstream (in the
+/// given pool, if any) if it isn't pinned. Upstreams that can't be reached are marked dead and
+/// another is chosen. A slot on the upstream is waited for before connecting to it, so that
+/// requests queued for a slot don't hold upstream connections open. Returns the connection along
+/// with the upstream's address and the slot, or the error to send the client if no slot came free
+/// (503) or no upstream could be reached (502).
+async fn connect_with_slot<T>(
+    state: &ProxyState,
+    request: &http::Request<T>,
+    pool: Option<canary::Pool>,
+    client_addrs: Option<&ConnectionAddrs>,
+    request_id: &str,
+) -> Result<(Stream, String, Option<concurrency::Permit>), http::Response<Vec<u8>>> {
+    loop {
+        let upstream_ip = match target_upstream(state, Some(request), pool).await {
+            Some(upstream_ip) => upstream_ip,
+            None => {
+                log::error!("Failed to connect to upstream: empty alive_upstreams");
+                break;
+            }
+        };
+        let slot = reserve_slot(state, &upstream_ip, request_id).await?;
+        match open_upstream_conn(state, &upstream_ip, client_addrs).await {
+            Ok(stream) => return Ok((stream, upstream_ip, slot)),
+            Err(err) => {
+                log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
+                // The slot is given back without a response, which counts against the upstream
+                drop(slot);
+                if !mark_unreachable(state, &upstream_ip).await {
+                    break;
                 }
             }
-        } else {
-            log::error!("Failed to connect to upstream: empty alive_upstreams");
-            return Err(Error::new(ErrorKind::Other, "empty alive_upstreams"));
         }
     }
+    Err(error_pages::make_error(state, http::StatusCode::BAD_GATEWAY, request_id).await)
 }
 
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+/// Counts a request from the given client against its per-minute budget, returning true if the
+/// client has gone over max_requests_per_minute and the request should be rejected.
+async fn is_rate_limited(state: &ProxyState, client_ip: &str) -> bool {
+    if state.max_requests_per_minute == 0 {
+        return false;
+    }
+    let mut rate_limit_map = state.rate_limit_map.lock().await;
+    let cnt = rate_limit_map.entry(client_ip.to_string()).or_insert(0);
+    *cnt += 1;
+    *cnt as usize > state.max_requests_per_minute
+}
+
+/// Turns the request away if we are too busy to handle requests of its priority, returning the
+/// error to send the client.
+async fn shed_load<T>(
+    state: &ProxyState,
+    request: &http::Request<T>,
+    request_id: &str,
+    client_ip: &str,
+) -> Option<http::Response<Vec<u8>>> {
+    let shedder = state.shedder.as_ref()?;
+    let route_priority = routes::priority(state, request).await;
+    let priority = shedder.classify(request, route_priority, client_ip);
+    if !shedder.should_shed(priority) {
+        return None;
+    }
+    log::warn!("Shedding {} priority request", priority.as_str());
+    state.metrics.increment(
+        "balancebeam_shed_requests_total",
+        &[("priority", priority.as_str())],
+    );
+    Some(error_pages::make_error(state, http::StatusCode::SERVICE_UNAVAILABLE, request_id).await)
+}
+
+/// Turns the request away if we are shedding load, its route doesn't let the client in, or the
+/// client has gone over its rate limit, returning the error to send the client. Only the request's
+/// head is needed, so this is done before the body is read.
+async fn admit_request<T>(
+    state: &ProxyState,
+    request: &mut http::Request<T>,
+    request_id: &str,
+    client_ip: &str,
+) -> Result<(), http::Response<Vec<u8>>> {
+    if let Some(response) = shed_load(state, request, request_id, client_ip).await {
+        return Err(response);
+    }
+    routes::screen_request(state, request, request_id, client_ip).await?;
+    if is_rate_limited(state, client_ip).await {
+        return Err(error_pages::make_error(
+            state,
+            http::StatusCode::TOO_MANY_REQUESTS,
+            request_id,
+        )
+        .await);
+    }
+    Ok(())
+}
+
+/// Waits for a slot on the upstream if the number of requests in flight to each upstream is
+/// capped, returning the slot (to be held until the response has been read) or the error to send
+/// the client if none came free.
+async fn reserve_slot(
+    state: &ProxyState,
+    upstream_ip: &str,
+    request_id: &str,
+) -> Result<Option<concurrency::Permit>, http::Response<Vec<u8>>> {
+    let limiter = match &state.concurrency {
+        Some(limiter) => limiter,
+        None => return Ok(None),
+    };
+    match limiter.acquire(upstream_ip, &state.metrics).await {
+        Ok(permit) => Ok(Some(permit)),
+        Err(rejection) => {
+            log::warn!("No slot for request on {}: {:?}", upstream_ip, rejection);
+            Err(
+                error_pages::make_error(state, http::StatusCode::SERVICE_UNAVAILABLE, request_id)
+                    .await,
+            )
+        }
+    }
+}
+
+async fn send_response(
+    client_conn: &mut (impl AsyncWrite + Unpin),
+    client_ip: &str,
+    response: &http::Response<Vec<u8>>,
+) {
     log::info!(
         "{} <- {}",
         client_ip,
-        response::format_response_line(&response)
+        response::format_response_line(response)
     );
-    if let Err(error) = response::write_to_stream(&response, client_conn).await {
+    if let Err(error) = response::write_to_stream(response, client_conn).await {
         log::warn!("Failed to send response to client: {}", error);
+    }
+}
+
+/// Answers a request that couldn't be read. Returns false if the connection can't be used for
+/// any more requests, either because the client has gone or because we can't tell where the next
+/// request would start.
+async fn reject_unreadable_request(
+    client_conn: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    client_ip: &str,
+    state: &ProxyState,
+    error: request::Error,
+) -> bool {
+    match error {
+        // Handle case where client closed connection and is no longer sending requests
+        request::Error::IncompleteRequest(0) => {
+            log::debug!("Client finished sending requests. Shutting down connection");
+            false
+        }
+        // Handle I/O error in reading from the client
+        request::Error::Io(io_err) => {
+            log::info!("Error reading request from client stream: {}", io_err);
+            false
+        }
+        error => {
+            log::debug!("Error parsing request: {}", error);
+            let mut response =
+                error_pages::make_error(state, error.status_code(), &request::new_request_id())
+                    .await;
+            if error.leaves_unread_data() {
+                set_connection_header(&mut response, http::Version::HTTP_11, false);
+                send_response(client_conn, client_ip, &response).await;
+                lingering_close(client_conn).await;
+                return false;
+            }
+            send_response(client_conn, client_ip, &response).await;
+            true
+        }
+    }
+}
+
+/// Closes a client connection that may still have unread request data waiting. Closing a socket
+/// with unread data makes the kernel reset the connection, which can destroy a response the
+/// client hasn't read yet, so stop writing and discard whatever else arrives for a moment first.
+async fn lingering_close(client_conn: &mut (impl AsyncRead + AsyncWrite + Unpin)) {
+    let _ = client_conn.shutdown().await;
+    let mut buffer = [0_u8; 4096];
+    let _ = tokio::time::timeout(Duration::from_secs(2), async {
+        while matches!(client_conn.read(&mut buffer).await, Ok(n) if n > 0) {}
+    })
+    .await;
+}
+
+/// Works out which version of HTTP the client is speaking (terminating TLS first, if configured)
+/// and hands the connection off to the matching handler.
+async fn serve_http(
+    client_conn: Stream,
+    client_ip: String,
+    client_addrs: Option<ConnectionAddrs>,
+    state: &ProxyState,
+) {
+    if let Some(tls_acceptor) = &state.tls_acceptor {
+        let tls_conn = match tls_acceptor.accept(client_conn).await {
+            Ok(tls_conn) => tls_conn,
+            Err(err) => {
+                log::info!("TLS handshake with {} failed: {}", client_ip, err);
+                return;
+            }
+        };
+        if tls_conn.get_ref().1.alpn_protocol() == Some(b"h2") {
+            http2::handle_connection(tls_conn, client_ip, client_addrs, state).await;
+        } else {
+            handle_connection(tls_conn, &client_ip, client_addrs, state).await;
+        }
         return;
     }
+
+    match http2::detect_prior_knowledge(client_conn).await {
+        Ok((true, client_conn)) => {
+            http2::handle_connection(client_conn, client_ip, client_addrs, state).await
+        }
+        Ok((false, client_conn)) => {
+            handle_connection(client_conn, &client_ip, client_addrs, state).await
+        }
+        Err(err) => log::info!("Error reading from client {}: {}", client_ip, err),
+    }
 }
 
-async fn handle_connection(mut client_conn: TcpStream, state: &ProxyState) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+async fn handle_connection(
+    client_conn: impl AsyncRead + AsyncWrite + ResetOnClose + Unpin,
+    client_ip: &str,
+    client_addrs: Option<ConnectionAddrs>,
+    state: &ProxyState,
+) {
     log::info!("Connection received from {}", client_ip);
 
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(state).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
-        }
-    };
-    let upstream_ip = upstream_conn.peer_addr().unwrap().ip().to_string();
+    // Clients may pipeline requests, sending the next one before the last one is answered, so
+    // whatever is read past the end of one request is put back to be read as part of the next
+    let mut client_conn = PrefixedStream::new(Vec::new(), client_conn);
+
+    // The upstream connection is opened once we know which upstream the first request should go
+    // to, and is then reused for later requests unless sticky sessions pin one of them elsewhere,
+    // it is assigned to the other side of a canary split, or the upstream closes it
+    let mut upstream: Option<(PrefixedStream<Stream>, String)> = None;
+    let mut requests_read = 0;
 
     // The client may now send us one or more requests. Keep trying to read requests until the
-    // client hangs up or we get an error.
+    // client hangs up, asks us to close the connection, or we get an error.
     loop {
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
+        // Read a request's head from the client
+        let mut request = match routes::read_request_head(&mut client_conn, state).await {
             Ok(request) => request,
-            // Handle case where client closed connection and is no longer sending requests
-            Err(request::Error::IncompleteRequest(0)) => {
-                log::debug!("Client finished sending requests. Shutting down connection");
+            Err(error) => {
+                if reject_unreadable_request(&mut client_conn, client_ip, state, error).await {
+                    continue;
+                }
                 return;
             }
-            // Handle I/O error in reading from the client
-            Err(request::Error::ConnectionError(io_err)) => {
-                log::info!("Error reading request from client stream: {}", io_err);
+        };
+        requests_read += 1;
+
+        let request_id = request::request_id(&mut request);
+        let client_version = request.version();
+        let mut keep_alive = request::wants_keep_alive(&request)
+            && (state.max_requests_per_connection == 0
+                || requests_read < state.max_requests_per_connection);
+
+        // Decide whether to serve the request before reading its body, so that a client waiting
+        // for 100 Continue isn't told to send a body only to have the request refused
+        let admitted = match admit_request(state, &mut request, &request_id, client_ip).await {
+            Err(mut response) if matches!(request::expects_continue(&request), Ok(true)) => {
+                // The client may send the body anyway, so we can't tell where the next request
+                // would start
+                set_connection_header(&mut response, client_version, false);
+                send_response(&mut client_conn, client_ip, &response).await;
+                lingering_close(&mut client_conn).await;
                 return;
             }
-            Err(error) => {
-                log::debug!("Error parsing request: {:?}", error);
-                let response = response::make_http_error(match error {
-                    request::Error::IncompleteRequest(_)
-                    | request::Error::MalformedRequest(_)
-                    | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
-                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
-                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
-                });
-                send_response(&mut client_conn, &response).await;
+            admitted => admitted,
+        };
+        if let Err(error) = routes::read_request_body(&mut client_conn, state, &mut request).await {
+            if reject_unreadable_request(&mut client_conn, client_ip, state, error).await {
                 continue;
             }
-        };
-        log::info!(
-            "{} -> {}: {}",
-            client_ip,
-            upstream_ip,
-            request::format_request_line(&request)
-        );
+            return;
+        }
+
+        let mut response = 'response: {
+            if let Err(response) = admitted {
+                break 'response response;
+            }
 
-        if state.max_requests_per_minute > 0 {
+            if let Some(response) = routes::serve_static(state, &request, &request_id).await {
+                log::info!(
+                    "{} -> static: {}",
+                    client_ip,
+                    request::format_request_line(&request)
+                );
+                break 'response response;
+            }
+
+            if let Some(response) =
+                routes::inject_faults(state, &mut request, &request_id, client_ip).await
             {
-                let mut rate_limit_map = state.rate_limit_map.clone().lock_owned().await;
-                let cnt = rate_limit_map.entry(client_ip.to_string()).or_insert(0);
-                *cnt += 1;
-
-                if *cnt > state.max_requests_per_minute.try_into().unwrap() {
-                    let response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
-                    if let Err(error) = response::write_to_stream(&response, &mut client_conn).await
-                    {
-                        log::error!("failed to send response to client: {:?}", error);
-                    }
-                    continue;
-                }
+                break 'response response;
             }
-        }
 
-        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
-        // (We're the ones connecting directly to the upstream server, so without this header, the
-        // upstream server will only know our IP, not the client's.)
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+            let pool = choose_pool(state, Some(&request));
+            let needs_new_upstream = match &upstream {
+                None => true,
+                Some((_, current_ip)) => match pinned_upstream(state, &request).await {
+                    Some(pinned_ip) => &pinned_ip != current_ip,
+                    None => match (pool, &state.canary) {
+                        (Some(pool), Some(split)) => split.pool_of(current_ip) != pool,
+                        _ => false,
+                    },
+                },
+            };
+            let mut slot = if needs_new_upstream {
+                match connect_with_slot(state, &request, pool, client_addrs.as_ref(), &request_id)
+                    .await
+                {
+                    Ok((stream, upstream_ip, slot)) => {
+                        upstream = Some((PrefixedStream::new(Vec::new(), stream), upstream_ip));
+                        slot
+                    }
+                    Err(response) => {
+                        if response.status() == http::StatusCode::BAD_GATEWAY {
+                            keep_alive = false;
+                        }
+                        break 'response response;
+                    }
+                }
+            } else {
+                let (_, upstream_ip) = upstream.as_ref().unwrap();
+                match reserve_slot(state, upstream_ip, &request_id).await {
+                    Ok(slot) => slot,
+                    Err(response) => break 'response response,
+                }
+            };
+            let (upstream_conn, upstream_ip) = upstream.as_mut().unwrap();
+            let upstream_ip = upstream_ip.as_str();
 
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
-            log::error!(
-                "Failed to send request to upstream {}: {}",
+            log::info!(
+                "{} -> {}: {}",
+                client_ip,
                 upstream_ip,
-                error
+                request::format_request_line(&request)
             );
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
-        }
-        log::debug!("Forwarded request to server");
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await
-        {
-            Ok(response) => response,
-            Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
-                return;
+            // Add X-Forwarded-For header so that the upstream server knows the client's IP
+            // address. (We're the ones connecting directly to the upstream server, so without
+            // this header, the upstream server will only know our IP, not the client's.)
+            request::extend_header_value(&mut request, "x-forwarded-for", client_ip);
+            // Our connection to the upstream is persistent whatever the client's is
+            *request.version_mut() = http::Version::HTTP_11;
+
+            let shadow = state
+                .mirror
+                .as_ref()
+                .and_then(|mirror| mirror.start(&request, state));
+            let started = Instant::now();
+
+            // Forward the request to the server
+            if let Err(error) = request::write_to_stream(&request, upstream_conn).await {
+                log::error!(
+                    "Failed to send request to upstream {}: {}",
+                    upstream_ip,
+                    error
+                );
+                keep_alive = false;
+                break 'response error_pages::make_error(
+                    state,
+                    http::StatusCode::BAD_GATEWAY,
+                    &request_id,
+                )
+                .await;
+            }
+            log::debug!("Forwarded request to server");
+
+            // Read the server's response
+            let (interim, mut response) = match response::read_with_interim(
+                upstream_conn,
+                request.method(),
+                &state.response_limits,
+            )
+            .await
+            {
+                Ok(responses) => responses,
+                Err(error) => {
+                    log::error!("Error reading response from server: {}", error);
+                    keep_alive = false;
+                    break 'response error_pages::make_error(
+                        state,
+                        http::StatusCode::BAD_GATEWAY,
+                        &request_id,
+                    )
+                    .await;
+                }
+            };
+            // The upstream may have sent the start of its next response along with this one
+            upstream_conn.unread(response::take_pipelined(&mut response));
+            let latency = started.elapsed();
+            if let Some(slot) = &mut slot {
+                slot.record_response(latency, response.status());
+            }
+            if let Some(outliers) = &state.outliers {
+                outliers.record(upstream_ip, latency);
+            }
+            if let Some(shadow) = shadow {
+                shadow.finish(response.status(), latency);
+            }
+            if let Some(affinity) = &state.affinity {
+                affinity.pin_response(&request, &mut response, upstream_ip);
+            }
+
+            // Pass on interim responses (e.g. 103 Early Hints) ahead of the real one. HTTP/1.0
+            // clients don't understand them, and 100 Continue is no use to the client since we
+            // have already read its whole request.
+            if client_version == http::Version::HTTP_11 {
+                for interim_response in interim.iter().filter(|interim_response| {
+                    interim_response.status() != http::StatusCode::CONTINUE
+                }) {
+                    send_response(&mut client_conn, client_ip, interim_response).await;
+                }
+            }
+
+            // The upstream won't take any more requests on this connection, so the next request
+            // needs a new one
+            if response.status() != http::StatusCode::SWITCHING_PROTOCOLS
+                && request::has_connection_option(response.headers(), "close")
+            {
+                log::debug!("Upstream {} closed the connection", upstream_ip);
+                upstream = None;
             }
+            response
         };
+
+        if response.extensions().get::<faults::Reset>().is_some() {
+            log::info!("Resetting connection to {}", client_ip);
+            client_conn.reset_on_close();
+            return;
+        }
+
         // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
-        log::debug!("Forwarded response to client");
+        set_connection_header(&mut response, client_version, keep_alive);
+        send_response(&mut client_conn, client_ip, &response).await;
+        log::debug!("Sent response to client");
+
+        // If the upstream agreed to switch protocols (e.g. for a WebSocket handshake), the
+        // connection no longer carries HTTP requests and responses, so stop parsing and just
+        // shuttle bytes between the two sides. Any bytes that arrived right after the 101 headers
+        // were kept in the response body and have already been forwarded by send_response.
+        if response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+            if let Some((upstream_conn, upstream_ip)) = upstream.as_mut() {
+                tunnel(&mut client_conn, upstream_conn, client_ip, upstream_ip).await;
+            }
+            return;
+        }
+
+        if !keep_alive {
+            log::debug!("Closing connection to {}", client_ip);
+            // The client may have pipelined more requests we won't answer
+            lingering_close(&mut client_conn).await;
+            return;
+        }
+    }
+}
+
+/// Replaces whatever the upstream said about its connection to us with what the client needs to
+/// know about its connection to us: whether it will be closed after this response. HTTP/1.1
+/// connections stay open unless told otherwise, while HTTP/1.0 clients need to be told they do.
+/// Responses switching protocols keep their `Connection: upgrade`.
+fn set_connection_header(
+    response: &mut http::Response<Vec<u8>>,
+    client_version: http::Version,
+    keep_alive: bool,
+) {
+    if response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+        return;
+    }
+    let headers = response.headers_mut();
+    headers.remove(http::header::CONNECTION);
+    headers.remove("keep-alive");
+    if !keep_alive {
+        headers.insert(
+            http::header::CONNECTION,
+            http::HeaderValue::from_static("close"),
+        );
+    } else if client_version == http::Version::HTTP_10 {
+        headers.insert(
+            http::header::CONNECTION,
+            http::HeaderValue::from_static("keep-alive"),
+        );
+    }
+}
+
+/// Copies bytes in both directions between the client and the upstream until both sides have
+/// closed their connections. This is used once a connection has been upgraded away from HTTP, at
+/// which point balancebeam no longer understands the traffic flowing through it. Returns the
+/// number of bytes sent upstream and to the client, if the tunnel closed cleanly.
+async fn tunnel(
+    client_conn: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    upstream_conn: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    client_ip: &str,
+    upstream_ip: &str,
+) -> Option<(u64, u64)> {
+    log::info!("{} <-> {}: tunneling connection", client_ip, upstream_ip);
+    match tokio::io::copy_bidirectional(client_conn, upstream_conn).await {
+        Ok((to_upstream, to_client)) => {
+            log::debug!(
+                "Tunnel closed after sending {} bytes upstream and {} bytes to the client",
+                to_upstream,
+                to_client
+            );
+            Some((to_upstream, to_client))
+        }
+        Err(err) => {
+            log::info!(
+                "Tunnel between {} and {} closed: {}",
+                client_ip,
+                upstream_ip,
+                err
+            );
+            None
+        }
     }
 }
@@ -0,0 +1,67 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+/// Counters and gauges describing what balancebeam has been doing, exposed in the Prometheus text
+/// format on the admin interface. Each series is identified by a metric name plus a set of labels.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    /// Current value of every series, whether it is a counter or a gauge
+    values: Arc<Mutex<BTreeMap<String, f64>>>,
+}
+
+/// Formats a series name the way Prometheus expects, e.g. `name{label="value"}`.
+fn series(name: &str, labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return name.to_string();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(label, value)| {
+            format!(
+                "{}=\"{}\"",
+                label,
+                value.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+        })
+        .collect();
+    format!("{}{{{}}}", name, labels.join(","))
+}
+
+impl Metrics {
+    /// Adds one to a counter, creating it if this is the first time it has been counted.
+    pub fn increment(&self, name: &str, labels: &[(&str, &str)]) {
+        self.add(name, labels, 1.0);
+    }
+
+    /// Adds an amount to a counter, e.g. to keep the running total of a duration.
+    pub fn add(&self, name: &str, labels: &[(&str, &str)], amount: f64) {
+        *self
+            .values
+            .lock()
+            .entry(series(name, labels))
+            .or_insert(0.0) += amount;
+    }
+
+    /// Sets a gauge, i.e. a series that goes down as well as up, such as a queue's length.
+    pub fn set(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.values.lock().insert(series(name, labels), value);
+    }
+
+    /// Records one observation of a value, as a Prometheus summary without quantiles: the `_sum`
+    /// and `_count` series of the metric.
+    pub fn observe(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.add(&format!("{}_sum", name), labels, value);
+        self.increment(&format!("{}_count", name), labels);
+    }
+
+    /// Renders every series in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        self.values
+            .lock()
+            .iter()
+            .map(|(series, value)| format!("{} {}\n", series, value))
+            .collect()
+    }
+}
@@ -0,0 +1,157 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+use tokio::sync::{oneshot, Semaphore};
+
+use crate::stream::Stream;
+use crate::{request, response, ProxyState};
+
+/// How long to wait for a shadow upstream to answer before giving up on it
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most copies to have in flight at once. Each holds a copy of the body and a connection, so when
+/// the shadow pool falls behind, further copies are dropped rather than piling up.
+const MAX_MIRRORS_IN_FLIGHT: usize = 100;
+
+/// Sends copies of a sample of requests to a pool of shadow upstreams, so that a new version of
+/// an upstream can be tried out with real traffic. The shadow pool's responses are thrown away;
+/// only how they compare with the real upstream's is recorded in the metrics.
+#[derive(Clone)]
+pub struct Mirror {
+    upstreams: Vec<String>,
+    /// Percentage of requests to mirror, from 0 to 100
+    percent: f64,
+    /// Slots for copies in flight
+    in_flight: Arc<Semaphore>,
+}
+
+/// Handle for telling a mirrored request how the real upstream responded, so the two can be
+/// compared. Dropping it without calling finish means the real request failed.
+pub struct Shadow {
+    primary: oneshot::Sender<(http::StatusCode, Duration)>,
+}
+
+impl Shadow {
+    pub fn finish(self, status: http::StatusCode, latency: Duration) {
+        let _ = self.primary.send((status, latency));
+    }
+}
+
+impl Mirror {
+    pub fn new(upstreams: Vec<String>, percent: f64) -> Mirror {
+        Mirror {
+            upstreams,
+            percent,
+            in_flight: Arc::new(Semaphore::new(MAX_MIRRORS_IN_FLIGHT)),
+        }
+    }
+
+    /// Decides whether to mirror this request, and if so, starts sending a copy of it to a shadow
+    /// upstream in the background. The client's request is never held up by the copy.
+    pub fn start(&self, request: &http::Request<Vec<u8>>, state: &ProxyState) -> Option<Shadow> {
+        // A shadow upstream can't take part in a protocol switch
+        if request.headers().contains_key(http::header::UPGRADE) {
+            return None;
+        }
+        if rand::random::<f64>() * 100.0 >= self.percent {
+            return None;
+        }
+        let upstream = self.upstreams.choose(&mut rand::thread_rng())?.clone();
+        let permit = match self.in_flight.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                log::debug!("Too many mirrored requests in flight; not mirroring this one");
+                record_failure(state, "dropped");
+                return None;
+            }
+        };
+        let request = copy_request(request);
+        let state = state.clone();
+        let (sender, receiver) = oneshot::channel::<(http::StatusCode, Duration)>();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let started = Instant::now();
+            let result =
+                tokio::time::timeout(MIRROR_TIMEOUT, send_copy(&upstream, &request, &state)).await;
+            let mirror_latency = started.elapsed();
+            let mirror_status = match result {
+                Ok(Ok(status)) => status,
+                Ok(Err(err)) => {
+                    log::debug!("Mirroring to {} failed: {}", upstream, err);
+                    record_failure(&state, "error");
+                    return;
+                }
+                Err(_) => {
+                    log::debug!("Mirroring to {} timed out", upstream);
+                    record_failure(&state, "timeout");
+                    return;
+                }
+            };
+            state
+                .metrics
+                .increment("balancebeam_mirror_requests_total", &[("result", "ok")]);
+
+            // Only compare the two if the real request got a response too
+            let (primary_status, primary_latency) = match receiver.await {
+                Ok(primary) => primary,
+                Err(_) => return,
+            };
+            state.metrics.increment(
+                "balancebeam_mirror_responses_total",
+                &[
+                    ("primary_status", primary_status.as_str()),
+                    ("mirror_status", mirror_status.as_str()),
+                ],
+            );
+            state.metrics.observe(
+                "balancebeam_mirror_latency_seconds",
+                &[("upstream", "primary")],
+                primary_latency.as_secs_f64(),
+            );
+            state.metrics.observe(
+                "balancebeam_mirror_latency_seconds",
+                &[("upstream", "mirror")],
+                mirror_latency.as_secs_f64(),
+            );
+        });
+        Some(Shadow { primary: sender })
+    }
+}
+
+fn record_failure(state: &ProxyState, result: &str) {
+    state
+        .metrics
+        .increment("balancebeam_mirror_requests_total", &[("result", result)]);
+}
+
+/// http::Request isn't Clone, since bodies usually can't be copied; ours are just bytes.
+fn copy_request(request: &http::Request<Vec<u8>>) -> http::Request<Vec<u8>> {
+    let mut copy = http::Request::builder()
+        .method(request.method().clone())
+        .uri(request.uri().clone())
+        .version(request.version())
+        .body(request.body().clone())
+        .unwrap();
+    *copy.headers_mut() = request.headers().clone();
+    copy
+}
+
+/// Sends the request to a shadow upstream over a fresh connection, returning the status it
+/// answered with.
+async fn send_copy(
+    upstream: &str,
+    request: &http::Request<Vec<u8>>,
+    state: &ProxyState,
+) -> Result<http::StatusCode, String> {
+    let mut conn = Stream::connect(upstream)
+        .await
+        .map_err(|err| format!("could not connect: {}", err))?;
+    request::write_to_stream(request, &mut conn)
+        .await
+        .map_err(|err| format!("could not send request: {}", err))?;
+    let response = response::read_from_stream(&mut conn, request.method(), &state.response_limits)
+        .await
+        .map_err(|err| format!("could not read response: {}", err))?;
+    Ok(response.status())
+}
@@ -0,0 +1,131 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::metrics::Metrics;
+use crate::ProxyState;
+
+/// Number of recent response times kept for each upstream
+const WINDOW: usize = 100;
+/// Upstreams with fewer recent response times than this aren't judged
+const MIN_SAMPLES: usize = 10;
+/// How often upstreams are checked for being outliers
+const EVALUATION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Finds upstreams that are up but much slower than the rest of the pool, and stops sending them
+/// traffic for a while. An upstream is ejected when its p99 response time is more than `multiple`
+/// times the median of the pool's p99s.
+pub struct Detector {
+    multiple: f64,
+    /// Most of the pool that may be ejected at once, as a percentage. At least one upstream is
+    /// always left in.
+    max_ejection_percent: f64,
+    ejection_time: Duration,
+    latencies: Mutex<HashMap<String, VecDeque<Duration>>>,
+    /// Upstreams that are currently ejected, and when they will be let back in
+    ejected: Mutex<HashMap<String, Instant>>,
+}
+
+impl Detector {
+    pub fn new(multiple: f64, max_ejection_percent: f64, ejection_time: Duration) -> Detector {
+        Detector {
+            multiple,
+            max_ejection_percent,
+            ejection_time,
+            latencies: Mutex::new(HashMap::new()),
+            ejected: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records how long an upstream took to respond to a request.
+    pub fn record(&self, upstream_ip: &str, latency: Duration) {
+        let mut latencies = self.latencies.lock();
+        let samples = latencies.entry(upstream_ip.to_string()).or_default();
+        samples.push_back(latency);
+        if samples.len() > WINDOW {
+            samples.pop_front();
+        }
+    }
+
+    pub fn is_ejected(&self, upstream_ip: &str) -> bool {
+        self.ejected.lock().contains_key(upstream_ip)
+    }
+
+    /// Lets back in upstreams whose ejection is over, then ejects any new outliers among the
+    /// given upstreams.
+    fn evaluate(&self, upstreams: &[String], metrics: &Metrics) {
+        let now = Instant::now();
+        let mut ejected = self.ejected.lock();
+        let mut latencies = self.latencies.lock();
+        ejected.retain(|upstream_ip, until| {
+            if *until > now {
+                return true;
+            }
+            log::info!("Letting outlier {} back into the pool", upstream_ip);
+            // Judge it on how it does from now on, not on what got it ejected
+            latencies.remove(upstream_ip);
+            false
+        });
+
+        let mut p99s: Vec<(&String, Duration)> = upstreams
+            .iter()
+            .filter_map(|upstream_ip| {
+                let samples = latencies.get(upstream_ip)?;
+                if samples.len() < MIN_SAMPLES {
+                    return None;
+                }
+                let mut sorted: Vec<Duration> = samples.iter().copied().collect();
+                sorted.sort();
+                let index = ((sorted.len() as f64 * 0.99).ceil() as usize).max(1) - 1;
+                Some((upstream_ip, sorted[index]))
+            })
+            .collect();
+        if p99s.len() < 2 {
+            return;
+        }
+        // Take the lower middle value when there are an even number of upstreams, so that with
+        // two upstreams the slow one is compared against the fast one rather than the average
+        p99s.sort_by_key(|(_, p99)| std::cmp::Reverse(*p99));
+        let median = p99s[p99s.len() / 2].1;
+        let threshold = median.mul_f64(self.multiple);
+
+        let max_ejected = ((upstreams.len() as f64 * self.max_ejection_percent / 100.0).floor()
+            as usize)
+            .min(upstreams.len().saturating_sub(1));
+        for (upstream_ip, p99) in p99s {
+            if p99 <= threshold || ejected.len() >= max_ejected {
+                break;
+            }
+            if ejected.contains_key(upstream_ip) {
+                continue;
+            }
+            log::warn!(
+                "Ejecting {} for {:?}: p99 response time {:?} is over {}x the pool median {:?}",
+                upstream_ip,
+                self.ejection_time,
+                p99,
+                self.multiple,
+                median
+            );
+            ejected.insert(upstream_ip.clone(), now + self.ejection_time);
+            metrics.increment(
+                "balancebeam_outlier_ejections_total",
+                &[("upstream", upstream_ip)],
+            );
+        }
+    }
+}
+
+/// Periodically checks upstreams for being outliers, for as long as balancebeam runs.
+pub async fn run(state: ProxyState) {
+    let detector = match &state.outliers {
+        Some(detector) => detector.clone(),
+        None => return,
+    };
+    loop {
+        tokio::time::sleep(EVALUATION_INTERVAL).await;
+        let upstreams = state.upstream_addresses.read().await.clone();
+        detector.evaluate(&upstreams, &state.metrics);
+    }
+}
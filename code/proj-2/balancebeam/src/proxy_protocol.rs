@@ -0,0 +1,220 @@
+//! The PROXY protocol (<https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>) lets a
+//! layer-4 proxy tell the server behind it where a connection really came from, by sending a
+//! short header before any of the client's bytes.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use clap::ValueEnum;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::stream::ConnectionAddrs;
+
+/// Every version 2 header starts with these 12 bytes.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// A version 1 header is a single line of at most 107 bytes, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Which version of the PROXY protocol header to send.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Version {
+    /// Human-readable text header
+    V1,
+    /// Binary header
+    V2,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads a PROXY protocol header (of either version) from the start of a connection, consuming
+/// exactly the header's bytes. Returns the addresses it carries, or None if the sender didn't
+/// have any to share (e.g. its own health checks).
+pub async fn read_header(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> io::Result<Option<ConnectionAddrs>> {
+    // Both versions are told apart by their first bytes: "PROXY " for v1 and the start of the
+    // binary signature for v2. Neither header can be shorter than six bytes.
+    let mut start = [0_u8; 6];
+    stream.read_exact(&mut start).await?;
+    if &start == b"PROXY " {
+        read_v1(stream).await
+    } else if start == V2_SIGNATURE[..6] {
+        read_v2(stream).await
+    } else {
+        Err(invalid(
+            "connection did not start with a PROXY protocol header",
+        ))
+    }
+}
+
+/// Reads the rest of a v1 header, e.g. "TCP4 203.0.113.7 10.0.0.1 56324 443\r\n".
+async fn read_v1(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<ConnectionAddrs>> {
+    // Read a byte at a time so we never consume anything past the end of the header
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH - 6 {
+            return Err(invalid("PROXY protocol v1 header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY protocol v1 header is not valid text"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["UNKNOWN", ..] => Ok(None),
+        [family @ ("TCP4" | "TCP6"), source_ip, destination_ip, source_port, destination_port] => {
+            let parse_ip = |ip: &str| {
+                ip.parse::<IpAddr>()
+                    .ok()
+                    .filter(|ip| ip.is_ipv4() == (family == "TCP4"))
+                    .ok_or_else(|| invalid("PROXY protocol v1 header has an invalid address"))
+            };
+            let parse_port = |port: &str| {
+                port.parse::<u16>()
+                    .map_err(|_| invalid("PROXY protocol v1 header has an invalid port"))
+            };
+            Ok(Some(ConnectionAddrs {
+                source: SocketAddr::new(parse_ip(source_ip)?, parse_port(source_port)?),
+                destination: SocketAddr::new(
+                    parse_ip(destination_ip)?,
+                    parse_port(destination_port)?,
+                ),
+            }))
+        }
+        _ => Err(invalid("PROXY protocol v1 header is malformed")),
+    }
+}
+
+/// Reads the rest of a v2 header, once its first six bytes have been read.
+async fn read_v2(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<ConnectionAddrs>> {
+    let mut rest = [0_u8; 10];
+    stream.read_exact(&mut rest).await?;
+    if rest[..6] != V2_SIGNATURE[6..] {
+        return Err(invalid("PROXY protocol v2 header has a bad signature"));
+    }
+    let version_command = rest[6];
+    let family = rest[7];
+    let length = u16::from_be_bytes([rest[8], rest[9]]) as usize;
+    let mut payload = vec![0_u8; length];
+    stream.read_exact(&mut payload).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL: the sender opened this connection itself, so the real addresses apply
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        _ => return Err(invalid("unsupported PROXY protocol v2 command")),
+    }
+    // Only TCP over IPv4 (0x11) and IPv6 (0x21) carry addresses we can use. Any TLVs following
+    // the addresses are ignored.
+    match family {
+        0x11 if payload.len() >= 12 => {
+            let source_ip = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+            let destination_ip = Ipv4Addr::new(payload[4], payload[5], payload[6], payload[7]);
+            Ok(Some(ConnectionAddrs {
+                source: SocketAddr::new(
+                    source_ip.into(),
+                    u16::from_be_bytes([payload[8], payload[9]]),
+                ),
+                destination: SocketAddr::new(
+                    destination_ip.into(),
+                    u16::from_be_bytes([payload[10], payload[11]]),
+                ),
+            }))
+        }
+        0x21 if payload.len() >= 36 => {
+            let source_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&payload[0..16]).unwrap());
+            let destination_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&payload[16..32]).unwrap());
+            Ok(Some(ConnectionAddrs {
+                source: SocketAddr::new(
+                    source_ip.into(),
+                    u16::from_be_bytes([payload[32], payload[33]]),
+                ),
+                destination: SocketAddr::new(
+                    destination_ip.into(),
+                    u16::from_be_bytes([payload[34], payload[35]]),
+                ),
+            }))
+        }
+        0x11 | 0x21 => Err(invalid("PROXY protocol v2 header is too short")),
+        _ => Ok(None),
+    }
+}
+
+/// Builds a header describing a connection. Without addresses (e.g. for a client of a Unix domain
+/// socket, or for our own health checks), the header tells the upstream to use the connection's
+/// real addresses instead.
+pub fn encode_header(version: Version, addrs: Option<&ConnectionAddrs>) -> Vec<u8> {
+    // Both ends need to be the same address family, so use IPv4-mapped IPv6 addresses if they
+    // differ
+    let addrs = addrs.map(|addrs| match (addrs.source, addrs.destination) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => *addrs,
+        (source, destination) => ConnectionAddrs {
+            source: to_ipv6(source),
+            destination: to_ipv6(destination),
+        },
+    });
+
+    match version {
+        Version::V1 => match addrs {
+            Some(addrs) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if addrs.source.is_ipv4() {
+                    "TCP4"
+                } else {
+                    "TCP6"
+                },
+                addrs.source.ip(),
+                addrs.destination.ip(),
+                addrs.source.port(),
+                addrs.destination.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        Version::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            let mut payload = Vec::new();
+            match addrs {
+                Some(addrs) => {
+                    header.push(0x21);
+                    match (addrs.source.ip(), addrs.destination.ip()) {
+                        (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                            header.push(0x11);
+                            payload.extend_from_slice(&source_ip.octets());
+                            payload.extend_from_slice(&destination_ip.octets());
+                        }
+                        (IpAddr::V6(source_ip), IpAddr::V6(destination_ip)) => {
+                            header.push(0x21);
+                            payload.extend_from_slice(&source_ip.octets());
+                            payload.extend_from_slice(&destination_ip.octets());
+                        }
+                        _ => unreachable!("address families were made to match above"),
+                    }
+                    payload.extend_from_slice(&addrs.source.port().to_be_bytes());
+                    payload.extend_from_slice(&addrs.destination.port().to_be_bytes());
+                }
+                None => {
+                    header.push(0x20);
+                    header.push(0x00);
+                }
+            }
+            header.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+            header.extend_from_slice(&payload);
+            header
+        }
+    }
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+        SocketAddr::V6(_) => addr,
+    }
+}
@@ -1,10 +1,30 @@
 use std::cmp::min;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
-const MAX_NUM_HEADERS: usize = 32;
+/// Limits on the size of messages balancebeam will read. The defaults can be changed on the
+/// command line, and individual routes can override them in the config file.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Maximum size of the request line and headers, in bytes
+    pub max_headers_size: usize,
+    /// Maximum number of headers
+    pub max_num_headers: usize,
+    /// Maximum length of the request target, in bytes
+    pub max_uri_length: usize,
+    /// Maximum size of a message body, in bytes
+    pub max_body_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_headers_size: 8000,
+            max_num_headers: 32,
+            max_uri_length: 8000,
+            max_body_size: 10000000,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum Error {
@@ -17,12 +37,26 @@ pub enum Error {
     InvalidContentLength,
     /// The Content-Length header does not match the size of the request body that was sent
     ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
+    /// The request body is bigger than the max_body_size limit
     RequestBodyTooLarge,
+    /// The request line and headers are bigger than the max_headers_size limit
+    HeadersTooLarge,
+    /// The request has more headers than the max_num_headers limit
+    TooManyHeaders,
+    /// The request target is longer than the max_uri_length limit
+    UriTooLong,
+    /// The request has an Expect header asking for something other than 100-continue
+    ExpectationFailed,
+    /// The request body is sent with a Transfer-Encoding, which we can't find the end of
+    UnsupportedTransferEncoding,
     /// Encountered an I/O error when reading/writing a TcpStream
-    ConnectionError(std::io::Error),
+    Io(std::io::Error),
 }
 
+/// A parsed request and the number of bytes of the buffer it took up, or None if the buffer
+/// doesn't hold a complete request yet
+type ParseResult = Result<Option<(http::Request<Vec<u8>>, usize)>, Error>;
+
 /// Extracts the Content-Length header value from the provided request. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
@@ -66,6 +100,35 @@ pub fn extend_header_value(
         .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
 }
 
+/// Generates an ID for a request, for tying the responses clients see to our logs.
+pub fn new_request_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// Returns the request's ID. An X-Request-Id set by the client (or a proxy in front of us) is kept
+/// if it is short and only uses characters that are safe to put in an error page; otherwise a new
+/// ID is generated and set on the request, so the upstream sees it too.
+pub fn request_id<T>(request: &mut http::Request<T>) -> String {
+    if let Some(id) = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            (1..=128).contains(&id.len())
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+        })
+    {
+        return id.to_string();
+    }
+    let id = new_request_id();
+    request
+        .headers_mut()
+        .insert("x-request-id", http::HeaderValue::from_str(&id).unwrap());
+    id
+}
+
 /// Attempts to parse the data in the supplied buffer as an HTTP request. Returns one of the
 /// following:
 ///
@@ -74,18 +137,30 @@ pub fn extend_header_value(
 /// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
 ///
 /// You won't need to touch this function.
-fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
-    let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
+fn parse_request(buffer: &[u8], limits: &Limits) -> ParseResult {
+    let mut headers = vec![httparse::EMPTY_HEADER; limits.max_num_headers];
     let mut req = httparse::Request::new(&mut headers);
-    let res = req
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedRequest(err)))?;
+    let res = req.parse(buffer).map_err(|err| match err {
+        httparse::Error::TooManyHeaders => Error::TooManyHeaders,
+        err => Error::MalformedRequest(err),
+    })?;
+    // The request line is parsed before any headers, so an overly long target can be caught even
+    // if the rest of the request hasn't arrived yet
+    if req
+        .path
+        .is_some_and(|path| path.len() > limits.max_uri_length)
+    {
+        return Err(Error::UriTooLong);
+    }
 
     if let httparse::Status::Complete(len) = res {
         let mut request = http::Request::builder()
             .method(req.method.unwrap())
             .uri(req.path.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(match req.version {
+                Some(0) => http::Version::HTTP_10,
+                _ => http::Version::HTTP_11,
+            });
         for header in req.headers {
             request = request.header(header.name, header.value);
         }
@@ -103,18 +178,26 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
 /// Returns Ok(http::Request) if a valid request is received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+pub async fn read_headers(
+    stream: &mut (impl AsyncRead + Unpin),
+    limits: &Limits,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
-    let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
+    let mut request_buffer = vec![0_u8; limits.max_headers_size];
     let mut bytes_read = 0;
     loop {
+        if bytes_read == request_buffer.len() {
+            // The buffer is full and we still don't have all of the headers
+            return Err(Error::HeadersTooLarge);
+        }
+
         // Read bytes from the connection into the buffer, starting at position bytes_read
         let new_bytes = stream
             .read(&mut request_buffer[bytes_read..])
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::Io)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete request
             return Err(Error::IncompleteRequest(bytes_read));
@@ -122,7 +205,9 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>,
         bytes_read += new_bytes;
 
         // See if we've read a valid request so far
-        if let Some((mut request, headers_len)) = parse_request(&request_buffer[..bytes_read])? {
+        if let Some((mut request, headers_len)) =
+            parse_request(&request_buffer[..bytes_read], limits)?
+        {
             // We've read a complete set of headers. However, if this was a POST request, a request
             // body might have been included as well, and we might have read part of the body out of
             // the stream into header_buffer. We need to add those bytes to the Request body so that
@@ -135,25 +220,92 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>,
     }
 }
 
+/// Checks a request whose headers have already been read against a (possibly stricter) set of
+/// limits than the ones it was read with. The headers' size is measured as they would be sent.
+pub fn check_headers(request: &http::Request<Vec<u8>>, limits: &Limits) -> Result<(), Error> {
+    if request.uri().to_string().len() > limits.max_uri_length {
+        return Err(Error::UriTooLong);
+    }
+    if request.headers().len() > limits.max_num_headers {
+        return Err(Error::TooManyHeaders);
+    }
+    let headers_size = format_request_line(request).len()
+        + request
+            .headers()
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len() + 4)
+            .sum::<usize>()
+        + 4;
+    if headers_size > limits.max_headers_size {
+        return Err(Error::HeadersTooLarge);
+    }
+    Ok(())
+}
+
+/// Checks the body size announced by the Content-Length header (if any) against the max_body_size
+/// limit, so that a body that is too big can be refused before it is read. Bodies with a
+/// Transfer-Encoding are refused outright: reading them as Content-Length (or empty) bodies would
+/// let the client slip extra requests past us.
+pub fn check_body_size(request: &http::Request<Vec<u8>>, limits: &Limits) -> Result<(), Error> {
+    if request
+        .headers()
+        .contains_key(http::header::TRANSFER_ENCODING)
+    {
+        return Err(Error::UnsupportedTransferEncoding);
+    }
+    match get_content_length(request)? {
+        Some(content_length) if content_length > limits.max_body_size => {
+            Err(Error::RequestBodyTooLarge)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Returns true if the client sent `Expect: 100-continue`, meaning it will wait to be told to go
+/// ahead before sending the body. Any other expectation is one we can't meet. HTTP/1.0 clients
+/// don't know about expectations, so an Expect header from one is ignored (RFC 9110, section
+/// 10.1.1).
+pub fn expects_continue<T>(request: &http::Request<T>) -> Result<bool, Error> {
+    if request.version() < http::Version::HTTP_11 {
+        return Ok(false);
+    }
+    match request.headers().get(http::header::EXPECT) {
+        None => Ok(false),
+        Some(value) if value.as_bytes().eq_ignore_ascii_case(b"100-continue") => Ok(true),
+        Some(_) => Err(Error::ExpectationFailed),
+    }
+}
+
+/// Reads the request body announced by the Content-Length header (if any), refusing bodies bigger
+/// than the max_body_size limit.
+pub async fn read_body(
+    stream: &mut (impl AsyncRead + Unpin),
+    request: &mut http::Request<Vec<u8>>,
+    limits: &Limits,
+) -> Result<(), Error> {
+    check_body_size(request, limits)?;
+    if let Some(content_length) = get_content_length(request)? {
+        read_body_bytes(stream, request, content_length).await?;
+    }
+    Ok(())
+}
+
 /// This function reads the body for a request from the stream. The client only sends a body if the
 /// Content-Length header is present; this function reads that number of bytes from the stream. It
 /// returns Ok(()) if successful, or Err(Error) if Content-Length bytes couldn't be read.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
+async fn read_body_bytes(
+    stream: &mut (impl AsyncRead + Unpin),
     request: &mut http::Request<Vec<u8>>,
     content_length: usize,
 ) -> Result<(), Error> {
     // Keep reading data until we read the full body length, or until we hit an error.
     while request.body().len() < content_length {
         // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
-        // space to read that body.)
-        let mut buffer = vec![0_u8; min(512, content_length)];
-        let bytes_read = stream
-            .read(&mut buffer)
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        // space to read that body, so that we don't read into a pipelined request after it.)
+        let mut buffer = vec![0_u8; min(512, content_length - request.body().len())];
+        let bytes_read = stream.read(&mut buffer).await.map_err(Error::Io)?;
 
         // Make sure the client is still sending us bytes
         if bytes_read == 0 {
@@ -180,22 +332,109 @@ async fn read_body(
     Ok(())
 }
 
+/// Removes anything the client sent after the end of the request's body from it and returns it.
+/// A client pipelining requests may send the next one before getting a response, in which case
+/// read_headers can read the start of it along with this request's headers.
+pub fn take_pipelined(request: &mut http::Request<Vec<u8>>) -> Result<Vec<u8>, Error> {
+    let body_len = get_content_length(request)?.unwrap_or(0);
+    if request.body().len() > body_len {
+        Ok(request.body_mut().split_off(body_len))
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+/// Returns true if the Connection header lists the given option (e.g. "close"). Option names
+/// are case-insensitive.
+pub fn has_connection_option(headers: &http::HeaderMap, option: &str) -> bool {
+    headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(option))
+}
+
+/// Returns true if the client wants the connection kept open after this request. HTTP/1.1
+/// connections are persistent unless the client says `Connection: close`, while HTTP/1.0 ones
+/// only are if it asks for `Connection: keep-alive`.
+pub fn wants_keep_alive<T>(request: &http::Request<T>) -> bool {
+    if request.version() == http::Version::HTTP_10 {
+        has_connection_option(request.headers(), "keep-alive")
+    } else {
+        !has_connection_option(request.headers(), "close")
+    }
+}
+
 /// This function reads and returns an HTTP request from a stream, returning an Error if the client
 /// closes the connection prematurely or sends an invalid request.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+pub async fn read_from_stream(
+    stream: &mut (impl AsyncRead + Unpin),
+    limits: &Limits,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Read headers
-    let mut request = read_headers(stream).await?;
+    let mut request = read_headers(stream, limits).await?;
     // Read body if the client supplied the Content-Length header (which it does for POST requests)
-    if let Some(content_length) = get_content_length(&request)? {
-        if content_length > MAX_BODY_SIZE {
-            return Err(Error::RequestBodyTooLarge);
-        } else {
-            read_body(stream, &mut request, content_length).await?;
+    read_body(stream, &mut request, limits).await?;
+    Ok(request)
+}
+
+impl Error {
+    /// Returns the status to answer the client with when its request couldn't be read.
+    pub fn status_code(&self) -> http::StatusCode {
+        match self {
+            Error::IncompleteRequest(_)
+            | Error::MalformedRequest(_)
+            | Error::InvalidContentLength
+            | Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
+            Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+            Error::HeadersTooLarge | Error::TooManyHeaders => {
+                http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+            }
+            Error::UriTooLong => http::StatusCode::URI_TOO_LONG,
+            Error::ExpectationFailed => http::StatusCode::EXPECTATION_FAILED,
+            Error::UnsupportedTransferEncoding => http::StatusCode::NOT_IMPLEMENTED,
+            Error::Io(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// Returns true if the rest of the request may still be waiting to be read, so the connection
+    /// can't be used for further requests.
+    pub fn leaves_unread_data(&self) -> bool {
+        matches!(
+            self,
+            Error::RequestBodyTooLarge
+                | Error::HeadersTooLarge
+                | Error::TooManyHeaders
+                | Error::UriTooLong
+                | Error::ExpectationFailed
+                | Error::UnsupportedTransferEncoding
+        )
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::IncompleteRequest(bytes_read) => write!(
+                f,
+                "client hung up after sending {} bytes of a request",
+                bytes_read
+            ),
+            Error::MalformedRequest(err) => write!(f, "malformed request: {}", err),
+            Error::InvalidContentLength => write!(f, "invalid Content-Length"),
+            Error::ContentLengthMismatch => write!(f, "body doesn't match Content-Length"),
+            Error::RequestBodyTooLarge => write!(f, "request body too large"),
+            Error::HeadersTooLarge => write!(f, "request headers too large"),
+            Error::TooManyHeaders => write!(f, "too many request headers"),
+            Error::UriTooLong => write!(f, "request target too long"),
+            Error::ExpectationFailed => write!(f, "unsupported Expect header"),
+            Error::UnsupportedTransferEncoding => write!(f, "unsupported Transfer-Encoding"),
+            Error::Io(err) => write!(f, "{}", err),
         }
     }
-    Ok(request)
 }
 
 /// This function serializes a request to bytes and writes those bytes to the provided stream.
@@ -203,22 +442,22 @@ pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Ve
 /// You will need to modify this function in Milestone 2.
 pub async fn write_to_stream(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncWrite + Unpin),
 ) -> Result<(), std::io::Error> {
     stream
-        .write(&format_request_line(request).into_bytes())
+        .write_all(format_request_line(request).as_bytes())
         .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(b"\r\n").await?; // \r\n
     for (header_name, header_value) in request.headers() {
         stream
-            .write(&format!("{}: ", header_name).as_bytes())
+            .write_all(format!("{}: ", header_name).as_bytes())
             .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?; // \r\n
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await?;
+    stream.write_all(b"\r\n").await?;
+    if !request.body().is_empty() {
+        stream.write_all(request.body()).await?;
     }
     Ok(())
 }
@@ -1,9 +1,6 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
-const MAX_NUM_HEADERS: usize = 32;
+use crate::request::Limits;
 
 #[derive(Debug)]
 pub enum Error {
@@ -15,12 +12,43 @@ pub enum Error {
     InvalidContentLength,
     /// The Content-Length header does not match the size of the request body that was sent
     ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
+    /// The response body is bigger than the max_body_size limit
     ResponseBodyTooLarge,
+    /// The status line and headers are bigger than the max_headers_size limit
+    HeadersTooLarge,
+    /// A chunked response body isn't framed properly
+    MalformedChunkedBody,
+    /// The response body has a transfer coding other than chunked, so we can't find its end and
+    /// pass it on over a connection we keep open
+    UnsupportedTransferEncoding,
     /// Encountered an I/O error when reading/writing a TcpStream
-    ConnectionError(std::io::Error),
+    Io(std::io::Error),
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::IncompleteResponse => write!(f, "upstream hung up before a complete response"),
+            Error::MalformedResponse(err) => write!(f, "malformed response: {}", err),
+            Error::InvalidContentLength => write!(f, "invalid Content-Length"),
+            Error::ContentLengthMismatch => write!(f, "body doesn't match Content-Length"),
+            Error::ResponseBodyTooLarge => write!(f, "response body too large"),
+            Error::HeadersTooLarge => write!(f, "response headers too large"),
+            Error::MalformedChunkedBody => write!(f, "malformed chunked body"),
+            Error::UnsupportedTransferEncoding => write!(f, "unsupported Transfer-Encoding"),
+            Error::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Longest chunk size line (with any chunk extensions), or trailer section, we accept in a chunked
+/// response body
+const MAX_CHUNK_LINE_SIZE: usize = 4096;
+
+/// A parsed response and the number of bytes of the buffer it took up, or None if the buffer
+/// doesn't hold a complete response yet
+type ParseResult = Result<Option<(http::Response<Vec<u8>>, usize)>, Error>;
+
 /// Extracts the Content-Length header value from the provided response. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
@@ -52,12 +80,10 @@ fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize
 ///   Err(Error)
 ///
 /// You won't need to touch this function.
-fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
-    let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
+fn parse_response(buffer: &[u8], limits: &Limits) -> ParseResult {
+    let mut headers = vec![httparse::EMPTY_HEADER; limits.max_num_headers];
     let mut resp = httparse::Response::new(&mut headers);
-    let res = resp
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedResponse(err)))?;
+    let res = resp.parse(buffer).map_err(Error::MalformedResponse)?;
 
     if let httparse::Status::Complete(len) = res {
         let mut response = http::Response::builder()
@@ -75,61 +101,228 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 
 /// Reads an HTTP response from the provided stream, waiting until a complete set of headers is
 /// sent. This function only reads the response line and headers; the read_body function can
-/// subsequently be called in order to read the response body.
+/// subsequently be called in order to read the response body. `already_read` holds bytes of the
+/// response that were read from the stream earlier (e.g. along with an interim response).
 ///
 /// Returns Ok(http::Response) if a valid response is received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+async fn read_headers(
+    stream: &mut (impl AsyncRead + Unpin),
+    limits: &Limits,
+    already_read: Vec<u8>,
+) -> Result<http::Response<Vec<u8>>, Error> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
-    let mut response_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    let mut response_buffer = already_read;
     loop {
-        // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut response_buffer[bytes_read..])
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
-        if new_bytes == 0 {
-            // We didn't manage to read a complete response
-            return Err(Error::IncompleteResponse);
-        }
-        bytes_read += new_bytes;
-
         // See if we've read a valid response so far
-        if let Some((mut response, headers_len)) = parse_response(&response_buffer[..bytes_read])? {
+        if let Some((mut response, headers_len)) = parse_response(&response_buffer, limits)? {
             // We've read a complete set of headers. We may have also read the first part of the
             // response body; take whatever is left over in the response buffer and save that as
             // the start of the response body.
             response
                 .body_mut()
-                .extend_from_slice(&response_buffer[headers_len..bytes_read]);
+                .extend_from_slice(&response_buffer[headers_len..]);
             return Ok(response);
         }
+        if response_buffer.len() >= limits.max_headers_size {
+            return Err(Error::HeadersTooLarge);
+        }
+
+        // Read more bytes from the connection, up to the most the headers may take up
+        let mut buffer = vec![0_u8; limits.max_headers_size - response_buffer.len()];
+        let new_bytes = stream.read(&mut buffer).await.map_err(Error::Io)?;
+        if new_bytes == 0 {
+            // We didn't manage to read a complete response
+            return Err(Error::IncompleteResponse);
+        }
+        response_buffer.extend_from_slice(&buffer[..new_bytes]);
     }
 }
 
-/// This function reads the body for a response from the stream. If the Content-Length header is
-/// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
+/// Returns the boundary string if the response body is multipart/byteranges (the way a 206
+/// response carries several ranges of a resource at once).
+fn byteranges_boundary(response: &http::Response<Vec<u8>>) -> Option<String> {
+    let content_type = response
+        .headers()
+        .get(http::header::CONTENT_TYPE)?
+        .to_str()
+        .ok()?;
+    let mut parts = content_type.split(';');
+    if !parts
+        .next()?
+        .trim()
+        .eq_ignore_ascii_case("multipart/byteranges")
+    {
+        return None;
+    }
+    parts.find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case("boundary") {
+            Some(value.trim().trim_matches('"').to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/// Returns the transfer codings applied to the response body, in the order they were applied.
+fn transfer_codings(response: &http::Response<Vec<u8>>) -> Vec<String> {
+    response
+        .headers()
+        .get_all(http::header::TRANSFER_ENCODING)
+        .iter()
+        .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        .flat_map(|value| {
+            value
+                .split(',')
+                .map(|coding| coding.trim().to_ascii_lowercase())
+                .collect::<Vec<_>>()
+        })
+        .filter(|coding| !coding.is_empty())
+        .collect()
+}
+
+/// Returns true if the response body is sent with chunked transfer encoding, which has to be the
+/// last coding applied to it.
+fn is_chunked(response: &http::Response<Vec<u8>>) -> bool {
+    transfer_codings(response)
+        .last()
+        .is_some_and(|coding| coding == "chunked")
+}
+
+/// Checks that the response body has no transfer coding other than chunked. Other codings can't be
+/// undone here, and a body sent with them (and without chunked on top) only ends when the upstream
+/// closes the connection, so it couldn't be passed on as it is.
+fn check_transfer_encoding(response: &http::Response<Vec<u8>>) -> Result<(), Error> {
+    match transfer_codings(response).as_slice() {
+        [] => Ok(()),
+        [coding] if coding == "chunked" => Ok(()),
+        _ => Err(Error::UnsupportedTransferEncoding),
+    }
+}
+
+/// Decodes as many whole chunks of a chunked body as `raw` holds, starting at `decoded_up_to` and
+/// appending their data to `body`. Returns how many bytes of `raw` the whole body took up once its
+/// last chunk (and any trailer fields, which are dropped) has been read, or None if more is to
+/// come.
+fn decode_chunks(
+    raw: &[u8],
+    decoded_up_to: &mut usize,
+    body: &mut Vec<u8>,
+    limits: &Limits,
+) -> Result<Option<usize>, Error> {
+    loop {
+        let rest = &raw[*decoded_up_to..];
+        let line_len = match rest.windows(2).position(|window| window == b"\r\n") {
+            Some(line_len) => line_len,
+            None if rest.len() > MAX_CHUNK_LINE_SIZE => return Err(Error::MalformedChunkedBody),
+            None => return Ok(None),
+        };
+        let size = std::str::from_utf8(&rest[..line_len])
+            .ok()
+            .and_then(|line| line.split(';').next())
+            .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+            .ok_or(Error::MalformedChunkedBody)?;
+        let data = &rest[line_len + 2..];
+        if size == 0 {
+            // The trailer section ends with an empty line
+            if data.starts_with(b"\r\n") {
+                return Ok(Some(raw.len() - data.len() + 2));
+            }
+            return match data.windows(4).position(|window| window == b"\r\n\r\n") {
+                Some(trailers_len) => Ok(Some(raw.len() - data.len() + trailers_len + 4)),
+                None if data.len() > MAX_CHUNK_LINE_SIZE => Err(Error::MalformedChunkedBody),
+                None => Ok(None),
+            };
+        }
+        // Checked without adding to the size, which may be anything the upstream cared to send
+        if size > limits.max_body_size.saturating_sub(body.len()) {
+            return Err(Error::ResponseBodyTooLarge);
+        }
+        let chunk_end = size.checked_add(2).ok_or(Error::MalformedChunkedBody)?;
+        if data.len() < chunk_end {
+            return Ok(None);
+        }
+        if &data[size..chunk_end] != b"\r\n" {
+            return Err(Error::MalformedChunkedBody);
+        }
+        body.extend_from_slice(&data[..size]);
+        *decoded_up_to = raw.len() - data.len() + chunk_end;
+    }
+}
+
+/// Reads a chunked response body, replacing the raw bytes read along with the headers with the
+/// decoded body.
+async fn read_chunked_body(
+    stream: &mut (impl AsyncRead + Unpin),
+    response: &mut http::Response<Vec<u8>>,
+    limits: &Limits,
+) -> Result<(), Error> {
+    let mut raw = std::mem::take(response.body_mut());
+    let mut decoded_up_to = 0;
+    loop {
+        if let Some(body_len) =
+            decode_chunks(&raw, &mut decoded_up_to, response.body_mut(), limits)?
+        {
+            keep_pipelined(response, raw.split_off(body_len));
+            return Ok(());
+        }
+        let mut buffer = [0_u8; 512];
+        let bytes_read = stream.read(&mut buffer).await.map_err(Error::Io)?;
+        if bytes_read == 0 {
+            return Err(Error::IncompleteResponse);
+        }
+        raw.extend_from_slice(&buffer[..bytes_read]);
+    }
+}
+
+/// This function reads the body for a response from the stream. If the body is chunked, it reads
+/// and decodes the chunks; if the Content-Length header is present, it reads that many bytes; if
+/// the body is multipart/byteranges, it reads up to the closing boundary; otherwise, it reads
+/// bytes until the connection is closed.
 ///
 /// You will need to modify this function in Milestone 2.
 async fn read_body(
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncRead + Unpin),
     response: &mut http::Response<Vec<u8>>,
+    limits: &Limits,
 ) -> Result<(), Error> {
+    if is_chunked(response) {
+        return read_chunked_body(stream, response, limits).await;
+    }
     // The response may or may not supply a Content-Length header. If it provides the header, then
     // we want to read that number of bytes; if it does not, we want to keep reading bytes until
     // the connection is closed.
     let content_length = get_content_length(response)?;
+    // A multipart/byteranges body without a Content-Length marks its own end, and the server may
+    // keep the connection open for the next response once it has sent it
+    let closing_delimiter = match content_length {
+        Some(_) => None,
+        None => byteranges_boundary(response).map(|boundary| format!("--{}--", boundary)),
+    };
+    // How much of the body has been searched for the closing delimiter already
+    let mut searched = 0;
+    // Where the body ends, once the closing delimiter has been found
+    let mut body_end = None;
 
     while content_length.is_none() || response.body().len() < content_length.unwrap() {
+        if let Some(delimiter) = &closing_delimiter {
+            let body = response.body();
+            if let Some(pos) = body[searched..]
+                .windows(delimiter.len())
+                .position(|window| window == delimiter.as_bytes())
+            {
+                body_end = Some(searched + pos + delimiter.len());
+                break;
+            }
+            // The delimiter may be split between what we have and the next read
+            searched = body.len().saturating_sub(delimiter.len() - 1);
+        }
         let mut buffer = [0_u8; 512];
-        let bytes_read = stream
-            .read(&mut buffer)
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        let bytes_read = stream.read(&mut buffer).await.map_err(Error::Io)?;
         if bytes_read == 0 {
             // The server has hung up!
             if content_length.is_none() {
@@ -149,35 +342,133 @@ async fn read_body(
         }
 
         // Make sure server doesn't send more bytes than we allow
-        if response.body().len() + bytes_read > MAX_BODY_SIZE {
+        if response.body().len() + bytes_read > limits.max_body_size {
             return Err(Error::ResponseBodyTooLarge);
         }
 
         // Append received bytes to the response body
         response.body_mut().extend_from_slice(&buffer[..bytes_read]);
     }
+    if let Some(content_length) = content_length {
+        // The headers may have been read along with more than the whole body
+        if response.body().len() > content_length {
+            let pipelined = response.body_mut().split_off(content_length);
+            keep_pipelined(response, pipelined);
+        }
+    }
+    if let Some(mut body_end) = body_end {
+        if response.body()[body_end..].starts_with(b"\r\n") {
+            body_end += 2;
+        }
+        // Anything after the closing delimiter is the start of the server's next response
+        let pipelined = response.body_mut().split_off(body_end);
+        keep_pipelined(response, pipelined);
+    }
     Ok(())
 }
 
+/// Bytes the server sent after a response that marked its own end, which belong to the response
+/// after it.
+struct Pipelined(Vec<u8>);
+
+fn keep_pipelined(response: &mut http::Response<Vec<u8>>, pipelined: Vec<u8>) {
+    if !pipelined.is_empty() {
+        response.extensions_mut().insert(Pipelined(pipelined));
+    }
+}
+
+/// Takes the bytes that were read along with the response but belong to the server's next one,
+/// so they can be put back in front of the connection (see `PrefixedStream::unread`).
+pub fn take_pipelined(response: &mut http::Response<Vec<u8>>) -> Vec<u8> {
+    response
+        .extensions_mut()
+        .remove::<Pipelined>()
+        .map(|Pipelined(bytes)| bytes)
+        .unwrap_or_default()
+}
+
 /// This function reads and returns an HTTP response from a stream, returning an Error if the server
-/// closes the connection prematurely or sends an invalid response.
+/// closes the connection prematurely or sends an invalid response. Interim (1xx) responses sent
+/// ahead of the final one are skipped.
 ///
 /// You will need to modify this function in Milestone 2.
 pub async fn read_from_stream(
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncRead + Unpin),
     request_method: &http::Method,
+    limits: &Limits,
 ) -> Result<http::Response<Vec<u8>>, Error> {
-    let mut response = read_headers(stream).await?;
+    let (_, response) = read_with_interim(stream, request_method, limits).await?;
+    Ok(response)
+}
+
+/// Like read_from_stream, but also returns the interim (1xx) responses the server sent before the
+/// final response, in the order they arrived. 101 Switching Protocols counts as a final response,
+/// since nothing more follows it over HTTP.
+pub async fn read_with_interim(
+    stream: &mut (impl AsyncRead + Unpin),
+    request_method: &http::Method,
+    limits: &Limits,
+) -> Result<(Vec<http::Response<Vec<u8>>>, http::Response<Vec<u8>>), Error> {
+    let mut interim = Vec::new();
+    let mut response = read_headers(stream, limits, Vec::new()).await?;
+    while response.status().is_informational()
+        && response.status() != http::StatusCode::SWITCHING_PROTOCOLS
+    {
+        // Interim responses have no body, so anything read after their headers belongs to the
+        // next response
+        let already_read = std::mem::take(response.body_mut());
+        interim.push(response);
+        response = read_headers(stream, limits, already_read).await?;
+    }
     // A response may have a body as long as it is not responding to a HEAD request and as long as
-    // the response status code is not 1xx, 204 (no content), or 304 (not modified).
+    // the response status code is not 1xx, 204 (no content), or 304 (not modified). Bodyless
+    // responses may still carry a Content-Length (e.g. the length a GET would have returned),
+    // which must not be waited for.
     if !(request_method == http::Method::HEAD
-        || response.status().as_u16() < 200
+        || response.status().is_informational()
         || response.status() == http::StatusCode::NO_CONTENT
         || response.status() == http::StatusCode::NOT_MODIFIED)
     {
-        read_body(stream, &mut response).await?;
+        check_transfer_encoding(&response)?;
+        let chunked = is_chunked(&response);
+        read_body(stream, &mut response, limits).await?;
+        if chunked {
+            // The body has been decoded, so it is passed on with a Content-Length instead (of any
+            // that came with it, which the chunked encoding overrides)
+            let headers = response.headers_mut();
+            headers.remove(http::header::TRANSFER_ENCODING);
+            headers.remove(http::header::CONTENT_LENGTH);
+        }
+        // Bodies that marked their own end, or ended when the server closed the connection, are
+        // passed on over a connection we keep open, so the client needs to be told their length
+        if !response
+            .headers()
+            .contains_key(http::header::CONTENT_LENGTH)
+            && !response
+                .headers()
+                .contains_key(http::header::TRANSFER_ENCODING)
+        {
+            let body_len = response.body().len();
+            response.headers_mut().insert(
+                http::header::CONTENT_LENGTH,
+                http::HeaderValue::from(body_len),
+            );
+            if !chunked && byteranges_boundary(&response).is_none() {
+                // The upstream closed the connection to mark the end of the body, so it can't be
+                // used for another request
+                response.headers_mut().insert(
+                    http::header::CONNECTION,
+                    http::HeaderValue::from_static("close"),
+                );
+            }
+        }
+    } else if response.status() != http::StatusCode::SWITCHING_PROTOCOLS {
+        // Anything read after the head of a bodyless response belongs to the next one. (After a
+        // 101, it is the start of the upgraded protocol's traffic, which is passed on.)
+        let pipelined = std::mem::take(response.body_mut());
+        keep_pipelined(&mut response, pipelined);
     }
-    Ok(response)
+    Ok((interim, response))
 }
 
 /// This function serializes a response to bytes and writes those bytes to the provided stream.
@@ -185,22 +476,22 @@ pub async fn read_from_stream(
 /// You will need to modify this function in Milestone 2.
 pub async fn write_to_stream(
     response: &http::Response<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncWrite + Unpin),
 ) -> Result<(), std::io::Error> {
     stream
-        .write(&format_response_line(response).into_bytes())
+        .write_all(format_response_line(response).as_bytes())
         .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(b"\r\n").await?; // \r\n
     for (header_name, header_value) in response.headers() {
         stream
-            .write(&format!("{}: ", header_name).as_bytes())
+            .write_all(format!("{}: ", header_name).as_bytes())
             .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?; // \r\n
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if response.body().len() > 0 {
-        stream.write(response.body()).await?;
+    stream.write_all(b"\r\n").await?;
+    if !response.body().is_empty() {
+        stream.write_all(response.body()).await?;
     }
     Ok(())
 }
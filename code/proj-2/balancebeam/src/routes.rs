@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::auth::{AuthConfig, Rejection};
+use crate::cidr::{self, Cidr};
+use crate::error_pages::{self, ErrorPage};
+use crate::faults::{self, FaultConfig};
+use crate::shedding::Priority;
+use crate::stream::PrefixedStream;
+use crate::{request, response, static_files, ProxyState};
+
+/// Settings that apply to requests whose path falls under a given prefix. Routes are loaded from
+/// the JSON file given with --config, e.g.
+///
+/// ```json
+/// {"routes": [{"path_prefix": "/admin", "allow": ["10.0.0.0/8"], "deny": ["10.1.2.3"],
+///              "auth": {"htpasswd": "/etc/balancebeam/htpasswd"}},
+///             {"path_prefix": "/assets", "static_dir": "/srv/assets"},
+///             {"path_prefix": "/reports", "priority": "low"},
+///             {"path_prefix": "/api", "faults": {"delay": {"percent": 10, "ms": 500},
+///                                                "abort": {"percent": 1, "status": 503}}}]}
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// Name used for this route in logs and metrics. Defaults to the path prefix.
+    #[serde(default)]
+    pub name: Option<String>,
+    pub path_prefix: String,
+    /// If not empty, only clients in these blocks may use this route
+    #[serde(default)]
+    pub allow: Vec<Cidr>,
+    /// Clients in these blocks may not use this route, even if they are allowed above
+    #[serde(default)]
+    pub deny: Vec<Cidr>,
+    /// If set, requests must carry credentials accepted by this route
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    /// Size limits that replace the global ones for this route
+    #[serde(default)]
+    pub limits: RouteLimits,
+    /// If set, requests are answered with files from this directory instead of being forwarded.
+    /// The path prefix is removed from the request path to find the file.
+    #[serde(default)]
+    pub static_dir: Option<String>,
+    /// How important requests on this route are when deciding what to shed under load
+    #[serde(default)]
+    pub priority: Option<Priority>,
+    /// Delays or errors to inject into this route's requests. These can be changed through the
+    /// admin interface while balancebeam is running.
+    #[serde(default)]
+    pub faults: Option<FaultConfig>,
+}
+
+/// Per-route overrides for the global size limits. Headers are read before we know which route a
+/// request is for, so the header limits here can only tighten the global ones; the body limit can
+/// also be raised.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteLimits {
+    #[serde(default)]
+    pub max_headers_size: Option<usize>,
+    #[serde(default)]
+    pub max_num_headers: Option<usize>,
+    #[serde(default)]
+    pub max_uri_length: Option<usize>,
+    #[serde(default)]
+    pub max_body_size: Option<usize>,
+}
+
+impl RouteLimits {
+    fn apply_to(&self, limits: request::Limits) -> request::Limits {
+        request::Limits {
+            max_headers_size: self.max_headers_size.unwrap_or(limits.max_headers_size),
+            max_num_headers: self.max_num_headers.unwrap_or(limits.max_num_headers),
+            max_uri_length: self.max_uri_length.unwrap_or(limits.max_uri_length),
+            max_body_size: self.max_body_size.unwrap_or(limits.max_body_size),
+        }
+    }
+}
+
+impl Route {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.path_prefix)
+    }
+
+    /// Returns true if a normalized request path falls under this route. The prefix has to match
+    /// whole segments, so "/admin" covers "/admin" and "/admin/users" but not "/administrator".
+    fn matches(&self, path: &str) -> bool {
+        match path.strip_prefix(self.path_prefix.as_str()) {
+            Some(rest) => {
+                rest.is_empty() || rest.starts_with('/') || self.path_prefix.ends_with('/')
+            }
+            None => false,
+        }
+    }
+
+    /// Returns true if the client is allowed to use this route. Clients without an IP address
+    /// (i.e. connected over a Unix domain socket) are only let in if there is no allowlist.
+    fn admits(&self, client_ip: &str) -> bool {
+        match client_ip.parse::<IpAddr>() {
+            Ok(ip) => {
+                !cidr::any_contains(&self.deny, &ip)
+                    && (self.allow.is_empty() || cidr::any_contains(&self.allow, &ip))
+            }
+            Err(_) => self.allow.is_empty(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    /// Custom pages for the errors balancebeam sends, by status code
+    #[serde(default)]
+    pub error_pages: HashMap<u16, ErrorPage>,
+}
+
+/// Reads and parses a config file, along with any files it refers to.
+pub fn load(path: &str) -> Result<Config, String> {
+    let contents = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let mut config: Config = serde_json::from_str(&contents).map_err(|err| err.to_string())?;
+    for route in config.routes.iter_mut() {
+        if let Some(auth) = route.auth.as_mut() {
+            auth.prepare()?;
+        }
+        if let Some(dir) = &route.static_dir {
+            if !std::path::Path::new(dir).is_dir() {
+                return Err(format!("{} is not a directory", dir));
+            }
+        }
+        if let Some(faults) = &route.faults {
+            faults
+                .validate()
+                .map_err(|err| format!("Faults for route {}: {}", route.name(), err))?;
+        }
+    }
+    for (status, page) in config.error_pages.iter_mut() {
+        if http::StatusCode::from_u16(*status).is_err() {
+            return Err(format!("{} is not a valid status code", status));
+        }
+        page.prepare()?;
+    }
+    Ok(config)
+}
+
+/// Re-reads the config file (if there is one) and starts using the new routes. If the file can't
+/// be loaded, the routes already in use are kept.
+pub async fn reload(state: &ProxyState) -> Result<(), String> {
+    let path = match &state.config_path {
+        Some(path) => path,
+        None => return Err("no config file was given with --config".to_string()),
+    };
+    match load(path) {
+        Ok(config) => {
+            log::info!("Reloaded {} ({} routes)", path, config.routes.len());
+            *state.routes.write().await = config.routes.into_iter().map(Arc::new).collect();
+            *state.error_pages.write().await = config.error_pages;
+            Ok(())
+        }
+        Err(err) => {
+            log::error!("Could not reload {}: {}", path, err);
+            Err(err)
+        }
+    }
+}
+
+/// Decodes %XX escapes in a path, leaving malformed ones as they are.
+fn decode_lossy(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Returns the request's path the way an upstream is likely to understand it: decoded, with
+/// repeated slashes collapsed and "." and ".." segments resolved. Routes are matched against this
+/// rather than the raw path, so that e.g. "//admin", "/%61dmin" and "/x/../admin" can't get past
+/// the checks on the "/admin" route. The path is split on its literal slashes before each segment
+/// is decoded, since an upstream may keep an encoded "%2F" as part of a segment rather than
+/// treating it as a separator (so "/admin/..%2F../public" is still under "/admin").
+pub fn normalized_path<T>(request: &http::Request<T>) -> String {
+    let raw = request.uri().path();
+    let mut segments: Vec<String> = Vec::new();
+    for segment in raw.split('/').map(decode_lossy) {
+        match segment.as_str() {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    let mut path = format!("/{}", segments.join("/"));
+    if !segments.is_empty() && raw.ends_with('/') {
+        path.push('/');
+    }
+    path
+}
+
+/// Returns the route with the longest path prefix matching the request, if any.
+pub async fn find<T>(state: &ProxyState, request: &http::Request<T>) -> Option<Arc<Route>> {
+    let path = normalized_path(request);
+    state
+        .routes
+        .read()
+        .await
+        .iter()
+        .filter(|route| route.matches(&path))
+        .max_by_key(|route| route.path_prefix.len())
+        .cloned()
+}
+
+/// Returns the priority of the request's route, or normal priority if it has none.
+pub async fn priority<T>(state: &ProxyState, request: &http::Request<T>) -> Priority {
+    find(state, request)
+        .await
+        .and_then(|route| route.priority)
+        .unwrap_or(Priority::Normal)
+}
+
+/// Returns the size limits that apply to the request.
+pub async fn limits<T>(state: &ProxyState, request: &http::Request<T>) -> request::Limits {
+    match find(state, request).await {
+        Some(route) => route.limits.apply_to(state.limits),
+        None => state.limits,
+    }
+}
+
+/// Reads a request's headers from the client, enforcing the size limits of the route it is for.
+/// The body is left for `read_request_body`, so that the request can be refused before a client
+/// waiting for `100 Continue` is told to send it.
+pub async fn read_request_head(
+    stream: &mut PrefixedStream<impl AsyncRead + AsyncWrite + Unpin>,
+    state: &ProxyState,
+) -> Result<http::Request<Vec<u8>>, request::Error> {
+    let request = request::read_headers(stream, &state.limits).await?;
+    request::check_headers(&request, &limits(state, &request).await)?;
+    Ok(request)
+}
+
+/// Reads the body of a request whose head was read with `read_request_head`. A client that sent
+/// `Expect: 100-continue` is told to go ahead with the body if it is within the route's limit, or
+/// is refused without the body being read if it isn't.
+pub async fn read_request_body(
+    stream: &mut PrefixedStream<impl AsyncRead + AsyncWrite + Unpin>,
+    state: &ProxyState,
+    request: &mut http::Request<Vec<u8>>,
+) -> Result<(), request::Error> {
+    let limits = limits(state, request).await;
+    if request::expects_continue(request)? {
+        request::check_body_size(request, &limits)?;
+        if request.body().is_empty() {
+            log::debug!("Telling client to continue sending request body");
+            let interim = http::Response::builder()
+                .status(http::StatusCode::CONTINUE)
+                .version(http::Version::HTTP_11)
+                .body(Vec::new())
+                .unwrap();
+            response::write_to_stream(&interim, stream)
+                .await
+                .map_err(request::Error::Io)?;
+        }
+    }
+    // We answer any expectation ourselves; the upstream gets the request with its body
+    request.headers_mut().remove(http::header::EXPECT);
+    request::read_body(stream, request, &limits).await?;
+    // Keep anything read past the end of this request for reading the next one
+    stream.unread(request::take_pipelined(request)?);
+    Ok(())
+}
+
+/// Applies the checks configured for the request's route before it is forwarded, removing any
+/// credentials the route consumed. Returns the response to send the client instead if the request
+/// is refused.
+pub async fn screen_request<T>(
+    state: &ProxyState,
+    request: &mut http::Request<T>,
+    request_id: &str,
+    client_ip: &str,
+) -> Result<(), http::Response<Vec<u8>>> {
+    let route = match find(state, request).await {
+        Some(route) => route,
+        None => return Ok(()),
+    };
+    if !route.admits(client_ip) {
+        log::info!(
+            "Refusing {} access to route {}: address not allowed",
+            client_ip,
+            route.name()
+        );
+        state.metrics.increment(
+            "balancebeam_requests_rejected_total",
+            &[("route", route.name()), ("reason", "ip_denied")],
+        );
+        return Err(error_pages::make_error(state, http::StatusCode::FORBIDDEN, request_id).await);
+    }
+    if let Some(auth) = &route.auth {
+        if let Err(rejection) = auth.check(request).await {
+            log::info!(
+                "Refusing {} access to route {}: {:?}",
+                client_ip,
+                route.name(),
+                rejection
+            );
+            let reason = match rejection {
+                Rejection::MissingCredentials => "missing_credentials",
+                Rejection::InvalidCredentials => "invalid_credentials",
+            };
+            state.metrics.increment(
+                "balancebeam_requests_rejected_total",
+                &[("route", route.name()), ("reason", reason)],
+            );
+            let mut response = response::make_http_error(http::StatusCode::UNAUTHORIZED);
+            for challenge in auth.challenges(&rejection) {
+                match http::HeaderValue::from_str(&challenge) {
+                    Ok(value) => {
+                        response
+                            .headers_mut()
+                            .append(http::header::WWW_AUTHENTICATE, value);
+                    }
+                    Err(_) => log::error!("Could not send challenge {:?}", challenge),
+                }
+            }
+            return Err(error_pages::customize(state, response, request_id).await);
+        }
+        // The upstream trusts us to have checked the credentials, and has no use for them
+        request.headers_mut().remove(http::header::AUTHORIZATION);
+    }
+    Ok(())
+}
+
+/// Answers the request from its route's directory, if it is for a static route. Returns None if
+/// the request should be forwarded to an upstream as usual.
+pub async fn serve_static<T>(
+    state: &ProxyState,
+    request: &http::Request<T>,
+    request_id: &str,
+) -> Option<http::Response<Vec<u8>>> {
+    let route = find(state, request).await?;
+    let dir = route.static_dir.as_ref()?;
+    let path = normalized_path(request);
+    let relative_path = path.strip_prefix(route.path_prefix.as_str()).unwrap_or("");
+    Some(
+        match static_files::serve(
+            dir,
+            relative_path,
+            request,
+            state.response_limits.max_body_size,
+        )
+        .await
+        {
+            Ok(response) => response,
+            Err(status) => {
+                let mut response = error_pages::make_error(state, status, request_id).await;
+                if status == http::StatusCode::METHOD_NOT_ALLOWED {
+                    response.headers_mut().insert(
+                        http::header::ALLOW,
+                        http::HeaderValue::from_static("GET, HEAD"),
+                    );
+                }
+                response
+            }
+        },
+    )
+}
+
+/// Delays or aborts the request if its route's faults (or, for a client allowed to ask for them,
+/// its fault headers) call for it, returning the response to send the client instead if it is
+/// aborted. A response carrying the `faults::Reset` extension means the client should have its
+/// connection reset instead.
+pub async fn inject_faults<T>(
+    state: &ProxyState,
+    request: &mut http::Request<T>,
+    request_id: &str,
+    client_ip: &str,
+) -> Option<http::Response<Vec<u8>>> {
+    let requested = match state.faults.take_requested(request, client_ip) {
+        Ok(requested) => requested,
+        Err(err) => {
+            log::info!(
+                "Refusing request {} from {}: {}",
+                request_id,
+                client_ip,
+                err
+            );
+            return Some(
+                error_pages::make_error(state, http::StatusCode::BAD_REQUEST, request_id).await,
+            );
+        }
+    };
+    let route = find(state, request).await;
+    let faults = requested.or_else(|| {
+        let route = route.as_ref()?;
+        state.faults.for_route(route.name(), route.faults.as_ref())
+    })?;
+    let route_name = route.as_ref().map_or("", |route| route.name());
+    let record = |fault| {
+        log::info!(
+            "Injecting {} into request {} from {}",
+            fault,
+            request_id,
+            client_ip
+        );
+        match route {
+            Some(_) => state.metrics.increment(
+                "balancebeam_faults_injected_total",
+                &[("route", route_name), ("fault", fault)],
+            ),
+            None => state
+                .metrics
+                .increment("balancebeam_faults_injected_total", &[("fault", fault)]),
+        }
+    };
+
+    let (delay, action) = faults.roll();
+    if let Some(delay) = delay {
+        record("delay");
+        tokio::time::sleep(delay).await;
+    }
+    match action? {
+        faults::Action::Status(status) => {
+            record("abort");
+            Some(error_pages::make_error(state, status, request_id).await)
+        }
+        faults::Action::Reset => {
+            record("reset");
+            let mut response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
+            response.extensions_mut().insert(faults::Reset);
+            Some(response)
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::cidr::{self, Cidr};
+
+/// How often the event loop is checked for falling behind
+const LAG_PROBE_INTERVAL: Duration = Duration::from_millis(100);
+/// Low priority requests are shed once the load reaches this fraction of the limits, so that
+/// normal traffic has some headroom left
+const LOW_PRIORITY_THRESHOLD: f64 = 0.8;
+
+/// How important a request is. When balancebeam is overloaded, the least important requests are
+/// turned away first.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    Normal,
+    /// Never shed
+    Critical,
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::Critical => "critical",
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Priority, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "critical" => Ok(Priority::Critical),
+            _ => Err(format!("{} is not a priority", value)),
+        }
+    }
+}
+
+/// Decides when balancebeam is too busy to take on more requests, by comparing how many client
+/// connections are open and how far behind the event loop is running against their limits. Once
+/// either reaches its limit, only critical requests are let through; low priority requests are
+/// shed a little earlier than that.
+pub struct Shedder {
+    max_connections: Option<usize>,
+    max_loop_lag: Option<Duration>,
+    /// Header that clients (or a trusted proxy in front of us) may use to give a request's
+    /// priority, overriding the one for its route
+    header: Option<http::HeaderName>,
+    /// Clients allowed to raise their requests' priority with the header. Anyone else can only
+    /// lower it
+    header_allow: Vec<Cidr>,
+    connections: AtomicUsize,
+    loop_lag_micros: AtomicU64,
+}
+
+/// Counts a client connection as open until it is dropped.
+pub struct OpenConnection(Arc<Shedder>);
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Shedder {
+    pub fn new(
+        max_connections: Option<usize>,
+        max_loop_lag: Option<Duration>,
+        header: Option<http::HeaderName>,
+        header_allow: Vec<Cidr>,
+    ) -> Shedder {
+        Shedder {
+            max_connections,
+            max_loop_lag,
+            header,
+            header_allow,
+            connections: AtomicUsize::new(0),
+            loop_lag_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn connection_opened(self: &Arc<Self>) -> OpenConnection {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        OpenConnection(self.clone())
+    }
+
+    /// Works out a request's priority from the priority header, if it has a valid one, or else
+    /// from its route's priority. Otherwise anyone could mark their requests critical to get past
+    /// the shedding, so only clients in the header allowlist may use the header to raise a
+    /// request's priority above its route's.
+    pub fn classify<T>(
+        &self,
+        request: &http::Request<T>,
+        route_priority: Priority,
+        client_ip: &str,
+    ) -> Priority {
+        let requested: Priority = match self
+            .header
+            .as_ref()
+            .and_then(|header| request.headers().get(header))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+        {
+            Some(requested) => requested,
+            None => return route_priority,
+        };
+        let allowed = client_ip
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| cidr::any_contains(&self.header_allow, &ip));
+        if allowed {
+            requested
+        } else {
+            requested.min(route_priority)
+        }
+    }
+
+    /// How busy we are, as the largest fraction of any limit in use.
+    fn load(&self) -> f64 {
+        let connections = self.max_connections.map_or(0.0, |max_connections| {
+            self.connections.load(Ordering::SeqCst) as f64 / max_connections as f64
+        });
+        let loop_lag = self.max_loop_lag.map_or(0.0, |max_loop_lag| {
+            self.loop_lag_micros.load(Ordering::SeqCst) as f64 / max_loop_lag.as_micros() as f64
+        });
+        connections.max(loop_lag)
+    }
+
+    pub fn should_shed(&self, priority: Priority) -> bool {
+        let threshold = match priority {
+            Priority::Low => LOW_PRIORITY_THRESHOLD,
+            Priority::Normal => 1.0,
+            Priority::Critical => return false,
+        };
+        self.load() >= threshold
+    }
+}
+
+/// Measures how late the event loop wakes up from a short sleep, for as long as balancebeam runs.
+/// A loop with too much to do can't get back to a sleeping task on time.
+pub async fn measure_loop_lag(shedder: Arc<Shedder>) {
+    if shedder.max_loop_lag.is_none() {
+        return;
+    }
+    loop {
+        let started = Instant::now();
+        tokio::time::sleep(LAG_PROBE_INTERVAL).await;
+        let lag = started.elapsed().saturating_sub(LAG_PROBE_INTERVAL);
+        shedder
+            .loop_lag_micros
+            .store(lag.as_micros() as u64, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,265 @@
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// Content types for the kinds of files a maintenance page or small site is likely to be made of.
+/// Anything else is served as application/octet-stream.
+const CONTENT_TYPES: [(&str, &str); 24] = [
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("xml", "application/xml"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("ico", "image/x-icon"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("pdf", "application/pdf"),
+    ("wasm", "application/wasm"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("map", "application/json"),
+];
+
+/// Guesses a file's content type from its extension.
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    CONTENT_TYPES
+        .iter()
+        .find(|(known, _)| *known == extension)
+        .map(|(_, content_type)| *content_type)
+        .unwrap_or("application/octet-stream")
+}
+
+/// The part of a file a Range header asks for.
+#[derive(Debug, PartialEq, Eq)]
+enum Range {
+    /// No range (or one we don't support) was asked for, so the whole file is sent
+    Whole,
+    /// The bytes from the first offset to the second, inclusive
+    Part(u64, u64),
+    /// The range starts past the end of the file
+    Unsatisfiable,
+}
+
+/// Works out which bytes of a file of the given length a Range header asks for. Only a single
+/// range of bytes is supported; headers that are malformed or ask for several ranges are ignored,
+/// as RFC 9110 allows.
+fn parse_range(header: Option<&http::HeaderValue>, len: u64) -> Range {
+    let spec = match header
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().strip_prefix("bytes="))
+    {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Range::Whole,
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return Range::Whole,
+    };
+    if first.is_empty() {
+        // A suffix range, asking for the last so many bytes
+        return match last.parse::<u64>() {
+            Ok(0) => Range::Unsatisfiable,
+            Ok(_) if len == 0 => Range::Unsatisfiable,
+            Ok(suffix) => Range::Part(len.saturating_sub(suffix), len - 1),
+            Err(_) => Range::Whole,
+        };
+    }
+    let first = match first.parse::<u64>() {
+        Ok(first) => first,
+        Err(_) => return Range::Whole,
+    };
+    let last = if last.is_empty() {
+        u64::MAX
+    } else {
+        match last.parse::<u64>() {
+            Ok(last) if last >= first => last,
+            _ => return Range::Whole,
+        }
+    };
+    if first >= len {
+        Range::Unsatisfiable
+    } else {
+        Range::Part(first, last.min(len - 1))
+    }
+}
+
+/// Decodes %XX escapes in a request path. Returns None if an escape is malformed or the result
+/// isn't UTF-8.
+pub fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Maps the part of a request path after a route's prefix to a path inside `dir`. Returns None if
+/// the path tries to climb out of the directory.
+fn resolve(dir: &str, relative_path: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::from(dir);
+    for segment in relative_path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            _ if segment.contains(['\0', '\\']) => return None,
+            _ => path.push(segment),
+        }
+    }
+    Some(path)
+}
+
+/// Follows any symlinks in `path`, returning where it really leads, or None if that is outside
+/// `dir`. `resolve` only keeps the request path itself inside the directory, while a symlink in
+/// the directory could point anywhere.
+async fn confine(dir: &str, path: &Path) -> Option<PathBuf> {
+    let dir = tokio::fs::canonicalize(dir).await.ok()?;
+    let path = tokio::fs::canonicalize(path).await.ok()?;
+    path.starts_with(&dir).then_some(path)
+}
+
+fn build_response(status: http::StatusCode) -> http::response::Builder {
+    http::Response::builder()
+        .status(status)
+        .version(http::Version::HTTP_11)
+}
+
+/// Answers a GET or HEAD request from the files in `dir`. `relative_path` is the normalized
+/// request path (see `routes::normalized_path`) with the route's prefix removed; a path naming a
+/// directory is answered with its index.html. Supports single byte ranges and If-Modified-Since.
+/// Only the bytes being sent are read, and bodies bigger than `max_body_size` are refused. Errors
+/// are returned as the status to answer with, so that the caller can send the matching error page.
+pub async fn serve<T>(
+    dir: &str,
+    relative_path: &str,
+    request: &http::Request<T>,
+    max_body_size: usize,
+) -> Result<http::Response<Vec<u8>>, http::StatusCode> {
+    if request.method() != http::Method::GET && request.method() != http::Method::HEAD {
+        return Err(http::StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let mut path = resolve(dir, relative_path).ok_or(http::StatusCode::NOT_FOUND)?;
+    let mut metadata = tokio::fs::metadata(&path)
+        .await
+        .map_err(|_| http::StatusCode::NOT_FOUND)?;
+    if metadata.is_dir() {
+        path.push("index.html");
+        metadata = tokio::fs::metadata(&path)
+            .await
+            .map_err(|_| http::StatusCode::NOT_FOUND)?;
+    }
+    if !metadata.is_file() {
+        return Err(http::StatusCode::NOT_FOUND);
+    }
+    let path = confine(dir, &path)
+        .await
+        .ok_or(http::StatusCode::NOT_FOUND)?;
+
+    // HTTP dates only go down to the second, so compare modification times at that resolution
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|since_epoch| UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs()));
+    let last_modified = modified.map(httpdate::fmt_http_date);
+    let not_modified = match (
+        modified,
+        request
+            .headers()
+            .get(http::header::IF_MODIFIED_SINCE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| httpdate::parse_http_date(value).ok()),
+    ) {
+        (Some(modified), Some(since)) => modified <= since,
+        _ => false,
+    };
+
+    let mut response = build_response(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, content_type(&path))
+        .header(http::header::ACCEPT_RANGES, "bytes");
+    if let Some(last_modified) = &last_modified {
+        response = response.header(http::header::LAST_MODIFIED, last_modified);
+    }
+    if not_modified {
+        return Ok(response
+            .status(http::StatusCode::NOT_MODIFIED)
+            .body(Vec::new())
+            .unwrap());
+    }
+
+    let len = metadata.len();
+    let range = if request.method() == http::Method::GET {
+        parse_range(request.headers().get(http::header::RANGE), len)
+    } else {
+        Range::Whole
+    };
+    let (first, last) = match range {
+        Range::Whole => (0, len.saturating_sub(1)),
+        Range::Part(first, last) => {
+            response = response.status(http::StatusCode::PARTIAL_CONTENT).header(
+                http::header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", first, last, len),
+            );
+            (first, last)
+        }
+        Range::Unsatisfiable => {
+            return Ok(build_response(http::StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(http::header::CONTENT_RANGE, format!("bytes */{}", len))
+                .header(http::header::CONTENT_LENGTH, "0")
+                .body(Vec::new())
+                .unwrap());
+        }
+    };
+    let body_len = if len == 0 { 0 } else { last - first + 1 };
+    let response = response.header(http::header::CONTENT_LENGTH, body_len.to_string());
+    // A HEAD response says how big the body would be without sending it
+    if request.method() == http::Method::HEAD {
+        return Ok(response.body(Vec::new()).unwrap());
+    }
+    if body_len > max_body_size as u64 {
+        log::warn!(
+            "Not serving {} bytes of {}: bigger than the max_body_size limit",
+            body_len,
+            path.display()
+        );
+        return Err(http::StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    let read_error = |err: std::io::Error| {
+        log::error!("Could not read {}: {}", path.display(), err);
+        http::StatusCode::INTERNAL_SERVER_ERROR
+    };
+    let mut file = tokio::fs::File::open(&path).await.map_err(read_error)?;
+    file.seek(SeekFrom::Start(first))
+        .await
+        .map_err(read_error)?;
+    // Fails if the file has shrunk since we looked at it
+    let mut body = vec![0_u8; body_len as usize];
+    file.read_exact(&mut body).await.map_err(read_error)?;
+    Ok(response.body(body).unwrap())
+}
@@ -0,0 +1,230 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+/// Addresses with this prefix (e.g. "unix:/run/app.sock") name Unix domain sockets rather than
+/// TCP host:port pairs.
+const UNIX_PREFIX: &str = "unix:";
+
+/// Returns the socket path if the address names a Unix domain socket.
+pub fn unix_path(address: &str) -> Option<&str> {
+    address.strip_prefix(UNIX_PREFIX)
+}
+
+/// The two ends of a client's TCP connection: where it came from, and which of our addresses it
+/// was sent to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionAddrs {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+/// Describes the client as it should appear in logs, rate limiting and X-Forwarded-For. Clients
+/// of a Unix domain socket have no IP address, so they all show up as "unix:".
+pub fn client_ip(addrs: Option<&ConnectionAddrs>) -> String {
+    match addrs {
+        Some(addrs) => addrs.source.ip().to_string(),
+        None => UNIX_PREFIX.to_string(),
+    }
+}
+
+/// Wraps a stream whose first few bytes have already been read (e.g. to work out which protocol
+/// the client is speaking) and replays those bytes before reading anything new from the stream.
+/// Writes go straight through to the underlying stream.
+pub struct PrefixedStream<S> {
+    prefix: Vec<u8>,
+    prefix_pos: usize,
+    inner: S,
+}
+
+impl<S> PrefixedStream<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> PrefixedStream<S> {
+        PrefixedStream {
+            prefix,
+            prefix_pos: 0,
+            inner,
+        }
+    }
+
+    /// Puts bytes back in front of whatever is still to be read, e.g. the start of a pipelined
+    /// request that was read along with the one before it.
+    pub fn unread(&mut self, mut bytes: Vec<u8>) {
+        if bytes.is_empty() {
+            return;
+        }
+        bytes.extend_from_slice(&self.prefix[self.prefix_pos..]);
+        self.prefix = bytes;
+        self.prefix_pos = 0;
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PrefixedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.prefix_pos < this.prefix.len() {
+            let remaining = &this.prefix[this.prefix_pos..];
+            let len = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..len]);
+            this.prefix_pos += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// A connection over either TCP or a Unix domain socket.
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Connects to a host:port over TCP, or to a unix:/path socket.
+    pub async fn connect(address: &str) -> io::Result<Stream> {
+        match unix_path(address) {
+            Some(path) => Ok(Stream::Unix(UnixStream::connect(path).await?)),
+            None => Ok(Stream::Tcp(TcpStream::connect(address).await?)),
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// A client connection that can be made to end abruptly, with a TCP reset rather than an orderly
+/// close, whatever it has been wrapped in.
+pub trait ResetOnClose {
+    /// Makes closing the connection reset it. Unix domain sockets have no such thing, so they just
+    /// close.
+    fn reset_on_close(&self);
+}
+
+impl ResetOnClose for Stream {
+    fn reset_on_close(&self) {
+        if let Stream::Tcp(stream) = self {
+            if let Err(err) = stream.set_zero_linger() {
+                log::debug!("Could not set up connection to be reset: {}", err);
+            }
+        }
+    }
+}
+
+impl<S: ResetOnClose> ResetOnClose for PrefixedStream<S> {
+    fn reset_on_close(&self) {
+        self.inner.reset_on_close();
+    }
+}
+
+impl<S: ResetOnClose> ResetOnClose for tokio_rustls::server::TlsStream<S> {
+    fn reset_on_close(&self) {
+        self.get_ref().0.reset_on_close();
+    }
+}
+
+/// Listens for connections over either TCP or a Unix domain socket.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds to a host:port over TCP, or to a unix:/path socket. A socket file left behind by an
+    /// earlier run is replaced; any other kind of file at that path is left alone.
+    pub async fn bind(address: &str) -> io::Result<Listener> {
+        match unix_path(address) {
+            Some(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        std::fs::remove_file(path)?;
+                    }
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+            None => Ok(Listener::Tcp(TcpListener::bind(address).await?)),
+        }
+    }
+
+    /// Accepts a connection, returning it along with its addresses (or None for a Unix domain
+    /// socket connection, which has none).
+    pub async fn accept(&self) -> io::Result<(Stream, Option<ConnectionAddrs>)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, source) = listener.accept().await?;
+                let destination = stream.local_addr()?;
+                let addrs = ConnectionAddrs {
+                    source,
+                    destination,
+                };
+                Ok((Stream::Tcp(stream), Some(addrs)))
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::Unix(stream), None))
+            }
+        }
+    }
+}
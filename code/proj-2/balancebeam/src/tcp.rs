@@ -0,0 +1,91 @@
+use crate::stream::{ConnectionAddrs, Stream};
+use crate::{
+    choose_pool, mark_unreachable, open_upstream_conn, target_upstream, tunnel, ProxyState,
+};
+
+/// Active health check used in tcp mode: since we don't know what protocol the upstream speaks,
+/// the best we can do is make sure it is still accepting connections.
+pub async fn probe_upstream(state: &ProxyState, upstream_ip: &str) -> bool {
+    match open_upstream_conn(state, upstream_ip, None).await {
+        Ok(_) => true,
+        Err(err) => {
+            log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
+            false
+        }
+    }
+}
+
+/// Connects to a random alive upstream (in the chosen pool, if traffic is being split), marking
+/// upstreams that can't be reached as dead and trying another. Connections and failures are
+/// counted per upstream. Returns None if no upstream could be reached.
+async fn connect(
+    state: &ProxyState,
+    client_addrs: Option<&ConnectionAddrs>,
+) -> Option<(Stream, String)> {
+    let pool = choose_pool(state, None::<&http::Request<()>>);
+    loop {
+        let upstream_ip = target_upstream(state, None::<&http::Request<()>>, pool).await?;
+        let labels = [("upstream", upstream_ip.as_str())];
+        match open_upstream_conn(state, &upstream_ip, client_addrs).await {
+            Ok(stream) => {
+                state
+                    .metrics
+                    .increment("balancebeam_tcp_connections_total", &labels);
+                return Some((stream, upstream_ip));
+            }
+            Err(err) => {
+                log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
+                state
+                    .metrics
+                    .increment("balancebeam_tcp_connect_failures_total", &labels);
+                if !mark_unreachable(state, &upstream_ip).await {
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Handles a client connection in tcp mode. The whole connection is assigned to a single upstream
+/// server and bytes are copied back and forth without being parsed. If no upstream is available,
+/// the client connection is simply closed.
+pub async fn handle_connection(
+    mut client_conn: Stream,
+    client_ip: String,
+    client_addrs: Option<ConnectionAddrs>,
+    state: &ProxyState,
+) {
+    log::info!("Connection received from {}", client_ip);
+
+    let (mut upstream_conn, upstream_ip) = match connect(state, client_addrs.as_ref()).await {
+        Some(upstream) => upstream,
+        None => {
+            log::info!(
+                "Closing connection from {}: no upstream available",
+                client_ip
+            );
+            return;
+        }
+    };
+
+    if let Some((to_upstream, to_client)) = tunnel(
+        &mut client_conn,
+        &mut upstream_conn,
+        &client_ip,
+        &upstream_ip,
+    )
+    .await
+    {
+        let upstream = upstream_ip.as_str();
+        state.metrics.add(
+            "balancebeam_tcp_bytes_total",
+            &[("upstream", upstream), ("direction", "to_upstream")],
+            to_upstream as f64,
+        );
+        state.metrics.add(
+            "balancebeam_tcp_bytes_total",
+            &[("upstream", upstream), ("direction", "to_client")],
+            to_client as f64,
+        );
+    }
+}
@@ -0,0 +1,793 @@
+//! Test doubles for putting balancebeam through its paces: upstreams that can be told to answer
+//! slowly, with a given status code, a dribble at a time, chunked, or not at all, and a harness
+//! that runs balancebeam in front of them. balancebeam's own integration tests are built on these,
+//! and other projects can use them to see how they cope with the same failures.
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use balancebeam::testing::{BalanceBeam, Behavior, Upstream};
+//! # async fn example() {
+//! // Every third request fails, and the rest are slow
+//! let requests = std::sync::atomic::AtomicUsize::new(0);
+//! let upstream = Upstream::builder()
+//!     .scenario(move |_request| {
+//!         if requests.fetch_add(1, std::sync::atomic::Ordering::SeqCst) % 3 == 2 {
+//!             Behavior::fixed(503, "Try again later")
+//!         } else {
+//!             Behavior::echo().with_latency(Duration::from_millis(200))
+//!         }
+//!     })
+//!     .start()
+//!     .await;
+//! let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+//! # }
+//! ```
+
+use std::path::PathBuf;
+use std::sync::{atomic, Arc};
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use rand::Rng;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::process::{Child, Command};
+use tokio::sync::{oneshot, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout};
+
+/// Returns a loopback address on a free port, for a server that needs to be told where to listen
+/// before it starts. The port is found by binding to one and letting it go again.
+pub fn random_address() -> String {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("Could not find a free port")
+        .to_string()
+}
+
+/// How an upstream answers a request. The default is to echo the request back (its request line,
+/// headers, a blank line and then its body) with a 200 OK, straight away; each of the `with_`
+/// methods changes one thing about that, and they can be combined.
+#[derive(Clone, Debug)]
+pub struct Behavior {
+    status: http::StatusCode,
+    /// The body to send, or None to describe the request
+    body: Option<Vec<u8>>,
+    latency: Duration,
+    /// Send the body with chunked transfer encoding, in chunks of this size
+    chunk_size: Option<usize>,
+    /// Send the response this many bytes at a time, waiting this long in between
+    trickle: Option<(usize, Duration)>,
+    /// Reset the connection once this many bytes of the response have been sent
+    reset_after: Option<usize>,
+}
+
+impl Default for Behavior {
+    fn default() -> Behavior {
+        Behavior::echo()
+    }
+}
+
+impl Behavior {
+    /// Answers with a description of the request.
+    pub fn echo() -> Behavior {
+        Behavior {
+            status: http::StatusCode::OK,
+            body: None,
+            latency: Duration::ZERO,
+            chunk_size: None,
+            trickle: None,
+            reset_after: None,
+        }
+    }
+
+    /// Answers with the given status code and body, whatever the request.
+    pub fn fixed(status: u16, body: impl Into<Vec<u8>>) -> Behavior {
+        Behavior {
+            body: Some(body.into()),
+            ..Behavior::echo().with_status(status)
+        }
+    }
+
+    /// Resets the connection instead of answering.
+    pub fn reset() -> Behavior {
+        Behavior::echo().with_reset_after(0)
+    }
+
+    pub fn with_status(self, status: u16) -> Behavior {
+        Behavior {
+            status: http::StatusCode::from_u16(status).expect("Invalid status code"),
+            ..self
+        }
+    }
+
+    /// Waits this long before answering. If the upstream has a limited number of workers, the
+    /// request holds one of them while it waits.
+    pub fn with_latency(self, latency: Duration) -> Behavior {
+        Behavior { latency, ..self }
+    }
+
+    /// Sends the body with chunked transfer encoding, in chunks of the given size, rather than
+    /// with a Content-Length.
+    pub fn with_chunked_body(self, chunk_size: usize) -> Behavior {
+        assert!(chunk_size > 0, "Chunks must not be empty");
+        Behavior {
+            chunk_size: Some(chunk_size),
+            ..self
+        }
+    }
+
+    /// Sends the response (head and body) a few bytes at a time, waiting between each write.
+    pub fn with_trickle(self, bytes: usize, interval: Duration) -> Behavior {
+        assert!(bytes > 0, "Must send at least one byte at a time");
+        Behavior {
+            trickle: Some((bytes, interval)),
+            ..self
+        }
+    }
+
+    /// Resets the connection partway through the response, once this many bytes of it have been
+    /// sent.
+    pub fn with_reset_after(self, bytes: usize) -> Behavior {
+        Behavior {
+            reset_after: Some(bytes),
+            ..self
+        }
+    }
+
+    /// Encodes the response to the given request, head and body.
+    fn response(&self, request: &http::Request<Vec<u8>>, keep_alive: bool) -> Vec<u8> {
+        let body = match &self.body {
+            Some(body) => body.clone(),
+            None => describe(request),
+        };
+        let mut response = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status.as_str(),
+            self.status.canonical_reason().unwrap_or("")
+        );
+        match self.chunk_size {
+            Some(_) => response += "Transfer-Encoding: chunked\r\n",
+            None => response += &format!("Content-Length: {}\r\n", body.len()),
+        }
+        if !keep_alive {
+            response += "Connection: close\r\n";
+        }
+        response += "\r\n";
+        let mut response = response.into_bytes();
+        if request.method() == http::Method::HEAD {
+            return response;
+        }
+        match self.chunk_size {
+            Some(chunk_size) => {
+                for chunk in body.chunks(chunk_size) {
+                    response.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+                    response.extend_from_slice(chunk);
+                    response.extend_from_slice(b"\r\n");
+                }
+                response.extend_from_slice(b"0\r\n\r\n");
+            }
+            None => response.extend_from_slice(&body),
+        }
+        response
+    }
+}
+
+/// Describes a request the way the echo behavior sends it back.
+fn describe(request: &http::Request<Vec<u8>>) -> Vec<u8> {
+    let mut description = format!(
+        "{} {} {:?}\n",
+        request.method(),
+        request.uri(),
+        request.version()
+    );
+    for (name, value) in request.headers() {
+        description += &format!(
+            "{}: {}\n",
+            name.as_str(),
+            value.to_str().unwrap_or("<binary value>")
+        );
+    }
+    description += "\n";
+    let mut description = description.into_bytes();
+    description.extend_from_slice(request.body());
+    description
+}
+
+/// Picks the behavior for each request an upstream receives.
+type Scenario = Arc<dyn Fn(&http::Request<Vec<u8>>) -> Behavior + Send + Sync>;
+
+struct UpstreamState {
+    requests_received: atomic::AtomicUsize,
+    scenario: Mutex<Scenario>,
+    /// Each request takes one of these while it is being worked on
+    workers: Semaphore,
+}
+
+/// What to do with a connection once we are done with it.
+enum Hangup {
+    Close,
+    Reset,
+}
+
+/// Reads the next request off the connection, leaving anything sent after it in the buffer.
+/// Returns None once the client hangs up (or sends something that isn't HTTP). Request bodies
+/// must have a Content-Length, which is all balancebeam sends.
+async fn read_request(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    buffer: &mut Vec<u8>,
+) -> Option<http::Request<Vec<u8>>> {
+    let (mut request, head_len, content_length) = loop {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut parsed = httparse::Request::new(&mut headers);
+        if let httparse::Status::Complete(head_len) = parsed.parse(buffer).ok()? {
+            let mut request = http::Request::builder()
+                .method(parsed.method?)
+                .uri(parsed.path?)
+                .version(match parsed.version? {
+                    0 => http::Version::HTTP_10,
+                    _ => http::Version::HTTP_11,
+                });
+            let mut content_length = 0;
+            for header in parsed.headers.iter() {
+                if header.name.eq_ignore_ascii_case("content-length") {
+                    content_length = std::str::from_utf8(header.value)
+                        .ok()?
+                        .trim()
+                        .parse()
+                        .ok()?;
+                }
+                request = request.header(header.name, header.value);
+            }
+            break (request.body(Vec::new()).ok()?, head_len, content_length);
+        }
+        if read_more(stream, buffer).await? == 0 {
+            return None;
+        }
+    };
+
+    if buffer.len() < head_len + content_length
+        && request_expects_continue(&request)
+        && stream
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .await
+            .is_err()
+    {
+        return None;
+    }
+    while buffer.len() < head_len + content_length {
+        if read_more(stream, buffer).await? == 0 {
+            return None;
+        }
+    }
+    *request.body_mut() = buffer[head_len..head_len + content_length].to_vec();
+    buffer.drain(..head_len + content_length);
+    Some(request)
+}
+
+async fn read_more(stream: &mut (impl AsyncRead + Unpin), buffer: &mut Vec<u8>) -> Option<usize> {
+    let mut chunk = [0_u8; 4096];
+    let bytes_read = stream.read(&mut chunk).await.ok()?;
+    buffer.extend_from_slice(&chunk[..bytes_read]);
+    Some(bytes_read)
+}
+
+fn request_expects_continue(request: &http::Request<Vec<u8>>) -> bool {
+    request
+        .headers()
+        .get(http::header::EXPECT)
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+}
+
+fn wants_keep_alive(request: &http::Request<Vec<u8>>) -> bool {
+    let has_option = |option: &str| {
+        request
+            .headers()
+            .get_all(http::header::CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(option))
+    };
+    match request.version() {
+        http::Version::HTTP_10 => has_option("keep-alive"),
+        _ => !has_option("close"),
+    }
+}
+
+/// Writes a response the way the behavior says to, stopping early if it calls for a reset.
+async fn write_response(
+    stream: &mut (impl AsyncWrite + Unpin),
+    response: &[u8],
+    behavior: &Behavior,
+) -> Result<Option<Hangup>, std::io::Error> {
+    let (sending, hangup) = match behavior.reset_after {
+        Some(reset_after) if reset_after < response.len() => {
+            (&response[..reset_after], Some(Hangup::Reset))
+        }
+        _ => (response, None),
+    };
+    match behavior.trickle {
+        Some((bytes, interval)) => {
+            for (i, piece) in sending.chunks(bytes).enumerate() {
+                if i > 0 {
+                    sleep(interval).await;
+                }
+                stream.write_all(piece).await?;
+            }
+        }
+        None => stream.write_all(sending).await?,
+    }
+    Ok(hangup)
+}
+
+/// Answers requests on one connection until the client hangs up, or we do.
+async fn serve_connection(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    state: &UpstreamState,
+) -> Hangup {
+    let mut buffer = Vec::new();
+    while let Some(request) = read_request(stream, &mut buffer).await {
+        state
+            .requests_received
+            .fetch_add(1, atomic::Ordering::SeqCst);
+        let scenario = state.scenario.lock().clone();
+        let behavior = scenario(&request);
+        {
+            // The semaphore is never closed
+            let _worker = state.workers.acquire().await.unwrap();
+            sleep(behavior.latency).await;
+        }
+        let keep_alive = wants_keep_alive(&request);
+        match write_response(stream, &behavior.response(&request, keep_alive), &behavior).await {
+            Ok(Some(hangup)) => return hangup,
+            Ok(None) if keep_alive => {}
+            Ok(None) | Err(_) => return Hangup::Close,
+        }
+    }
+    Hangup::Close
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+/// Accepts connections until told to shut down, then drops all of them.
+async fn accept_connections(
+    listener: Listener,
+    state: Arc<UpstreamState>,
+    mut shutdown_rx: oneshot::Receiver<()>,
+) {
+    let mut connections = JoinSet::new();
+    loop {
+        let state = state.clone();
+        tokio::select! {
+            accepted = async {
+                match &listener {
+                    Listener::Tcp(listener) => listener.accept().await.map(|(stream, _)| {
+                        let _ = stream.set_nodelay(true);
+                        connections.spawn(serve_tcp(stream, state));
+                    }),
+                    Listener::Unix(listener, _) => listener.accept().await.map(|(stream, _)| {
+                        connections.spawn(serve_unix(stream, state));
+                    }),
+                }
+            } => {
+                if let Err(e) = accepted {
+                    log::error!("Error in Upstream: {}", e);
+                }
+            }
+            _ = &mut shutdown_rx => break,
+        }
+        while connections.try_join_next().is_some() {}
+    }
+    if let Listener::Unix(_, path) = listener {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+async fn serve_tcp(mut stream: TcpStream, state: Arc<UpstreamState>) {
+    if let Hangup::Reset = serve_connection(&mut stream, &state).await {
+        // Closing with a zero linger time sends a RST rather than a FIN
+        let _ = stream.set_zero_linger();
+    }
+}
+
+async fn serve_unix(mut stream: UnixStream, state: Arc<UpstreamState>) {
+    // Unix sockets can't be reset, so the closest we can get is hanging up
+    serve_connection(&mut stream, &state).await;
+}
+
+/// An HTTP upstream whose behavior can be scripted. By default it echoes each request back; see
+/// `Behavior` for the other ways it can answer. Connections are kept open between requests (unless
+/// the client asks otherwise), and are cut when the upstream is stopped or dropped.
+pub struct Upstream {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<UpstreamState>,
+}
+
+/// Sets up an `Upstream`. Anything not set is the same as for `Upstream::new`.
+pub struct UpstreamBuilder {
+    address: Option<String>,
+    scenario: Scenario,
+    workers: usize,
+}
+
+impl UpstreamBuilder {
+    /// Listens on the given address, which may be a unix:/path socket, rather than a free port.
+    pub fn address(self, address: impl Into<String>) -> UpstreamBuilder {
+        UpstreamBuilder {
+            address: Some(address.into()),
+            ..self
+        }
+    }
+
+    /// Answers every request the same way.
+    pub fn behavior(self, behavior: Behavior) -> UpstreamBuilder {
+        self.scenario(move |_| behavior.clone())
+    }
+
+    /// Picks how to answer each request by calling the given function with it.
+    pub fn scenario(
+        self,
+        scenario: impl Fn(&http::Request<Vec<u8>>) -> Behavior + Send + Sync + 'static,
+    ) -> UpstreamBuilder {
+        UpstreamBuilder {
+            scenario: Arc::new(scenario),
+            ..self
+        }
+    }
+
+    /// Works on at most this many requests at once, like a real server with a fixed number of
+    /// workers, so that requests beyond that wait their turn (and take longer).
+    pub fn workers(self, workers: usize) -> UpstreamBuilder {
+        UpstreamBuilder { workers, ..self }
+    }
+
+    pub async fn start(self) -> Upstream {
+        let address = self.address.unwrap_or_else(|| "127.0.0.1:0".to_string());
+        let (listener, address) = match address.strip_prefix("unix:") {
+            Some(path) => (
+                Listener::Unix(
+                    UnixListener::bind(path).expect("Upstream could not bind to socket"),
+                    PathBuf::from(path),
+                ),
+                address.clone(),
+            ),
+            None => {
+                let listener = TcpListener::bind(&address)
+                    .await
+                    .expect("Upstream could not bind to address");
+                let address = listener.local_addr().unwrap().to_string();
+                (Listener::Tcp(listener), address)
+            }
+        };
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let state = Arc::new(UpstreamState {
+            requests_received: atomic::AtomicUsize::new(0),
+            scenario: Mutex::new(self.scenario),
+            workers: Semaphore::new(self.workers),
+        });
+        let server_task = tokio::spawn(accept_connections(listener, state.clone(), shutdown_rx));
+
+        Upstream {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            address,
+            state,
+        }
+    }
+}
+
+impl Upstream {
+    pub fn builder() -> UpstreamBuilder {
+        UpstreamBuilder {
+            address: None,
+            scenario: Arc::new(|_| Behavior::echo()),
+            workers: Semaphore::MAX_PERMITS,
+        }
+    }
+
+    /// Starts an echo upstream on a free port.
+    pub async fn new() -> Upstream {
+        Upstream::builder().start().await
+    }
+
+    /// Starts an echo upstream listening on a Unix domain socket in the temporary directory. Its
+    /// address takes the form "unix:/path", just like balancebeam's.
+    pub async fn new_unix() -> Upstream {
+        let path = std::env::temp_dir().join(format!(
+            "balancebeam-test-{}-upstream.sock",
+            rand::thread_rng().gen::<u32>()
+        ));
+        Upstream::new_at_address(format!("unix:{}", path.to_str().unwrap())).await
+    }
+
+    /// Starts an echo upstream listening on the given address.
+    pub async fn new_at_address(address: impl Into<String>) -> Upstream {
+        Upstream::builder().address(address).start().await
+    }
+
+    /// Starts an upstream on a free port that answers every request the same way.
+    pub async fn with_behavior(behavior: Behavior) -> Upstream {
+        Upstream::builder().behavior(behavior).start().await
+    }
+
+    /// Changes how the upstream answers from now on, e.g. to make a healthy upstream fail.
+    pub fn set_behavior(&self, behavior: Behavior) {
+        *self.state.scenario.lock() = Arc::new(move |_| behavior.clone());
+    }
+
+    pub fn requests_received(&self) -> usize {
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    /// Stops the upstream, returning how many requests it received.
+    pub async fn stop(self) -> usize {
+        // Tell the accept loop to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("Upstream server task panicked");
+
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+}
+
+/// A balancebeam process, killed when this is dropped. The binary is taken from
+/// $BALANCEBEAM_BIN if that is set, or else from the target directory of the cargo build running
+/// the tests.
+pub struct BalanceBeam {
+    child: Child,
+    pub address: String,
+}
+
+impl BalanceBeam {
+    fn target_bin_path() -> PathBuf {
+        if let Some(path) = std::env::var_os("BALANCEBEAM_BIN") {
+            return PathBuf::from(path);
+        }
+        // Test executables live in target/<profile>/deps
+        let mut path = std::env::current_exe().expect("Could not get current test executable path");
+        path.pop();
+        path.pop();
+        path.push("balancebeam");
+        path
+    }
+
+    pub async fn new(
+        upstreams: &[&str],
+        active_health_check_interval: Option<usize>,
+        max_requests_per_minute: Option<usize>,
+    ) -> BalanceBeam {
+        let mut args = Vec::new();
+        for upstream in upstreams {
+            args.push("--upstream".to_string());
+            args.push(upstream.to_string());
+        }
+        if let Some(active_health_check_interval) = active_health_check_interval {
+            args.push("--active-health-check-interval".to_string());
+            args.push(active_health_check_interval.to_string());
+        }
+        if let Some(max_requests_per_minute) = max_requests_per_minute {
+            args.push("--max-requests-per-minute".to_string());
+            args.push(max_requests_per_minute.to_string());
+        }
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+        BalanceBeam::new_with_args(&args).await
+    }
+
+    /// Starts balancebeam on a random port with the given command-line arguments (in addition to
+    /// --bind). This is useful for tests that exercise options `new` doesn't know about.
+    pub async fn new_with_args(args: &[&str]) -> BalanceBeam {
+        BalanceBeam::new_at_address(random_address(), args).await
+    }
+
+    /// Starts balancebeam bound to the given address (which may be a unix:/path socket) with the
+    /// given command-line arguments.
+    pub async fn new_at_address(address: String, args: &[&str]) -> BalanceBeam {
+        let mut cmd = Command::new(BalanceBeam::target_bin_path());
+        cmd.arg("--bind").arg(&address);
+        cmd.args(args);
+        cmd.kill_on_drop(true);
+        cmd.stdout(std::process::Stdio::piped());
+        cmd.stderr(std::process::Stdio::piped());
+        let mut child = cmd.spawn().unwrap_or_else(|_| {
+            panic!(
+                "Could not execute balancebeam binary {}",
+                BalanceBeam::target_bin_path().to_str().unwrap()
+            )
+        });
+
+        // Print output from the child. We want to intercept and log this output (instead of letting
+        // the child inherit stderr and print directly to the terminal) so that the output can be
+        // suppressed if the test passes and displayed if it fails.
+        let stdout = child
+            .stdout
+            .take()
+            .expect("Child process somehow missing stdout pipe!");
+        tokio::spawn(async move {
+            let mut stdout_reader = BufReader::new(stdout).lines();
+            while let Some(line) = stdout_reader
+                .next_line()
+                .await
+                .expect("I/O error reading from child stdout")
+            {
+                println!("Balancebeam output: {}", line);
+            }
+        });
+        let stderr = child
+            .stderr
+            .take()
+            .expect("Child process somehow missing stderr pipe!");
+        tokio::spawn(async move {
+            let mut stderr_reader = BufReader::new(stderr).lines();
+            while let Some(line) = stderr_reader
+                .next_line()
+                .await
+                .expect("I/O error reading from child stderr")
+            {
+                println!("Balancebeam output: {}", line);
+            }
+        });
+
+        // Hack: wait for executable to start running
+        sleep(Duration::from_secs(1)).await;
+        BalanceBeam { child, address }
+    }
+
+    /// Runs balancebeam with the given command-line arguments, expecting it to refuse them, and
+    /// returns its exit status. Panics if it is still running after a few seconds.
+    pub async fn run_expecting_exit(args: &[&str]) -> std::process::ExitStatus {
+        let mut cmd = Command::new(BalanceBeam::target_bin_path());
+        cmd.arg("--bind").arg(random_address());
+        cmd.args(args);
+        cmd.kill_on_drop(true);
+        let output = timeout(Duration::from_secs(5), cmd.output())
+            .await
+            .unwrap_or_else(|_| panic!("balancebeam started with arguments {:?}", args))
+            .expect("Could not run balancebeam");
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            println!("Balancebeam output: {}", line);
+        }
+        output.status
+    }
+
+    /// Sends a signal (e.g. SIGHUP, to make balancebeam reload its config) to the balancebeam
+    /// process.
+    pub fn send_signal(&self, signal: nix::sys::signal::Signal) {
+        let pid = nix::unistd::Pid::from_raw(self.child.id().unwrap() as i32);
+        nix::sys::signal::kill(pid, signal).expect("Could not signal balancebeam");
+    }
+
+    /// Formats a request for balancebeam, asking for the connection to be closed after it unless
+    /// `keep_alive` is set.
+    fn format_request(&self, method: &str, path: &str, body: &str, keep_alive: bool) -> String {
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nX-Sent-By: balancebeam-tests\r\n",
+            method, path, self.address
+        );
+        if !keep_alive {
+            request += "Connection: close\r\n";
+        }
+        if !body.is_empty() {
+            request += &format!("Content-Length: {}\r\n", body.len());
+        }
+        request += "\r\n";
+        request += body;
+        request
+    }
+
+    /// Sends a request over a connection of its own, returning the response body.
+    async fn send(&self, method: &str, path: &str, body: &str) -> std::io::Result<String> {
+        let mut stream = TcpStream::connect(&self.address).await?;
+        stream
+            .write_all(self.format_request(method, path, body, false).as_bytes())
+            .await?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        let body_start = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "balancebeam hung up before finishing the response",
+                )
+            })?;
+        Ok(String::from_utf8_lossy(&response[body_start + 4..]).to_string())
+    }
+
+    pub async fn get(&self, path: &str) -> std::io::Result<String> {
+        self.send("GET", path, "").await
+    }
+
+    pub async fn post(&self, path: &str, body: &str) -> std::io::Result<String> {
+        self.send("POST", path, body).await
+    }
+
+    /// Opens a connection that is kept alive between requests, so that each response has to be
+    /// framed properly rather than ending when balancebeam hangs up.
+    pub async fn connect(&self) -> std::io::Result<Connection<'_>> {
+        Ok(Connection {
+            balancebeam: self,
+            stream: TcpStream::connect(&self.address).await?,
+            buffer: Vec::new(),
+        })
+    }
+}
+
+/// A keep-alive connection to balancebeam, for sending several requests one after another.
+pub struct Connection<'a> {
+    balancebeam: &'a BalanceBeam,
+    stream: TcpStream,
+    /// Bytes read past the end of the last response
+    buffer: Vec<u8>,
+}
+
+impl Connection<'_> {
+    /// Sends a request and returns the response body, which must have a Content-Length.
+    async fn send(&mut self, method: &str, path: &str, body: &str) -> std::io::Result<String> {
+        let request = self.balancebeam.format_request(method, path, body, true);
+        self.stream.write_all(request.as_bytes()).await?;
+
+        let (head_len, content_length) = loop {
+            let mut headers = [httparse::EMPTY_HEADER; 64];
+            let mut response = httparse::Response::new(&mut headers);
+            if let httparse::Status::Complete(head_len) = response
+                .parse(&self.buffer)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?
+            {
+                let content_length = response
+                    .headers
+                    .iter()
+                    .find(|header| header.name.eq_ignore_ascii_case("content-length"))
+                    .and_then(|header| std::str::from_utf8(header.value).ok())
+                    .and_then(|value| value.trim().parse::<usize>().ok())
+                    .ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            "response has no Content-Length",
+                        )
+                    })?;
+                break (head_len, content_length);
+            }
+            self.read_more().await?;
+        };
+        while self.buffer.len() < head_len + content_length {
+            self.read_more().await?;
+        }
+        let rest = self.buffer.split_off(head_len + content_length);
+        let response = std::mem::replace(&mut self.buffer, rest);
+        Ok(String::from_utf8_lossy(&response[head_len..]).to_string())
+    }
+
+    async fn read_more(&mut self) -> std::io::Result<()> {
+        let mut buffer = [0_u8; 1024];
+        let bytes_read = self.stream.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "balancebeam hung up before finishing the response",
+            ));
+        }
+        self.buffer.extend_from_slice(&buffer[..bytes_read]);
+        Ok(())
+    }
+
+    pub async fn get(&mut self, path: &str) -> std::io::Result<String> {
+        self.send("GET", path, "").await
+    }
+
+    pub async fn post(&mut self, path: &str, body: &str) -> std::io::Result<String> {
+        self.send("POST", path, body).await
+    }
+}
@@ -0,0 +1,40 @@
+use std::fs::File;
+use std::io::{BufReader, Error};
+use std::sync::Arc;
+
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// ALPN protocol IDs we offer, in order of preference
+const ALPN_PROTOCOLS: [&[u8]; 2] = [b"h2", b"http/1.1"];
+
+/// Reads a PEM certificate chain and private key from disk and builds an acceptor that terminates
+/// TLS for client connections, negotiating HTTP/2 or HTTP/1.1 with ALPN.
+pub fn load_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, Error> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    let mut key = None;
+    for item in rustls_pemfile::read_all(&mut BufReader::new(File::open(key_path)?))? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::ECKey(der) => {
+                key = Some(PrivateKey(der));
+                break;
+            }
+            _ => {}
+        }
+    }
+    let key = key.ok_or_else(|| Error::other(format!("no private key found in {}", key_path)))?;
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(Error::other)?;
+    config.alpn_protocols = ALPN_PROTOCOLS.iter().map(|proto| proto.to_vec()).collect();
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
@@ -0,0 +1,342 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep, timeout};
+
+use crate::{choose_pool, choose_upstream, ProxyState};
+
+/// Largest possible UDP payload. Datagrams are relayed whole, so the buffers need to fit anything.
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// How long the active health check waits for an upstream to refuse a probe datagram
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Ties one client address to the upstream its datagrams are being forwarded to. Each session has
+/// its own socket connected to the upstream, so replies can be matched back up with the client.
+struct Session {
+    upstream_ip: String,
+    upstream_socket: Arc<UdpSocket>,
+    last_active: Instant,
+    /// Task relaying the upstream's replies back to the client
+    reply_task: tokio::task::JoinHandle<()>,
+    /// The session's place under the max_sessions cap, given back when it ends
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.reply_task.abort();
+    }
+}
+
+type Sessions = Arc<Mutex<HashMap<SocketAddr, Session>>>;
+
+/// Creates a UDP socket connected to the given upstream. Connecting the socket means the kernel
+/// will only hand us datagrams from that upstream, and will report ICMP port unreachable messages
+/// back to us as ConnectionRefused errors.
+async fn connect_upstream_socket(upstream_ip: &str) -> Result<UdpSocket, std::io::Error> {
+    let upstream_addr = tokio::net::lookup_host(upstream_ip)
+        .await?
+        .next()
+        .ok_or_else(|| std::io::Error::other("upstream address did not resolve"))?;
+    let local_addr = if upstream_addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local_addr).await?;
+    socket.connect(upstream_addr).await?;
+    Ok(socket)
+}
+
+/// Active health check used in udp mode. There is no protocol-independent way to ask a UDP
+/// service whether it is healthy, so this sends an empty datagram and only treats the upstream as
+/// dead if nothing is listening (i.e. the probe is refused).
+pub async fn probe_upstream(upstream_ip: &str) -> bool {
+    let socket = match connect_upstream_socket(upstream_ip).await {
+        Ok(socket) => socket,
+        Err(err) => {
+            log::error!("Failed to reach upstream {}: {}", upstream_ip, err);
+            return false;
+        }
+    };
+    if let Err(err) = socket.send(&[]).await {
+        log::error!("Failed to send probe to upstream {}: {}", upstream_ip, err);
+        return false;
+    }
+    let mut buffer = [0_u8; 1];
+    match timeout(PROBE_TIMEOUT, socket.recv(&mut buffer)).await {
+        Ok(Err(err)) if err.kind() == ErrorKind::ConnectionRefused => {
+            log::error!("Upstream {} refused health check probe", upstream_ip);
+            false
+        }
+        // Either the upstream answered or it stayed quiet, which is what most services do with a
+        // datagram they don't understand
+        _ => true,
+    }
+}
+
+/// Receives datagrams on the bind address and forwards each one to the upstream assigned to its
+/// sender, creating a new session (and choosing an upstream) for clients we haven't heard from
+/// recently. Sessions are forgotten after session_timeout without traffic in either direction.
+/// Each session takes up a socket, so at most max_sessions are kept (counting ones still being
+/// started); datagrams from new clients are dropped while that many are open.
+pub async fn serve(bind: &str, session_timeout: Duration, max_sessions: usize, state: ProxyState) {
+    let listener = match UdpSocket::bind(bind).await {
+        Ok(socket) => Arc::new(socket),
+        Err(err) => {
+            log::error!("Could not bind to {}: {}", bind, err);
+            std::process::exit(1);
+        }
+    };
+    log::info!("Listening for datagrams on {}", bind);
+
+    let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
+    tokio::spawn(expire_sessions(sessions.clone(), session_timeout));
+    let session_slots = Arc::new(Semaphore::new(max_sessions));
+
+    let mut buffer = vec![0_u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let (len, client_addr) = match listener.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(err) => {
+                log::warn!("Error receiving datagram: {}", err);
+                continue;
+            }
+        };
+        let datagram = &buffer[..len];
+        if let Some((upstream_ip, upstream_socket)) =
+            current_session(client_addr, &sessions, &state).await
+        {
+            forward_datagram(
+                datagram,
+                client_addr,
+                &upstream_ip,
+                &upstream_socket,
+                &sessions,
+                &state,
+            )
+            .await;
+            continue;
+        }
+        let permit = match session_slots.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                log::debug!(
+                    "Dropping datagram from {}: {} sessions already open",
+                    client_addr,
+                    max_sessions
+                );
+                continue;
+            }
+        };
+        // Starting a session can mean waiting on a DNS lookup, so do it off the receive loop
+        // rather than holding up datagrams from everyone else
+        let datagram = datagram.to_vec();
+        let listener = listener.clone();
+        let sessions = sessions.clone();
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Some((upstream_ip, upstream_socket)) =
+                start_session(client_addr, permit, &listener, &sessions, &state).await
+            {
+                forward_datagram(
+                    &datagram,
+                    client_addr,
+                    &upstream_ip,
+                    &upstream_socket,
+                    &sessions,
+                    &state,
+                )
+                .await;
+            }
+        });
+    }
+}
+
+/// Returns the upstream assigned to a client, and the socket connected to it. Returns None if the
+/// client has no session yet, or if its upstream has since been marked dead (in which case the
+/// session is ended, so that a new upstream is chosen).
+async fn current_session(
+    client_addr: SocketAddr,
+    sessions: &Sessions,
+    state: &ProxyState,
+) -> Option<(String, Arc<UdpSocket>)> {
+    let mut sessions_guard = sessions.lock().await;
+    let session = sessions_guard.get_mut(&client_addr)?;
+    if !state
+        .alive_upstreams
+        .read()
+        .await
+        .contains(&session.upstream_ip)
+    {
+        log::info!(
+            "Upstream {} for {} is no longer alive; choosing another",
+            session.upstream_ip,
+            client_addr
+        );
+        sessions_guard.remove(&client_addr);
+        return None;
+    }
+    session.last_active = Instant::now();
+    Some((session.upstream_ip.clone(), session.upstream_socket.clone()))
+}
+
+/// Sends a client's datagram to the upstream assigned to that client. If the send fails, the
+/// upstream is marked dead and the session is ended.
+async fn forward_datagram(
+    datagram: &[u8],
+    client_addr: SocketAddr,
+    upstream_ip: &str,
+    upstream_socket: &Arc<UdpSocket>,
+    sessions: &Sessions,
+    state: &ProxyState,
+) {
+    log::debug!(
+        "{} -> {}: {} byte datagram",
+        client_addr,
+        upstream_ip,
+        datagram.len()
+    );
+    if let Err(err) = upstream_socket.send(datagram).await {
+        log::error!(
+            "Failed to send datagram to upstream {}: {}",
+            upstream_ip,
+            err
+        );
+        state.alive_upstreams.write().await.remove(upstream_ip);
+        end_session(client_addr, upstream_socket, sessions).await;
+    }
+}
+
+/// Chooses an upstream for a client we don't have a session for, and starts relaying that
+/// upstream's replies back to the client. The upstream is resolved and connected to before the
+/// sessions map is locked, so a slow lookup only holds up this client. Returns the upstream and
+/// the socket connected to it, or None if no upstream is available.
+async fn start_session(
+    client_addr: SocketAddr,
+    permit: OwnedSemaphorePermit,
+    listener: &Arc<UdpSocket>,
+    sessions: &Sessions,
+    state: &ProxyState,
+) -> Option<(String, Arc<UdpSocket>)> {
+    let pool = choose_pool(state, None::<&http::Request<()>>);
+    let upstream_ip = match choose_upstream(state, pool).await {
+        Some(upstream_ip) => upstream_ip,
+        None => {
+            log::error!(
+                "Dropping datagram from {}: empty alive_upstreams",
+                client_addr
+            );
+            return None;
+        }
+    };
+    let upstream_socket = match connect_upstream_socket(&upstream_ip).await {
+        Ok(socket) => Arc::new(socket),
+        Err(err) => {
+            log::error!("Failed to reach upstream {}: {}", upstream_ip, err);
+            state.alive_upstreams.write().await.remove(&upstream_ip);
+            return None;
+        }
+    };
+
+    let mut sessions_guard = sessions.lock().await;
+    match sessions_guard.entry(client_addr) {
+        // Another datagram from the client got a session going while we were connecting
+        Entry::Occupied(entry) => {
+            let session = entry.get();
+            Some((session.upstream_ip.clone(), session.upstream_socket.clone()))
+        }
+        Entry::Vacant(entry) => {
+            log::info!("{} -> {}: new UDP session", client_addr, upstream_ip);
+            let reply_task = tokio::spawn(relay_replies(
+                client_addr,
+                upstream_ip.clone(),
+                upstream_socket.clone(),
+                listener.clone(),
+                sessions.clone(),
+                state.clone(),
+            ));
+            entry.insert(Session {
+                upstream_ip: upstream_ip.clone(),
+                upstream_socket: upstream_socket.clone(),
+                last_active: Instant::now(),
+                reply_task,
+                _permit: permit,
+            });
+            Some((upstream_ip, upstream_socket))
+        }
+    }
+}
+
+/// Ends a client's session, unless it has already been replaced by a newer one.
+async fn end_session(
+    client_addr: SocketAddr,
+    upstream_socket: &Arc<UdpSocket>,
+    sessions: &Sessions,
+) {
+    let mut sessions = sessions.lock().await;
+    if sessions
+        .get(&client_addr)
+        .is_some_and(|session| Arc::ptr_eq(&session.upstream_socket, upstream_socket))
+    {
+        sessions.remove(&client_addr);
+    }
+}
+
+/// Relays datagrams from a session's upstream back to the client until the session is dropped.
+/// If the upstream turns out not to be listening, it is marked dead and the session is ended so
+/// that the client's next datagram is sent somewhere else.
+async fn relay_replies(
+    client_addr: SocketAddr,
+    upstream_ip: String,
+    upstream_socket: Arc<UdpSocket>,
+    listener: Arc<UdpSocket>,
+    sessions: Sessions,
+    state: ProxyState,
+) {
+    let mut buffer = vec![0_u8; MAX_DATAGRAM_SIZE];
+    loop {
+        match upstream_socket.recv(&mut buffer).await {
+            Ok(len) => {
+                if let Some(session) = sessions.lock().await.get_mut(&client_addr) {
+                    session.last_active = Instant::now();
+                }
+                log::debug!("{} <- {}: {} byte datagram", client_addr, upstream_ip, len);
+                if let Err(err) = listener.send_to(&buffer[..len], client_addr).await {
+                    log::warn!("Failed to send datagram to client {}: {}", client_addr, err);
+                }
+            }
+            Err(err) => {
+                log::error!("Error receiving from upstream {}: {}", upstream_ip, err);
+                state.alive_upstreams.write().await.remove(&upstream_ip);
+                end_session(client_addr, &upstream_socket, &sessions).await;
+                return;
+            }
+        }
+    }
+}
+
+/// Periodically forgets sessions that have been idle for longer than session_timeout.
+async fn expire_sessions(sessions: Sessions, session_timeout: Duration) {
+    loop {
+        sleep(session_timeout.min(Duration::from_secs(1))).await;
+        sessions.lock().await.retain(|client_addr, session| {
+            let expired = session.last_active.elapsed() >= session_timeout;
+            if expired {
+                log::debug!(
+                    "Expiring idle UDP session {} -> {}",
+                    client_addr,
+                    session.upstream_ip
+                );
+            }
+            !expired
+        });
+    }
+}
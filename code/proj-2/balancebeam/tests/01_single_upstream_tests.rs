@@ -1,11 +1,11 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, Server};
+use common::{init_logging, BalanceBeam, Server, Upstream};
 use std::sync::Arc;
 
-async fn setup() -> (BalanceBeam, EchoServer) {
+async fn setup() -> (BalanceBeam, Upstream) {
     init_logging();
-    let upstream = EchoServer::new().await;
+    let upstream = Upstream::new().await;
     let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
     (balancebeam, upstream)
 }
@@ -69,7 +69,7 @@ async fn test_multiple_requests_per_connection() {
                 );
                 let path = format!("/conn-{}/req-{}", task_num, req_num);
                 let response_text = client
-                    .get(&format!("http://{}{}", balancebeam_shared.address, path))
+                    .get(format!("http://{}{}", balancebeam_shared.address, path))
                     .header("x-sent-by", "balancebeam-tests")
                     .send()
                     .await
@@ -1,6 +1,6 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+use common::{init_logging, BalanceBeam, Behavior, Server, Upstream};
 
 use std::time::Duration;
 use tokio::time::sleep;
@@ -13,7 +13,7 @@ async fn setup_with_params(
     init_logging();
     let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
     for _ in 0..n_upstreams {
-        upstreams.push(Box::new(EchoServer::new().await));
+        upstreams.push(Box::new(Upstream::new().await));
     }
     let upstream_addresses: Vec<String> = upstreams
         .iter()
@@ -159,7 +159,13 @@ async fn test_active_health_checks_check_http_status() {
     // Do a switcharoo with an upstream
     log::info!("Replacing one of the upstreams with a server that returns Error 500s...");
     upstreams.pop().unwrap().stop().await;
-    upstreams.push(Box::new(ErrorServer::new_at_address(failed_ip).await));
+    upstreams.push(Box::new(
+        Upstream::builder()
+            .address(failed_ip)
+            .behavior(Behavior::fixed(500, ""))
+            .start()
+            .await,
+    ));
 
     log::info!("Waiting for health checks to realize server is dead...");
     sleep(Duration::from_secs(3)).await;
@@ -206,7 +212,7 @@ async fn test_active_health_checks_restore_failed_upstream() {
     try_failover(&balancebeam, &mut upstreams).await;
 
     log::info!("Re-starting the \"failed\" upstream server...");
-    upstreams.push(Box::new(EchoServer::new_at_address(failed_ip).await));
+    upstreams.push(Box::new(Upstream::new_at_address(failed_ip).await));
 
     log::info!("Waiting a few seconds for the active health check to run...");
     sleep(Duration::from_secs(3)).await;
@@ -271,7 +277,7 @@ async fn test_rate_limiting() {
     for i in 0..num_extra_requests {
         let client = reqwest::Client::new();
         let response = client
-            .get(&format!("http://{}/overboard-{}", balancebeam.address, i))
+            .get(format!("http://{}/overboard-{}", balancebeam.address, i))
             .header("x-sent-by", "balancebeam-tests")
             .send()
             .await
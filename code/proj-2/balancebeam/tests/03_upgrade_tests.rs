@@ -0,0 +1,116 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, Server, WebSocketServer};
+use futures_util::{SinkExt, StreamExt};
+use tokio_tungstenite::tungstenite::Message;
+
+async fn setup() -> (BalanceBeam, WebSocketServer) {
+    init_logging();
+    let upstream = WebSocketServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    (balancebeam, upstream)
+}
+
+/// Open a WebSocket through balancebeam and make sure messages sent in both directions make it
+/// through once the connection has been upgraded.
+#[tokio::test]
+async fn test_websocket_echo() {
+    let (balancebeam, upstream) = setup().await;
+
+    log::info!("Opening a WebSocket through balancebeam");
+    let (mut websocket, response) =
+        tokio_tungstenite::connect_async(format!("ws://{}/echo", balancebeam.address))
+            .await
+            .expect("Error completing WebSocket handshake through balancebeam");
+    assert_eq!(response.status().as_u16(), 101);
+
+    for i in 0..5 {
+        let text = format!("Hello #{}", i);
+        log::info!("Sending text message {:?}", text);
+        websocket
+            .send(Message::Text(text.clone()))
+            .await
+            .expect("Error sending WebSocket message");
+        let reply = websocket
+            .next()
+            .await
+            .expect("WebSocket closed before echoing the message")
+            .expect("Error reading WebSocket message");
+        assert_eq!(reply, Message::Text(text));
+    }
+
+    log::info!("Sending a binary message");
+    let payload: Vec<u8> = (0..=255).collect();
+    websocket
+        .send(Message::Binary(payload.clone()))
+        .await
+        .expect("Error sending WebSocket message");
+    let reply = websocket
+        .next()
+        .await
+        .expect("WebSocket closed before echoing the message")
+        .expect("Error reading WebSocket message");
+    assert_eq!(reply, Message::Binary(payload));
+
+    websocket
+        .close(None)
+        .await
+        .expect("Error closing WebSocket");
+
+    log::info!("Checking that the upstream server saw exactly one handshake");
+    let num_handshakes = Box::new(upstream).stop().await;
+    assert_eq!(num_handshakes, 1);
+
+    log::info!("All done :)");
+}
+
+/// Make sure that several upgraded connections can be open at once without interfering with each
+/// other.
+#[tokio::test]
+async fn test_concurrent_websockets() {
+    let num_connections = 3;
+    let (balancebeam, upstream) = setup().await;
+
+    let mut websockets = Vec::new();
+    for _ in 0..num_connections {
+        let (websocket, _) =
+            tokio_tungstenite::connect_async(format!("ws://{}/echo", balancebeam.address))
+                .await
+                .expect("Error completing WebSocket handshake through balancebeam");
+        websockets.push(websocket);
+    }
+
+    // Interleave messages across the open connections
+    for round in 0..3 {
+        for (conn_num, websocket) in websockets.iter_mut().enumerate() {
+            let text = format!("conn-{}/msg-{}", conn_num, round);
+            websocket
+                .send(Message::Text(text.clone()))
+                .await
+                .expect("Error sending WebSocket message");
+        }
+        for (conn_num, websocket) in websockets.iter_mut().enumerate() {
+            let reply = websocket
+                .next()
+                .await
+                .expect("WebSocket closed before echoing the message")
+                .expect("Error reading WebSocket message");
+            assert_eq!(
+                reply,
+                Message::Text(format!("conn-{}/msg-{}", conn_num, round)),
+                "Received a message intended for a different connection"
+            );
+        }
+    }
+
+    for mut websocket in websockets {
+        websocket
+            .close(None)
+            .await
+            .expect("Error closing WebSocket");
+    }
+    let num_handshakes = Box::new(upstream).stop().await;
+    assert_eq!(num_handshakes, num_connections);
+
+    log::info!("All done :)");
+}
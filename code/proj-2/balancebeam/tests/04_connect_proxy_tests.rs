@@ -0,0 +1,126 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, Server, Upstream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn setup(allowed_destinations: &[&str]) -> BalanceBeam {
+    init_logging();
+    let mut args = vec!["--mode", "connect"];
+    for destination in allowed_destinations {
+        args.push("--connect-allow");
+        args.push(destination);
+    }
+    BalanceBeam::new_with_args(&args).await
+}
+
+/// Reads from the stream until a full set of response headers has arrived, returning them as a
+/// string.
+async fn read_response_head(stream: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    let mut byte = [0_u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        let bytes_read = stream
+            .read(&mut byte)
+            .await
+            .expect("Error reading response from balancebeam");
+        assert!(bytes_read > 0, "balancebeam hung up before sending headers");
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).expect("Response headers were not valid UTF-8")
+}
+
+/// Open a tunnel to an allowlisted destination and make sure bytes flow through it in both
+/// directions.
+#[tokio::test]
+async fn test_connect_tunnel() {
+    let destination = Upstream::new().await;
+    let balancebeam = setup(&[&destination.address]).await;
+
+    log::info!("Sending a CONNECT request");
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(
+            format!(
+                "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n",
+                destination.address
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let head = read_response_head(&mut stream).await;
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "Expected the tunnel to be established, got {:?}",
+        head
+    );
+
+    log::info!("Sending an HTTP request through the tunnel");
+    stream
+        .write_all(b"GET /tunneled HTTP/1.1\r\nHost: echo\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .await
+        .expect("Error reading tunneled response");
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.contains("GET /tunneled HTTP/1.1"));
+    assert!(
+        !response.contains("x-forwarded-for"),
+        "Tunneled traffic should be passed through untouched"
+    );
+
+    let num_requests_received = Box::new(destination).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("All done :)");
+}
+
+/// Make sure destinations outside the allowlist and non-CONNECT requests are rejected without
+/// closing the connection.
+#[tokio::test]
+async fn test_connect_rejections() {
+    let destination = Upstream::new().await;
+    let balancebeam = setup(&["example.com:443"]).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+
+    log::info!("Sending a CONNECT request for a destination that isn't allowlisted");
+    stream
+        .write_all(
+            format!(
+                "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n",
+                destination.address
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let head = read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 403"), "Got {:?}", head);
+    let mut body = vec![0_u8; "HTTP 403 Forbidden".len()];
+    stream.read_exact(&mut body).await.unwrap();
+
+    log::info!("Sending a plain GET request");
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let head = read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 405"), "Got {:?}", head);
+
+    let num_requests_received = Box::new(destination).stop().await;
+    assert_eq!(
+        num_requests_received, 0,
+        "A request reached a destination that isn't allowlisted"
+    );
+
+    log::info!("All done :)");
+}
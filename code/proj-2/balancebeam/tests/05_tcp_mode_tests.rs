@@ -0,0 +1,188 @@
+mod common;
+
+use common::{get_metrics, init_logging, random_address, BalanceBeam, Server, TcpEchoServer};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+
+async fn setup(
+    n_upstreams: usize,
+    active_health_check_interval: Option<usize>,
+) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    init_logging();
+    let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
+    for _ in 0..n_upstreams {
+        upstreams.push(Box::new(TcpEchoServer::new().await));
+    }
+    let mut args = vec!["--mode".to_string(), "tcp".to_string()];
+    for upstream in &upstreams {
+        args.push("--upstream".to_string());
+        args.push(upstream.address());
+    }
+    if let Some(active_health_check_interval) = active_health_check_interval {
+        args.push("--active-health-check-interval".to_string());
+        args.push(active_health_check_interval.to_string());
+    }
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    let balancebeam = BalanceBeam::new_with_args(&args).await;
+    (balancebeam, upstreams)
+}
+
+/// Opens a connection through balancebeam, sends the payload, and makes sure the exact same bytes
+/// come back.
+async fn echo_through(balancebeam: &BalanceBeam, payload: &[u8]) {
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(payload)
+        .await
+        .expect("Error sending data to balancebeam");
+    stream
+        .shutdown()
+        .await
+        .expect("Error closing write half of connection");
+    let mut echoed = Vec::new();
+    stream
+        .read_to_end(&mut echoed)
+        .await
+        .expect("Error reading data from balancebeam");
+    assert_eq!(
+        echoed, payload,
+        "Bytes sent through balancebeam came back different"
+    );
+}
+
+/// Open a bunch of connections and make sure they are spread evenly across the upstreams, and
+/// that non-HTTP bytes pass through untouched.
+#[tokio::test]
+async fn test_tcp_load_distribution() {
+    let n_upstreams = 3;
+    let n_connections = 60;
+    let (balancebeam, mut upstreams) = setup(n_upstreams, None).await;
+
+    for i in 0..n_connections {
+        let payload = format!("*1\r\n$4\r\nPING\r\n#{}\x00\x01\x02", i);
+        echo_through(&balancebeam, payload.as_bytes()).await;
+    }
+
+    let mut connection_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        connection_counters.insert(0, upstream.stop().await);
+    }
+    log::info!(
+        "Number of connections received by each upstream: {:?}",
+        connection_counters
+    );
+    assert_eq!(connection_counters.iter().sum::<usize>(), n_connections);
+    let avg_conn_count = n_connections as f64 / n_upstreams as f64;
+    for upstream_conn_count in connection_counters {
+        assert!(
+            (upstream_conn_count as f64 - avg_conn_count).abs() <= 0.5 * avg_conn_count,
+            "Upstream connection count {} differs too much from the average",
+            upstream_conn_count
+        );
+    }
+
+    log::info!("All done :)");
+}
+
+/// Kill an upstream and make sure connections fail over to the other one, then bring it back and
+/// make sure the TCP-connect health check notices.
+#[tokio::test]
+async fn test_tcp_health_checks() {
+    let (balancebeam, mut upstreams) = setup(2, Some(1)).await;
+    let failed_ip = upstreams[upstreams.len() - 1].address();
+
+    log::info!("Killing one of the upstream servers");
+    upstreams.pop().unwrap().stop().await;
+    for i in 0..6 {
+        log::info!("Opening connection #{} after killing an upstream server", i);
+        echo_through(&balancebeam, format!("failover-{}", i).as_bytes()).await;
+    }
+
+    log::info!("Re-starting the \"failed\" upstream server...");
+    upstreams.push(Box::new(TcpEchoServer::new_at_address(failed_ip).await));
+    log::info!("Waiting a few seconds for the active health check to run...");
+    sleep(Duration::from_secs(3)).await;
+
+    for i in 0..20 {
+        echo_through(&balancebeam, format!("after-restore-{}", i).as_bytes()).await;
+    }
+
+    // The restored upstream also counts the health checker's connections, but at a one-second
+    // interval that can account for at most a handful of them
+    let last_upstream_conn_count = upstreams.pop().unwrap().stop().await;
+    assert!(
+        last_upstream_conn_count > 5,
+        "We killed an upstream, then brought it back, but it never got any more connections!"
+    );
+
+    while let Some(upstream) = upstreams.pop() {
+        upstream.stop().await;
+    }
+
+    log::info!("All done :)");
+}
+
+/// Returns the value of a series on the admin interface's metrics page, if it is there.
+async fn metric(admin_address: &str, series: &str) -> Option<f64> {
+    let metrics = get_metrics(admin_address).await;
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+        .map(|value| value.parse().unwrap())
+}
+
+/// Connections, failed connection attempts and the bytes relayed each way should be counted for
+/// each upstream.
+#[tokio::test]
+async fn test_tcp_metrics() {
+    init_logging();
+    let upstream = TcpEchoServer::new().await;
+    let upstream_ip = upstream.address.clone();
+    let admin_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--mode",
+        "tcp",
+        "--upstream",
+        &upstream_ip,
+        "--admin-bind",
+        &admin_address,
+    ])
+    .await;
+
+    for payload in ["hello", "world!"] {
+        echo_through(&balancebeam, payload.as_bytes()).await;
+    }
+    // The tunnel is counted once balancebeam has seen both sides close
+    sleep(Duration::from_millis(200)).await;
+    let connections = format!(
+        "balancebeam_tcp_connections_total{{upstream=\"{}\"}}",
+        upstream_ip
+    );
+    assert_eq!(metric(&admin_address, &connections).await, Some(2.0));
+    for direction in ["to_upstream", "to_client"] {
+        let bytes = format!(
+            "balancebeam_tcp_bytes_total{{upstream=\"{}\",direction=\"{}\"}}",
+            upstream_ip, direction
+        );
+        assert_eq!(metric(&admin_address, &bytes).await, Some(11.0));
+    }
+
+    log::info!("Killing the upstream");
+    Box::new(upstream).stop().await;
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let mut buffer = Vec::new();
+    assert_eq!(stream.read_to_end(&mut buffer).await.unwrap_or(0), 0);
+    let failures = format!(
+        "balancebeam_tcp_connect_failures_total{{upstream=\"{}\"}}",
+        upstream_ip
+    );
+    assert_eq!(metric(&admin_address, &failures).await, Some(1.0));
+
+    log::info!("All done :)");
+}
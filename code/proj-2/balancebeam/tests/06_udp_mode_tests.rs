@@ -0,0 +1,186 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, Server, UdpEchoServer};
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+async fn setup(n_upstreams: usize, extra_args: &[&str]) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    init_logging();
+    let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
+    for _ in 0..n_upstreams {
+        upstreams.push(Box::new(UdpEchoServer::new().await));
+    }
+    let mut args = vec!["--mode".to_string(), "udp".to_string()];
+    for upstream in &upstreams {
+        args.push("--upstream".to_string());
+        args.push(upstream.address());
+    }
+    args.extend(extra_args.iter().map(|arg| arg.to_string()));
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    let balancebeam = BalanceBeam::new_with_args(&args).await;
+    (balancebeam, upstreams)
+}
+
+async fn client_socket(balancebeam: &BalanceBeam) -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind client socket");
+    socket
+        .connect(&balancebeam.address)
+        .await
+        .expect("Could not connect client socket to balancebeam");
+    socket
+}
+
+/// Sends a datagram and waits briefly for the reply. Returns the address of the upstream that
+/// answered, or None if no reply arrived (UDP makes no promises).
+async fn exchange(socket: &UdpSocket, payload: &str) -> Option<String> {
+    socket
+        .send(payload.as_bytes())
+        .await
+        .expect("Error sending datagram to balancebeam");
+    let mut buffer = [0_u8; 1024];
+    let len = timeout(Duration::from_millis(500), socket.recv(&mut buffer))
+        .await
+        .ok()?
+        .ok()?;
+    let reply = String::from_utf8(buffer[..len].to_vec()).expect("Reply was not valid UTF-8");
+    let (upstream, echoed) = reply
+        .split_once('|')
+        .expect("Reply did not come from an echo server");
+    assert_eq!(echoed, payload, "Upstream echoed back the wrong datagram");
+    Some(upstream.to_string())
+}
+
+/// Several clients each send a series of datagrams. Every datagram from a given client should be
+/// answered by the same upstream, while different clients should be spread across upstreams.
+#[tokio::test]
+async fn test_udp_session_affinity() {
+    let n_clients = 8;
+    let datagrams_per_client = 5;
+    let (balancebeam, mut upstreams) = setup(3, &[]).await;
+
+    let mut upstreams_used = HashSet::new();
+    for client_num in 0..n_clients {
+        let socket = client_socket(&balancebeam).await;
+        let mut client_upstreams = HashSet::new();
+        for i in 0..datagrams_per_client {
+            let upstream = exchange(&socket, &format!("client-{}/query-{}", client_num, i))
+                .await
+                .expect("No reply received through balancebeam");
+            client_upstreams.insert(upstream);
+        }
+        log::info!("Client {} was served by {:?}", client_num, client_upstreams);
+        assert_eq!(
+            client_upstreams.len(),
+            1,
+            "Datagrams from a single client were sent to different upstreams"
+        );
+        upstreams_used.extend(client_upstreams);
+    }
+    assert!(
+        upstreams_used.len() > 1,
+        "Every client was sent to the same upstream"
+    );
+
+    let mut total_datagrams = 0;
+    while let Some(upstream) = upstreams.pop() {
+        total_datagrams += upstream.stop().await;
+    }
+    assert_eq!(total_datagrams, n_clients * datagrams_per_client);
+
+    log::info!("All done :)");
+}
+
+/// Kill the upstream a client is talking to and make sure the client gets moved to the other
+/// upstream after retrying, the way a DNS client would.
+#[tokio::test]
+async fn test_udp_failover() {
+    let (balancebeam, mut upstreams) = setup(2, &[]).await;
+    let socket = client_socket(&balancebeam).await;
+
+    let first_upstream = exchange(&socket, "before-failure")
+        .await
+        .expect("No reply received through balancebeam");
+    log::info!("Client is being served by {}; killing it", first_upstream);
+    let index = upstreams
+        .iter()
+        .position(|upstream| upstream.address() == first_upstream)
+        .unwrap();
+    upstreams.remove(index).stop().await;
+
+    let mut new_upstream = None;
+    for attempt in 0..5 {
+        log::info!("Sending datagram #{} after killing the upstream", attempt);
+        new_upstream = exchange(&socket, &format!("after-failure-{}", attempt)).await;
+        if new_upstream.is_some() {
+            break;
+        }
+    }
+    assert_eq!(
+        new_upstream,
+        Some(upstreams[0].address()),
+        "Client was never moved to the surviving upstream"
+    );
+
+    while let Some(upstream) = upstreams.pop() {
+        upstream.stop().await;
+    }
+
+    log::info!("All done :)");
+}
+
+/// Once --udp-max-sessions clients have sessions, datagrams from new clients should be dropped
+/// until an old session expires.
+#[tokio::test]
+async fn test_udp_max_sessions() {
+    let (balancebeam, mut upstreams) = setup(
+        1,
+        &["--udp-max-sessions", "2", "--udp-session-timeout", "1"],
+    )
+    .await;
+
+    let mut sockets = Vec::new();
+    for client_num in 0..2 {
+        let socket = client_socket(&balancebeam).await;
+        exchange(&socket, &format!("client-{}", client_num))
+            .await
+            .expect("No reply received through balancebeam");
+        sockets.push(socket);
+    }
+    let socket = client_socket(&balancebeam).await;
+    assert_eq!(exchange(&socket, "over-the-cap").await, None);
+
+    log::info!("Waiting for the idle sessions to expire");
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    exchange(&socket, "after-expiry")
+        .await
+        .expect("No reply received once the other sessions expired");
+
+    while let Some(upstream) = upstreams.pop() {
+        upstream.stop().await;
+    }
+
+    log::info!("All done :)");
+}
+
+/// A session timeout of zero would have balancebeam expiring sessions in a busy loop, so it
+/// should be refused.
+#[tokio::test]
+async fn test_udp_zero_session_timeout() {
+    init_logging();
+    let status = BalanceBeam::run_expecting_exit(&[
+        "--mode",
+        "udp",
+        "--upstream",
+        "127.0.0.1:53",
+        "--udp-session-timeout",
+        "0",
+    ])
+    .await;
+    assert!(!status.success());
+
+    log::info!("All done :)");
+}
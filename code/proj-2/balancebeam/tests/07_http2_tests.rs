@@ -0,0 +1,418 @@
+mod common;
+
+use bytes::Bytes;
+use common::{
+    init_logging, start_balancebeam, BalanceBeam, Behavior, ScriptedServer, Server, TempFile,
+    Upstream,
+};
+use rand::Rng;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls;
+
+/// A self-signed certificate for "localhost", written out to temporary files so balancebeam can
+/// load it.
+struct TestCertificate {
+    der: Vec<u8>,
+    cert_path: std::path::PathBuf,
+    key_path: std::path::PathBuf,
+}
+
+impl TestCertificate {
+    fn generate() -> TestCertificate {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+            .expect("Could not generate certificate");
+        let id: u32 = rand::thread_rng().gen();
+        let cert_path = std::env::temp_dir().join(format!("balancebeam-test-{}-cert.pem", id));
+        let key_path = std::env::temp_dir().join(format!("balancebeam-test-{}-key.pem", id));
+        std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        TestCertificate {
+            der: cert.serialize_der().unwrap(),
+            cert_path,
+            key_path,
+        }
+    }
+
+    /// Opens a TLS connection to the given address, offering only the given ALPN protocol.
+    async fn connect(
+        &self,
+        address: &str,
+        alpn_protocol: &[u8],
+    ) -> tokio_rustls::client::TlsStream<TcpStream> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&rustls::Certificate(self.der.clone())).unwrap();
+        let mut config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![alpn_protocol.to_vec()];
+        let stream = TcpStream::connect(address)
+            .await
+            .expect("Could not connect to balancebeam");
+        tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect(rustls::ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .expect("TLS handshake with balancebeam failed")
+    }
+}
+
+impl Drop for TestCertificate {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.cert_path);
+        let _ = std::fs::remove_file(&self.key_path);
+    }
+}
+
+/// Sends a single request on an HTTP/2 connection and returns the status and body text.
+async fn send_h2_request(
+    client: h2::client::SendRequest<Bytes>,
+    method: &str,
+    path: &str,
+    body: &str,
+) -> (u16, String) {
+    let request = http::Request::builder()
+        .method(method)
+        .uri(format!("http://localhost{}", path))
+        .header("x-sent-by", "balancebeam-tests")
+        .body(())
+        .unwrap();
+    let mut client = client.ready().await.expect("HTTP/2 connection failed");
+    let (response, mut send_stream) = client
+        .send_request(request, body.is_empty())
+        .expect("Error sending HTTP/2 request");
+    if !body.is_empty() {
+        send_stream
+            .send_data(Bytes::from(body.to_string()), true)
+            .expect("Error sending HTTP/2 request body");
+    }
+    let response = response.await.expect("Error reading HTTP/2 response");
+    let status = response.status().as_u16();
+    let mut response_body = response.into_body();
+    let mut text = Vec::new();
+    while let Some(chunk) = response_body.data().await {
+        let chunk = chunk.expect("Error reading HTTP/2 response body");
+        let _ = response_body.flow_control().release_capacity(chunk.len());
+        text.extend_from_slice(&chunk);
+    }
+    (status, String::from_utf8(text).unwrap())
+}
+
+/// Reads a complete HTTP/1.1 response (which must have a Content-Length) and returns it as text.
+async fn read_http1_response(stream: &mut (impl AsyncRead + Unpin)) -> String {
+    let mut response = Vec::new();
+    loop {
+        let mut buffer = [0_u8; 1024];
+        let bytes_read = stream
+            .read(&mut buffer)
+            .await
+            .expect("Error reading response from balancebeam");
+        assert!(bytes_read > 0, "balancebeam hung up mid-response");
+        response.extend_from_slice(&buffer[..bytes_read]);
+
+        let text = String::from_utf8_lossy(&response).to_string();
+        if let Some((head, body)) = text.split_once("\r\n\r\n") {
+            let content_length = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length: "))
+                .expect("Response has no Content-Length")
+                .parse::<usize>()
+                .unwrap();
+            if body.len() >= content_length {
+                return text;
+            }
+        }
+    }
+}
+
+/// Performs the HTTP/2 handshake over the given connection and keeps the connection running in
+/// the background.
+async fn h2_handshake<S>(stream: S) -> h2::client::SendRequest<Bytes>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (client, connection) = h2::client::handshake(stream)
+        .await
+        .expect("HTTP/2 handshake with balancebeam failed");
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            log::error!("HTTP/2 client connection failed: {}", e);
+        }
+    });
+    client
+}
+
+/// Open one cleartext HTTP/2 connection with prior knowledge and send several requests on it at
+/// the same time.
+#[tokio::test]
+async fn test_h2c_multiplexing() {
+    init_logging();
+    let upstream = Upstream::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let client = h2_handshake(stream).await;
+
+    let n_requests = 5;
+    let mut tasks = Vec::new();
+    for i in 0..n_requests {
+        let client = client.clone();
+        tasks.push(tokio::spawn(async move {
+            let path = format!("/stream-{}", i);
+            log::info!("Sending HTTP/2 request for {}", path);
+            let (status, text) = send_h2_request(client, "GET", &path, "").await;
+            assert_eq!(status, 200);
+            assert!(text.contains(&format!("GET {} HTTP/1.1", path)));
+            assert!(text.contains("x-sent-by: balancebeam-tests"));
+            assert!(text.contains("x-forwarded-for: 127.0.0.1"));
+            assert!(text.contains("host: localhost"));
+        }));
+    }
+    for task in tasks {
+        task.await.expect("Task panicked");
+    }
+
+    log::info!("Sending an HTTP/2 POST request");
+    let (status, text) = send_h2_request(client, "POST", "/upload", "Hello world!").await;
+    assert_eq!(status, 200);
+    assert!(text.contains("POST /upload HTTP/1.1"));
+    assert!(text.contains("content-length: 12"));
+    assert!(text.contains("\n\nHello world!"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, n_requests + 1);
+
+    log::info!("All done :)");
+}
+
+/// Make sure clients that negotiate HTTP/2 with ALPN over TLS are served with HTTP/2, while
+/// clients that negotiate HTTP/1.1 are still served with HTTP/1.1.
+#[tokio::test]
+async fn test_tls_alpn() {
+    init_logging();
+    let certificate = TestCertificate::generate();
+    let upstream = Upstream::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--tls-cert",
+        certificate.cert_path.to_str().unwrap(),
+        "--tls-key",
+        certificate.key_path.to_str().unwrap(),
+    ])
+    .await;
+
+    log::info!("Sending a request over TLS with HTTP/2");
+    let stream = certificate.connect(&balancebeam.address, b"h2").await;
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+    let client = h2_handshake(stream).await;
+    let (status, text) = send_h2_request(client, "GET", "/over-h2", "").await;
+    assert_eq!(status, 200);
+    assert!(text.contains("GET /over-h2 HTTP/1.1"));
+
+    log::info!("Sending a request over TLS with HTTP/1.1");
+    let mut stream = certificate.connect(&balancebeam.address, b"http/1.1").await;
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"http/1.1"[..]));
+    stream
+        .write_all(b"GET /over-http1 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let response = read_http1_response(&mut stream).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Got {:?}", response);
+    assert!(response.contains("GET /over-http1 HTTP/1.1"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+
+    log::info!("All done :)");
+}
+
+/// Cookies split across several fields (as HTTP/2 clients are allowed to send them) should reach
+/// the upstream as a single Cookie header.
+#[tokio::test]
+async fn test_h2_cookies() {
+    init_logging();
+    let upstream = Upstream::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let client = h2_handshake(stream).await;
+    let request = http::Request::builder()
+        .uri("http://localhost/cookies")
+        .header("cookie", "a=1")
+        .header("cookie", "b=2")
+        .body(())
+        .unwrap();
+    let mut client = client.ready().await.expect("HTTP/2 connection failed");
+    let (response, _) = client
+        .send_request(request, true)
+        .expect("Error sending HTTP/2 request");
+    let response = response.await.expect("Error reading HTTP/2 response");
+    assert_eq!(response.status().as_u16(), 200);
+    let mut response_body = response.into_body();
+    let mut text = Vec::new();
+    while let Some(chunk) = response_body.data().await {
+        text.extend_from_slice(&chunk.expect("Error reading HTTP/2 response body"));
+    }
+    let text = String::from_utf8(text).unwrap();
+    assert!(text.contains("cookie: a=1; b=2\n"), "Got {:?}", text);
+    assert_eq!(text.matches("cookie:").count(), 1);
+
+    log::info!("All done :)");
+}
+
+/// A client shouldn't be able to have more streams in flight on one connection than
+/// --max-concurrent-streams allows.
+#[tokio::test]
+async fn test_h2_max_concurrent_streams() {
+    init_logging();
+    let latency = Duration::from_millis(300);
+    let upstream = Upstream::with_behavior(Behavior::echo().with_latency(latency)).await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--max-concurrent-streams",
+        "1",
+    ])
+    .await;
+
+    let stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let client = h2_handshake(stream).await;
+    // Once one request has been answered, the client has seen balancebeam's settings and knows to
+    // hold back the streams it isn't allowed to open yet (rather than having them refused)
+    let (status, _) = send_h2_request(client.clone(), "GET", "/first", "").await;
+    assert_eq!(status, 200);
+
+    let started = Instant::now();
+    let mut tasks = Vec::new();
+    for i in 0..3 {
+        let client = client.clone();
+        tasks.push(tokio::spawn(async move {
+            let (status, _) = send_h2_request(client, "GET", &format!("/stream-{}", i), "").await;
+            assert_eq!(status, 200);
+        }));
+    }
+    for task in tasks {
+        task.await.expect("Task panicked");
+    }
+    // The streams had to take turns
+    assert!(started.elapsed() >= latency * 3);
+    assert_eq!(Box::new(upstream).stop().await, 4);
+
+    log::info!("All done :)");
+}
+
+/// Streams should be held to the same header size limit as HTTP/1 requests, and refused by a
+/// route's access checks without balancebeam waiting for (and buffering) their bodies.
+#[tokio::test]
+async fn test_h2_refused_early() {
+    init_logging();
+    let upstream = Upstream::new().await;
+    let config_file = TempFile::new(
+        "config.json",
+        r#"{"routes": [{"path_prefix": "/private", "allow": ["10.0.0.0/8"]}]}"#,
+    );
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--config",
+        config_file.path_str(),
+        "--max-headers-size",
+        "1000",
+    ])
+    .await;
+
+    let stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let client = h2_handshake(stream).await;
+    // Let the client see balancebeam's settings first
+    let (status, _) = send_h2_request(client.clone(), "GET", "/", "").await;
+    assert_eq!(status, 200);
+
+    let request = http::Request::builder()
+        .uri("http://localhost/")
+        .header("x-padding", "x".repeat(2000))
+        .body(())
+        .unwrap();
+    let mut sender = client
+        .clone()
+        .ready()
+        .await
+        .expect("HTTP/2 connection failed");
+    let refused = match sender.send_request(request, true) {
+        Ok((response, _)) => match response.await {
+            Ok(response) => response.status().as_u16() == 431,
+            Err(_) => true,
+        },
+        Err(_) => true,
+    };
+    assert!(refused, "Oversized headers were accepted");
+
+    log::info!("Sending a request to a denied route and holding back its body");
+    let request = http::Request::builder()
+        .method("POST")
+        .uri("http://localhost/private")
+        .body(())
+        .unwrap();
+    let mut sender = client.ready().await.expect("HTTP/2 connection failed");
+    let (response, _send_stream) = sender
+        .send_request(request, false)
+        .expect("Error sending HTTP/2 request");
+    let response = tokio::time::timeout(Duration::from_secs(5), response)
+        .await
+        .expect("balancebeam waited for the body of a denied request")
+        .expect("Error reading HTTP/2 response");
+    assert_eq!(response.status().as_u16(), 403);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Answers with interim responses that an HTTP/2 client can't be sent as they are.
+fn respond_with_interim(head: &str) -> Vec<u8> {
+    match head.lines().next().unwrap() {
+        "GET /switch HTTP/1.1" => {
+            b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n"
+                .to_vec()
+        }
+        _ => b"HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload\r\n\r\n\
+               HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nfinal"
+            .to_vec(),
+    }
+}
+
+/// Interim responses should be skipped on HTTP/2 streams, and an upstream switching protocols
+/// (which HTTP/2 has no way to pass on) should be reported as a bad gateway.
+#[tokio::test]
+async fn test_h2_interim_responses() {
+    init_logging();
+    let upstream = ScriptedServer::new(respond_with_interim).await;
+    let balancebeam = start_balancebeam(&["--upstream", &upstream.address]).await;
+
+    let stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let client = h2_handshake(stream).await;
+    let (status, text) = send_h2_request(client.clone(), "GET", "/hints", "").await;
+    assert_eq!(status, 200);
+    assert_eq!(text, "final");
+    let (status, _) = tokio::time::timeout(
+        Duration::from_secs(5),
+        send_h2_request(client, "GET", "/switch", ""),
+    )
+    .await
+    .expect("Timed out waiting for a response to the switched request");
+    assert_eq!(status, 502);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,170 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, Server, Upstream};
+
+async fn setup(n_upstreams: usize, sticky_args: &[&str]) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    init_logging();
+    let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
+    for _ in 0..n_upstreams {
+        upstreams.push(Box::new(Upstream::new().await));
+    }
+    let mut args: Vec<String> = sticky_args.iter().map(|arg| arg.to_string()).collect();
+    for upstream in &upstreams {
+        args.push("--upstream".to_string());
+        args.push(upstream.address());
+    }
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    let balancebeam = BalanceBeam::new_with_args(&args).await;
+    (balancebeam, upstreams)
+}
+
+/// Sends a GET request on a new connection with the given extra header, returning the response's
+/// Set-Cookie header (if any).
+async fn get_with_header(
+    balancebeam: &BalanceBeam,
+    header: Option<(&str, &str)>,
+) -> Option<String> {
+    let client = reqwest::Client::new();
+    let mut request = client.get(format!("http://{}/", balancebeam.address));
+    if let Some((name, value)) = header {
+        request = request.header(name, value);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    response
+        .headers()
+        .get("set-cookie")
+        .map(|value| value.to_str().unwrap().to_string())
+}
+
+/// Stops every upstream, returning how many requests each one received.
+async fn stop_all(mut upstreams: Vec<Box<dyn Server>>) -> Vec<usize> {
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    request_counters
+}
+
+/// A client that sends back the cookie it was given should keep landing on the same upstream, even
+/// across connections, and a forged cookie should be ignored.
+#[tokio::test]
+async fn test_sticky_cookie() {
+    let n_requests = 20;
+    let (balancebeam, upstreams) = setup(3, &["--sticky-cookie", "bb-session"]).await;
+
+    let set_cookie = get_with_header(&balancebeam, None)
+        .await
+        .expect("balancebeam did not hand out a session cookie");
+    log::info!("Got session cookie {:?}", set_cookie);
+    assert!(set_cookie.starts_with("bb-session="));
+    let cookie = set_cookie.split(';').next().unwrap().to_string();
+    for _ in 0..n_requests {
+        let set_cookie = get_with_header(&balancebeam, Some(("cookie", &cookie))).await;
+        assert_eq!(
+            set_cookie, None,
+            "balancebeam replaced a cookie that was still valid"
+        );
+    }
+
+    log::info!("Sending a request with a forged cookie");
+    let forged = format!("bb-session={}.{}", upstreams[0].address(), "00".repeat(32));
+    let set_cookie = get_with_header(&balancebeam, Some(("cookie", &forged))).await;
+    assert!(
+        set_cookie.is_some(),
+        "balancebeam accepted a cookie with a bad signature"
+    );
+
+    let request_counters = stop_all(upstreams).await;
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    assert!(
+        request_counters.contains(&(n_requests + 1))
+            || request_counters.contains(&(n_requests + 2)),
+        "Requests with the same session cookie were spread across upstreams"
+    );
+
+    log::info!("All done :)");
+}
+
+/// When the upstream a session is pinned to dies, the client should be moved to another upstream
+/// and given a new cookie, and then stay there.
+#[tokio::test]
+async fn test_sticky_cookie_failover() {
+    let (balancebeam, mut upstreams) = setup(2, &["--sticky-cookie", "bb-session"]).await;
+
+    let set_cookie = get_with_header(&balancebeam, None).await.unwrap();
+    let cookie = set_cookie.split(';').next().unwrap().to_string();
+    let index = upstreams
+        .iter()
+        .position(|upstream| cookie.contains(&upstream.address()))
+        .expect("Cookie does not name one of the upstreams");
+    log::info!("Killing the upstream the session is pinned to");
+    upstreams.remove(index).stop().await;
+
+    let set_cookie = get_with_header(&balancebeam, Some(("cookie", &cookie)))
+        .await
+        .expect("balancebeam did not re-pin the session to the surviving upstream");
+    let cookie = set_cookie.split(';').next().unwrap().to_string();
+    assert!(cookie.contains(&upstreams[0].address()));
+    for _ in 0..5 {
+        assert_eq!(
+            get_with_header(&balancebeam, Some(("cookie", &cookie))).await,
+            None
+        );
+    }
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters, vec![6]);
+
+    log::info!("All done :)");
+}
+
+/// Requests with the same header value go to the same upstream, without any cookies involved.
+#[tokio::test]
+async fn test_sticky_header() {
+    let n_requests = 10;
+    let (balancebeam, upstreams) = setup(3, &["--sticky-header", "x-user-id"]).await;
+
+    for _ in 0..n_requests {
+        let set_cookie = get_with_header(&balancebeam, Some(("x-user-id", "alice"))).await;
+        assert_eq!(set_cookie, None);
+    }
+
+    let request_counters = stop_all(upstreams).await;
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    assert!(
+        request_counters.contains(&n_requests),
+        "Requests with the same header value were spread across upstreams"
+    );
+
+    log::info!("All done :)");
+}
+
+/// A cookie name that can't go in a Set-Cookie header should be refused at startup, just like an
+/// invalid --sticky-header.
+#[tokio::test]
+async fn test_invalid_sticky_cookie() {
+    init_logging();
+    for name in ["bb session", "bb;session", "bb-sëssion", "bb\tsession", ""] {
+        log::info!("Trying cookie name {:?}", name);
+        let status = BalanceBeam::run_expecting_exit(&[
+            "--upstream",
+            "127.0.0.1:1",
+            "--sticky-cookie",
+            name,
+        ])
+        .await;
+        assert!(!status.success());
+    }
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,111 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, Server, TempFile, Upstream};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Formats a list of upstreams the way --discover-file expects.
+fn upstreams_json(upstreams: &[&str]) -> String {
+    let quoted: Vec<String> = upstreams
+        .iter()
+        .map(|upstream| format!("\"{}\"", upstream))
+        .collect();
+    format!("[{}]", quoted.join(", "))
+}
+
+/// Upstreams listed in the file should receive traffic, and editing the file should move traffic
+/// to the new upstreams without restarting balancebeam.
+#[tokio::test]
+async fn test_file_discovery() {
+    init_logging();
+    let first_upstream = Upstream::new().await;
+    let second_upstream = Upstream::new().await;
+    let file = TempFile::new(
+        "upstreams.json",
+        &upstreams_json(&[&first_upstream.address]),
+    );
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--discover-file",
+        file.path_str(),
+        "--discovery-interval",
+        "1",
+    ])
+    .await;
+
+    for i in 0..5 {
+        let path = format!("/before-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    log::info!("Replacing the upstream in the upstreams file");
+    // Make sure the modification time changes even on filesystems with coarse timestamps
+    sleep(Duration::from_millis(1100)).await;
+    file.write(&upstreams_json(&[&second_upstream.address]));
+    sleep(Duration::from_secs(2)).await;
+
+    for i in 0..5 {
+        let path = format!("/after-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    assert_eq!(Box::new(first_upstream).stop().await, 5);
+    assert_eq!(Box::new(second_upstream).stop().await, 5);
+
+    log::info!("All done :)");
+}
+
+/// An upstream found by resolving a hostname should receive traffic, even with no --upstream.
+#[tokio::test]
+async fn test_dns_discovery() {
+    init_logging();
+    let upstream = Upstream::new().await;
+    let port = upstream.address.rsplit_once(':').unwrap().1.to_string();
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--discover-dns",
+        &format!("localhost:{}", port),
+        "--discovery-interval",
+        "1",
+    ])
+    .await;
+
+    // localhost may also resolve to ::1, where nothing is listening. balancebeam should notice
+    // that and stick to the address that works.
+    for i in 0..5 {
+        let path = format!("/resolved-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 5);
+
+    log::info!("All done :)");
+}
+
+/// A discovery interval of zero would have balancebeam re-running discovery in a busy loop, so it
+/// should be refused.
+#[tokio::test]
+async fn test_zero_discovery_interval() {
+    init_logging();
+    let discovery_file = TempFile::new("upstreams.json", &upstreams_json(&["127.0.0.1:1"]));
+    let status = BalanceBeam::run_expecting_exit(&[
+        "--discover-file",
+        discovery_file.path_str(),
+        "--discovery-interval",
+        "0",
+    ])
+    .await;
+    assert!(!status.success());
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,79 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, Server, Upstream};
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::time::sleep;
+
+/// Requests should be proxied to an upstream listening on a Unix domain socket, and active health
+/// checks should reach it there too.
+#[tokio::test]
+async fn test_unix_upstream() {
+    init_logging();
+    let n_requests = 5;
+    let upstream = Upstream::new_unix().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], Some(1), None).await;
+
+    for i in 0..n_requests {
+        let path = format!("/over-unix-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    }
+
+    log::info!("Waiting for the active health check to run...");
+    sleep(Duration::from_millis(2500)).await;
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert!(
+        num_requests_received > n_requests,
+        "Health checks never reached the upstream over its Unix domain socket"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Clients should be able to connect to balancebeam itself over a Unix domain socket.
+#[tokio::test]
+async fn test_unix_listener() {
+    init_logging();
+    let upstream = Upstream::new().await;
+    let socket_path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}-listener.sock",
+        rand::thread_rng().gen::<u32>()
+    ));
+    let _balancebeam = BalanceBeam::new_at_address(
+        format!("unix:{}", socket_path.to_str().unwrap()),
+        &["--upstream", &upstream.address],
+    )
+    .await;
+
+    let mut stream = UnixStream::connect(&socket_path)
+        .await
+        .expect("Could not connect to balancebeam's socket");
+    stream
+        .write_all(
+            b"GET /from-unix-client HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    stream.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .await
+        .expect("Error reading response from balancebeam");
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 200"), "Got {:?}", response);
+    assert!(response.contains("GET /from-unix-client HTTP/1.1"));
+    assert!(response.contains("x-forwarded-for: unix:"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    let _ = std::fs::remove_file(&socket_path);
+
+    log::info!("All done :)");
+}
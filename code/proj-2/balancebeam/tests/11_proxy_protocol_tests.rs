@@ -0,0 +1,173 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, Server, TcpEchoServer, Upstream};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Sends raw bytes on a new connection, closes the write half, and returns everything
+/// balancebeam sends back before closing the connection.
+async fn send_raw(balancebeam: &BalanceBeam, bytes: &[u8]) -> Vec<u8> {
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(bytes)
+        .await
+        .expect("Error sending data to balancebeam");
+    stream
+        .shutdown()
+        .await
+        .expect("Error closing write half of connection");
+    let mut received = Vec::new();
+    // balancebeam may reset the connection if it rejects it, which is as good as closing it
+    let _ = stream.read_to_end(&mut received).await;
+    received
+}
+
+/// Builds a v2 header for a TCP over IPv4 connection.
+fn v2_header(source: [u8; 4], destination: [u8; 4], source_port: u16, dest_port: u16) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+    header.extend_from_slice(&source);
+    header.extend_from_slice(&destination);
+    header.extend_from_slice(&source_port.to_be_bytes());
+    header.extend_from_slice(&dest_port.to_be_bytes());
+    header
+}
+
+/// Connections from a trusted address must announce the real client with a PROXY header, which
+/// should then be used for X-Forwarded-For.
+#[tokio::test]
+async fn test_inbound_proxy_protocol() {
+    init_logging();
+    let upstream = Upstream::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--proxy-protocol-from",
+        "127.0.0.0/8",
+    ])
+    .await;
+    let request = b"GET /proxied HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+    log::info!("Sending a request with a v1 header");
+    let mut bytes = b"PROXY TCP4 203.0.113.7 127.0.0.1 40000 1100\r\n".to_vec();
+    bytes.extend_from_slice(request);
+    let response = String::from_utf8_lossy(&send_raw(&balancebeam, &bytes).await).to_string();
+    assert!(response.starts_with("HTTP/1.1 200"), "Got {:?}", response);
+    assert!(response.contains("x-forwarded-for: 203.0.113.7\n"));
+
+    log::info!("Sending a request with a v2 header");
+    let mut bytes = v2_header([198, 51, 100, 9], [127, 0, 0, 1], 40001, 1100);
+    bytes.extend_from_slice(request);
+    let response = String::from_utf8_lossy(&send_raw(&balancebeam, &bytes).await).to_string();
+    assert!(response.starts_with("HTTP/1.1 200"), "Got {:?}", response);
+    assert!(response.contains("x-forwarded-for: 198.51.100.9\n"));
+
+    log::info!("Sending a request with no header");
+    let response = send_raw(&balancebeam, request).await;
+    assert!(
+        response.is_empty(),
+        "balancebeam answered a trusted connection that had no PROXY header"
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// A trusted connection that never sends its PROXY header should be dropped once the timeout is
+/// up, rather than being kept open forever.
+#[tokio::test]
+async fn test_inbound_proxy_protocol_timeout() {
+    init_logging();
+    let upstream = Upstream::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--proxy-protocol-from",
+        "127.0.0.0/8",
+        "--proxy-protocol-timeout",
+        "300",
+    ])
+    .await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let started = Instant::now();
+    let mut buffer = [0_u8; 1];
+    let read = timeout(Duration::from_secs(5), stream.read(&mut buffer))
+        .await
+        .expect("balancebeam kept a silent connection open");
+    // balancebeam may reset the connection rather than closing it, which is just as good
+    assert!(read.map_or(true, |bytes_read| bytes_read == 0));
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert_eq!(Box::new(upstream).stop().await, 0);
+
+    log::info!("All done :)");
+}
+
+/// Upstream connections should start with a header naming the client. In tcp mode, an echo
+/// server hands that header straight back to us.
+#[tokio::test]
+async fn test_outbound_proxy_protocol() {
+    init_logging();
+    let upstream = TcpEchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--mode",
+        "tcp",
+        "--upstream",
+        &upstream.address,
+        "--proxy-protocol-to",
+        "v1",
+    ])
+    .await;
+    let (_, balancebeam_port) = balancebeam.address.rsplit_once(':').unwrap();
+
+    let echoed = send_raw(&balancebeam, b"hello").await;
+    let echoed = String::from_utf8(echoed).unwrap();
+    log::info!("Upstream received {:?}", echoed);
+    let (header, payload) = echoed.split_once("\r\n").unwrap();
+    let fields: Vec<&str> = header.split(' ').collect();
+    assert_eq!(fields[..4], ["PROXY", "TCP4", "127.0.0.1", "127.0.0.1"]);
+    assert_eq!(fields[5], balancebeam_port);
+    assert_eq!(payload, "hello");
+
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// The client address learned from an inbound header should be passed along to upstreams.
+#[tokio::test]
+async fn test_proxy_protocol_passthrough() {
+    init_logging();
+    let upstream = TcpEchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--mode",
+        "tcp",
+        "--upstream",
+        &upstream.address,
+        "--proxy-protocol-from",
+        "127.0.0.1",
+        "--proxy-protocol-to",
+        "v2",
+    ])
+    .await;
+
+    let mut bytes = b"PROXY TCP4 203.0.113.7 192.0.2.1 40000 443\r\n".to_vec();
+    bytes.extend_from_slice(b"hello");
+    let echoed = send_raw(&balancebeam, &bytes).await;
+    let mut expected = v2_header([203, 0, 113, 7], [192, 0, 2, 1], 40000, 443);
+    expected.extend_from_slice(b"hello");
+    assert_eq!(echoed, expected);
+
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,158 @@
+mod common;
+
+use common::{
+    admin_request, get_metrics, init_logging, random_address, send_raw, BalanceBeam, Server,
+    TempFile, Upstream,
+};
+use std::time::Duration;
+use tokio::time::sleep;
+
+async fn setup(config: &str, extra_args: &[&str]) -> (BalanceBeam, Upstream, TempFile, String) {
+    init_logging();
+    let upstream = Upstream::new().await;
+    let config_file = TempFile::new("config.json", config);
+    let admin_address = random_address();
+    let mut args = vec![
+        "--upstream",
+        &upstream.address,
+        "--config",
+        config_file.path_str(),
+        "--admin-bind",
+        &admin_address,
+    ];
+    args.extend_from_slice(extra_args);
+    let balancebeam = BalanceBeam::new_with_args(&args).await;
+    (balancebeam, upstream, config_file, admin_address)
+}
+
+async fn get_status(balancebeam: &BalanceBeam, path: &str) -> u16 {
+    reqwest::get(format!("http://{}{}", balancebeam.address, path))
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Sends a request that claims (via a PROXY protocol header) to come from the given client, and
+/// returns the response's status code.
+async fn get_status_as(balancebeam: &BalanceBeam, header: &str, path: &str) -> u16 {
+    let request = format!("{}GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", header, path);
+    send_raw(balancebeam, &request).await
+}
+
+/// Routes with allow and deny lists should let in only the clients they name, and count the rest.
+#[tokio::test]
+async fn test_allow_and_deny_lists() {
+    let config = r#"{"routes": [
+        {"path_prefix": "/private", "allow": ["10.0.0.0/8"]},
+        {"path_prefix": "/private/local", "allow": ["127.0.0.0/8"]},
+        {"name": "blocked", "path_prefix": "/blocked",
+         "allow": ["127.0.0.0/8"], "deny": ["127.0.0.1"]}
+    ]}"#;
+    let (balancebeam, upstream, _config_file, admin_address) = setup(config, &[]).await;
+
+    assert_eq!(get_status(&balancebeam, "/open").await, 200);
+    assert_eq!(get_status(&balancebeam, "/private/data").await, 403);
+    assert_eq!(get_status(&balancebeam, "/private/data").await, 403);
+    // The longest matching prefix wins
+    assert_eq!(get_status(&balancebeam, "/private/local/data").await, 200);
+    // Deny lists take priority over allow lists
+    assert_eq!(get_status(&balancebeam, "/blocked").await, 403);
+
+    let metrics = get_metrics(&admin_address).await;
+    assert!(metrics.contains(
+        "balancebeam_requests_rejected_total{route=\"/private\",reason=\"ip_denied\"} 2\n"
+    ));
+    assert!(metrics.contains(
+        "balancebeam_requests_rejected_total{route=\"blocked\",reason=\"ip_denied\"} 1\n"
+    ));
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// Routes should match whole path segments, and paths should be normalized before they are
+/// matched, so that a request can't get past a route's checks by spelling its path differently.
+#[tokio::test]
+async fn test_path_normalization() {
+    let config = r#"{"routes": [{"path_prefix": "/admin", "allow": ["10.0.0.0/8"]}]}"#;
+    let (balancebeam, upstream, _config_file, _) = setup(config, &[]).await;
+
+    for path in [
+        "/admin",
+        "/admin/",
+        "/admin/users",
+        "//admin",
+        "/%61dmin",
+        "/x/../admin",
+        "/./admin/./users",
+        "/../admin",
+        "/%2e%2e/admin",
+        // Encoded slashes don't separate segments, so these stay under "/admin"
+        "/admin/..%2f../public",
+        "/admin/..%2F..%2Fpublic",
+    ] {
+        assert_eq!(get_status_as(&balancebeam, "", path).await, 403, "{}", path);
+    }
+    assert_eq!(get_status_as(&balancebeam, "", "/administrator").await, 200);
+    assert_eq!(
+        get_status_as(&balancebeam, "", "/admin/../public").await,
+        200
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// IPv6 clients (here, announced by a trusted proxy) should be matched against IPv6 blocks.
+#[tokio::test]
+async fn test_ipv6_blocks() {
+    let config = r#"{"routes": [{"path_prefix": "/", "allow": ["2001:db8::/32"]}]}"#;
+    let (balancebeam, upstream, _config_file, _) =
+        setup(config, &["--proxy-protocol-from", "127.0.0.1"]).await;
+
+    let inside = "PROXY TCP6 2001:db8::7 2001:db8::1 40000 80\r\n";
+    let outside = "PROXY TCP6 2001:db9::7 2001:db8::1 40000 80\r\n";
+    assert_eq!(get_status_as(&balancebeam, inside, "/").await, 200);
+    assert_eq!(get_status_as(&balancebeam, outside, "/").await, 403);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Editing the config and reloading it (through the admin interface or with SIGHUP) should change
+/// who is allowed in without restarting balancebeam.
+#[tokio::test]
+async fn test_reload_lists() {
+    let (balancebeam, upstream, config_file, admin_address) = setup(
+        r#"{"routes": [{"path_prefix": "/", "deny": ["127.0.0.1"]}]}"#,
+        &[],
+    )
+    .await;
+    assert_eq!(get_status(&balancebeam, "/").await, 403);
+
+    log::info!("Reloading through the admin interface");
+    config_file.write(r#"{"routes": []}"#);
+    let (status, _) = admin_request(&admin_address, reqwest::Method::POST, "/reload", "").await;
+    assert_eq!(status, 200);
+    assert_eq!(get_status(&balancebeam, "/").await, 200);
+
+    log::info!("Reloading a broken config should keep the old one");
+    config_file.write(r#"{"routes": [{"path_prefix": "/", "deny": ["not an address"]}]}"#);
+    let (status, _) = admin_request(&admin_address, reqwest::Method::POST, "/reload", "").await;
+    assert_eq!(status, 500);
+    assert_eq!(get_status(&balancebeam, "/").await, 200);
+
+    log::info!("Reloading with SIGHUP");
+    config_file.write(r#"{"routes": [{"path_prefix": "/", "deny": ["127.0.0.0/8"]}]}"#);
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGHUP);
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(get_status(&balancebeam, "/").await, 403);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,269 @@
+mod common;
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use common::{init_logging, BalanceBeam, Server, TempFile, Upstream};
+use hmac::{Hmac, Mac};
+use sha1::{Digest, Sha1};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::time::sleep;
+
+const JWT_SECRET: &str = "correct horse battery staple";
+
+async fn setup(config: &str) -> (BalanceBeam, Upstream, TempFile) {
+    init_logging();
+    let upstream = Upstream::new().await;
+    let config_file = TempFile::new("config.json", config);
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--config",
+        config_file.path_str(),
+    ])
+    .await;
+    (balancebeam, upstream, config_file)
+}
+
+/// Sends a GET request with the given Authorization header (if any), returning the response.
+async fn get_with_auth(
+    balancebeam: &BalanceBeam,
+    path: &str,
+    authorization: Option<&str>,
+) -> reqwest::Response {
+    let mut request = reqwest::Client::new().get(format!("http://{}{}", balancebeam.address, path));
+    if let Some(authorization) = authorization {
+        request = request.header("Authorization", authorization);
+    }
+    request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+}
+
+fn basic(user: &str, password: &str) -> String {
+    format!(
+        "Basic {}",
+        STANDARD.encode(format!("{}:{}", user, password))
+    )
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Builds an HS256-signed JWT carrying the given claims.
+fn make_jwt(claims: serde_json::Value, secret: &str) -> String {
+    let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"HS256","typ":"JWT"}"#);
+    let claims = URL_SAFE_NO_PAD.encode(claims.to_string());
+    let signed = format!("{}.{}", header, claims);
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(signed.as_bytes());
+    let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+    format!("{}.{}", signed, signature)
+}
+
+/// Users in an htpasswd file should be let in with the right password (bcrypt or {SHA}), and the
+/// upstream should never see their credentials.
+#[tokio::test]
+async fn test_basic_auth() {
+    let htpasswd = TempFile::new(
+        "htpasswd",
+        &format!(
+            "# test users\nalice:{}\nbob:{{SHA}}{}\n",
+            bcrypt::hash("wonderland", 4).unwrap(),
+            STANDARD.encode(Sha1::digest(b"builder"))
+        ),
+    );
+    let config = format!(
+        r#"{{"routes": [
+            {{"path_prefix": "/private", "auth": {{"realm": "test", "htpasswd": "{}"}}}}
+        ]}}"#,
+        htpasswd.path_str()
+    );
+    let (balancebeam, upstream, _config_file) = setup(&config).await;
+
+    let response = get_with_auth(&balancebeam, "/private", None).await;
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(
+        response.headers().get("www-authenticate").unwrap(),
+        "Basic realm=\"test\""
+    );
+
+    for (user, password) in [("alice", "wonderland"), ("bob", "builder")] {
+        log::info!("Logging in as {}", user);
+        let response = get_with_auth(&balancebeam, "/private", Some(&basic(user, password))).await;
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.text().await.unwrap();
+        assert!(!body.to_lowercase().contains("authorization:"));
+    }
+
+    for (user, password) in [("alice", "builder"), ("mallory", "wonderland")] {
+        let response = get_with_auth(&balancebeam, "/private", Some(&basic(user, password))).await;
+        assert_eq!(response.status().as_u16(), 401);
+    }
+
+    // Other routes are not affected, and keep their Authorization headers
+    let response = get_with_auth(&balancebeam, "/public", Some("Bearer abc")).await;
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .to_lowercase()
+        .contains("authorization: bearer abc"));
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+
+    log::info!("All done :)");
+}
+
+/// bcrypt is slow on purpose, so a client sending bad passwords for bcrypt users shouldn't hold
+/// up requests that don't need checking.
+#[tokio::test]
+async fn test_bcrypt_checks_dont_block() {
+    let hash = bcrypt::hash("wonderland", 10).unwrap();
+    let started = Instant::now();
+    assert!(!bcrypt::verify("guess", &hash).unwrap());
+    let check_time = started.elapsed();
+    let htpasswd = TempFile::new("htpasswd", &format!("alice:{}\n", hash));
+    let config = format!(
+        r#"{{"routes": [{{"path_prefix": "/private", "auth": {{"htpasswd": "{}"}}}}]}}"#,
+        htpasswd.path_str()
+    );
+    let (balancebeam, upstream, _config_file) = setup(&config).await;
+    log::info!("A bcrypt check takes {:?}", check_time);
+
+    let guesses: Vec<_> = (0..4)
+        .map(|i| {
+            let url = format!("http://{}/private", balancebeam.address);
+            tokio::spawn(async move {
+                reqwest::Client::new()
+                    .get(url)
+                    .header("Authorization", basic("alice", &format!("guess-{}", i)))
+                    .send()
+                    .await
+                    .expect("Error sending request to balancebeam")
+                    .status()
+                    .as_u16()
+            })
+        })
+        .collect();
+    // Give the guesses time to arrive and start being checked
+    sleep(Duration::from_millis(50)).await;
+    let started = Instant::now();
+    let response = get_with_auth(&balancebeam, "/public", None).await;
+    assert_eq!(response.status().as_u16(), 200);
+    log::info!(
+        "Request took {:?} while guesses were checked",
+        started.elapsed()
+    );
+    // Had the guesses been checked on balancebeam's own threads, this would have had to wait for
+    // all four of them
+    assert!(started.elapsed() < check_time * 2);
+    for guess in guesses {
+        assert_eq!(guess.await.expect("Task panicked"), 401);
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Routes accepting bearer tokens should let in static tokens and valid JWTs, and tell clients
+/// with bad tokens why they were refused.
+#[tokio::test]
+async fn test_bearer_tokens() {
+    let config = format!(
+        r#"{{"routes": [{{"path_prefix": "/", "auth": {{
+            "bearer_tokens": ["static-token"],
+            "jwt": {{"secret": "{}", "issuer": "tests", "audience": "balancebeam"}}
+        }}}}]}}"#,
+        JWT_SECRET
+    );
+    let (balancebeam, upstream, _config_file) = setup(&config).await;
+
+    let response = get_with_auth(&balancebeam, "/", None).await;
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(
+        response.headers().get("www-authenticate").unwrap(),
+        "Bearer realm=\"balancebeam\""
+    );
+
+    let response = get_with_auth(&balancebeam, "/", Some("Bearer static-token")).await;
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(!response
+        .text()
+        .await
+        .unwrap()
+        .to_lowercase()
+        .contains("authorization:"));
+
+    let valid = serde_json::json!({"iss": "tests", "aud": ["balancebeam"], "exp": now() + 60});
+    let token = make_jwt(valid.clone(), JWT_SECRET);
+    let response = get_with_auth(&balancebeam, "/", Some(&format!("Bearer {}", token))).await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    let rejected = [
+        ("a bad signature", make_jwt(valid, "wrong secret")),
+        (
+            "an expired token",
+            make_jwt(
+                serde_json::json!({"iss": "tests", "aud": "balancebeam", "exp": now() - 60}),
+                JWT_SECRET,
+            ),
+        ),
+        (
+            "the wrong issuer",
+            make_jwt(
+                serde_json::json!({"iss": "someone else", "aud": "balancebeam"}),
+                JWT_SECRET,
+            ),
+        ),
+        ("a wrong static token", "other-token".to_string()),
+    ];
+    for (description, token) in rejected {
+        log::info!("Trying {}", description);
+        let response = get_with_auth(&balancebeam, "/", Some(&format!("Bearer {}", token))).await;
+        assert_eq!(response.status().as_u16(), 401);
+        assert_eq!(
+            response.headers().get("www-authenticate").unwrap(),
+            "Bearer realm=\"balancebeam\", error=\"invalid_token\""
+        );
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// A realm that can't be sent as a quoted string in WWW-Authenticate should be refused when the
+/// config is loaded, rather than breaking every 401 the route sends.
+#[tokio::test]
+async fn test_invalid_realm() {
+    init_logging();
+    for realm in ["Zürich", "say \\\"hi\\\"", "back\\\\slash"] {
+        log::info!("Trying realm {}", realm);
+        let config_file = TempFile::new(
+            "config.json",
+            &format!(
+                r#"{{"routes": [
+                    {{"path_prefix": "/", "auth": {{"realm": "{}", "bearer_tokens": ["t"]}}}}
+                ]}}"#,
+                realm
+            ),
+        );
+        let status = BalanceBeam::run_expecting_exit(&[
+            "--upstream",
+            "127.0.0.1:1",
+            "--config",
+            config_file.path_str(),
+        ])
+        .await;
+        assert!(!status.success());
+    }
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,133 @@
+mod common;
+
+use common::{init_logging, send_raw, BalanceBeam, Server, TempFile, Upstream};
+
+const GLOBAL_LIMITS: [&str; 8] = [
+    "--max-body-size",
+    "100",
+    "--max-headers-size",
+    "1000",
+    "--max-num-headers",
+    "8",
+    "--max-uri-length",
+    "50",
+];
+
+async fn setup(config: &str) -> (BalanceBeam, Upstream, TempFile) {
+    init_logging();
+    let upstream = Upstream::new().await;
+    let config_file = TempFile::new("config.json", config);
+    let mut args = vec![
+        "--upstream",
+        &upstream.address,
+        "--config",
+        config_file.path_str(),
+    ];
+    args.extend_from_slice(&GLOBAL_LIMITS);
+    let balancebeam = BalanceBeam::new_with_args(&args).await;
+    (balancebeam, upstream, config_file)
+}
+
+/// Builds a request for the given path, with `num_headers` filler headers (on top of Host and
+/// Content-Length) and a body of `body_len` bytes.
+fn make_request(path: &str, num_headers: usize, body_len: usize) -> String {
+    let headers: String = (0..num_headers)
+        .map(|i| format!("X-Filler-{}: {}\r\n", i, i))
+        .collect();
+    format!(
+        "POST {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\n\r\n{}",
+        path,
+        headers,
+        body_len,
+        "a".repeat(body_len)
+    )
+}
+
+/// Requests that exceed the limits given on the command line should be refused with the matching
+/// status code, and never reach the upstream.
+#[tokio::test]
+async fn test_global_limits() {
+    let (balancebeam, upstream, _config_file) = setup(r#"{"routes": []}"#).await;
+
+    assert_eq!(
+        send_raw(&balancebeam, &make_request("/", 6, 100)).await,
+        200
+    );
+
+    log::info!("Sending a body that is too large");
+    assert_eq!(
+        send_raw(&balancebeam, &make_request("/", 0, 101)).await,
+        413
+    );
+
+    log::info!("Sending too many headers");
+    assert_eq!(send_raw(&balancebeam, &make_request("/", 7, 0)).await, 431);
+
+    log::info!("Sending headers that are too large");
+    let request = format!(
+        "GET / HTTP/1.1\r\nHost: localhost\r\nX-Big: {}\r\n\r\n",
+        "b".repeat(1000)
+    );
+    assert_eq!(send_raw(&balancebeam, &request).await, 431);
+
+    log::info!("Sending a URI that is too long");
+    let path = format!("/{}", "c".repeat(50));
+    assert_eq!(
+        send_raw(&balancebeam, &make_request(&path, 0, 0)).await,
+        414
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Routes can raise the body limit, and tighten any of the limits.
+#[tokio::test]
+async fn test_route_limits() {
+    let config = r#"{"routes": [
+        {"path_prefix": "/upload", "limits": {"max_body_size": 1000}},
+        {"path_prefix": "/strict",
+         "limits": {"max_num_headers": 4, "max_uri_length": 20, "max_headers_size": 200}}
+    ]}"#;
+    let (balancebeam, upstream, _config_file) = setup(config).await;
+
+    assert_eq!(
+        send_raw(&balancebeam, &make_request("/upload", 0, 1000)).await,
+        200
+    );
+    assert_eq!(
+        send_raw(&balancebeam, &make_request("/upload", 0, 1001)).await,
+        413
+    );
+    assert_eq!(
+        send_raw(&balancebeam, &make_request("/other", 0, 1000)).await,
+        413
+    );
+
+    assert_eq!(
+        send_raw(&balancebeam, &make_request("/strict", 2, 0)).await,
+        200
+    );
+    assert_eq!(
+        send_raw(&balancebeam, &make_request("/strict", 3, 0)).await,
+        431
+    );
+    assert_eq!(
+        send_raw(
+            &balancebeam,
+            &make_request("/strict/a/much/longer/path", 0, 0)
+        )
+        .await,
+        414
+    );
+    let request = format!(
+        "GET /strict HTTP/1.1\r\nHost: localhost\r\nX-Big: {}\r\n\r\n",
+        "b".repeat(200)
+    );
+    assert_eq!(send_raw(&balancebeam, &request).await, 431);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
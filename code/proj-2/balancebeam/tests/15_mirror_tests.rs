@@ -0,0 +1,119 @@
+mod common;
+
+use common::{
+    get_metrics, init_logging, random_address, start_balancebeam, BalanceBeam, Behavior, Server,
+    Upstream,
+};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+async fn setup(mirror: &str, extra_args: &[&str]) -> (BalanceBeam, Upstream, String) {
+    init_logging();
+    let upstream = Upstream::new().await;
+    let admin_address = random_address();
+    let mut args = vec![
+        "--upstream",
+        &upstream.address,
+        "--mirror",
+        mirror,
+        "--admin-bind",
+        &admin_address,
+    ];
+    args.extend_from_slice(extra_args);
+    let balancebeam = start_balancebeam(&args).await;
+    (balancebeam, upstream, admin_address)
+}
+
+async fn send_requests(balancebeam: &BalanceBeam, count: usize) {
+    for i in 0..count {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+}
+
+/// Every request should be copied to the mirror, and the difference in the two responses recorded,
+/// while clients only ever see the real upstream's response.
+#[tokio::test]
+async fn test_mirror_all_requests() {
+    let mirror = Upstream::with_behavior(Behavior::fixed(500, "")).await;
+    let (balancebeam, upstream, admin_address) = setup(&mirror.address, &[]).await;
+
+    send_requests(&balancebeam, 5).await;
+    sleep(Duration::from_millis(500)).await;
+
+    let metrics = get_metrics(&admin_address).await;
+    assert!(metrics.contains("balancebeam_mirror_requests_total{result=\"ok\"} 5\n"));
+    assert!(metrics.contains(
+        "balancebeam_mirror_responses_total{primary_status=\"200\",mirror_status=\"500\"} 5\n"
+    ));
+    assert!(metrics.contains("balancebeam_mirror_latency_seconds_count{upstream=\"mirror\"} 5\n"));
+    assert!(metrics.contains("balancebeam_mirror_latency_seconds_count{upstream=\"primary\"} 5\n"));
+
+    assert_eq!(Box::new(upstream).stop().await, 5);
+    assert_eq!(Box::new(mirror).stop().await, 5);
+
+    log::info!("All done :)");
+}
+
+/// Only the configured share of requests should be mirrored.
+#[tokio::test]
+async fn test_mirror_percent() {
+    let mirror = Upstream::new().await;
+    let (balancebeam, upstream, _) = setup(&mirror.address, &["--mirror-percent", "50"]).await;
+
+    send_requests(&balancebeam, 100).await;
+    sleep(Duration::from_millis(500)).await;
+
+    assert_eq!(Box::new(upstream).stop().await, 100);
+    let mirrored = Box::new(mirror).stop().await;
+    log::info!("{} of 100 requests were mirrored", mirrored);
+    assert!(
+        (25..=75).contains(&mirrored),
+        "{} of 100 requests were mirrored",
+        mirrored
+    );
+
+    log::info!("All done :)");
+}
+
+/// A mirror that is down should be counted, but must not affect clients.
+#[tokio::test]
+async fn test_dead_mirror() {
+    let dead_address = random_address();
+    let (balancebeam, upstream, admin_address) = setup(&dead_address, &[]).await;
+
+    send_requests(&balancebeam, 3).await;
+    sleep(Duration::from_millis(500)).await;
+
+    let metrics = get_metrics(&admin_address).await;
+    assert!(metrics.contains("balancebeam_mirror_requests_total{result=\"error\"} 3\n"));
+    assert!(!metrics.contains("balancebeam_mirror_responses_total"));
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+
+    log::info!("All done :)");
+}
+
+/// Copies waiting on a mirror that has stopped answering should be capped, with the copies beyond
+/// the cap dropped and counted, and clients shouldn't notice.
+#[tokio::test]
+async fn test_hung_mirror() {
+    let mirror =
+        Upstream::with_behavior(Behavior::echo().with_latency(Duration::from_secs(60))).await;
+    let (balancebeam, upstream, admin_address) = setup(&mirror.address, &[]).await;
+
+    let started = Instant::now();
+    send_requests(&balancebeam, 120).await;
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    let metrics = get_metrics(&admin_address).await;
+    assert!(metrics.contains("balancebeam_mirror_requests_total{result=\"dropped\"} 20\n"));
+
+    assert_eq!(Box::new(upstream).stop().await, 120);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,114 @@
+mod common;
+
+use common::{
+    admin_request, get_metrics, init_logging, random_address, start_balancebeam, BalanceBeam,
+    Behavior, Server, Upstream,
+};
+
+async fn setup(stable: &str, canary: &str, extra_args: &[&str]) -> (BalanceBeam, String) {
+    init_logging();
+    let admin_address = random_address();
+    let mut args = vec![
+        "--upstream",
+        stable,
+        "--canary",
+        canary,
+        "--admin-bind",
+        &admin_address,
+    ];
+    args.extend_from_slice(extra_args);
+    let balancebeam = start_balancebeam(&args).await;
+    (balancebeam, admin_address)
+}
+
+async fn send_requests(balancebeam: &BalanceBeam, count: usize) {
+    for i in 0..count {
+        balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+}
+
+/// The canary should get exactly the configured share of requests, and the share should be
+/// adjustable through the admin interface.
+#[tokio::test]
+async fn test_exact_split() {
+    let stable = Upstream::new().await;
+    let canary = Upstream::new().await;
+    let (balancebeam, admin_address) = setup(
+        &stable.address,
+        &canary.address,
+        &["--canary-percent", "20"],
+    )
+    .await;
+
+    send_requests(&balancebeam, 50).await;
+
+    log::info!("Changing the split through the admin interface");
+    let (status, body) = admin_request(&admin_address, reqwest::Method::PUT, "/canary", "50").await;
+    assert_eq!(status, 200);
+    assert_eq!(body, "50\n");
+    let (status, body) = admin_request(&admin_address, reqwest::Method::GET, "/canary", "").await;
+    assert_eq!(status, 200);
+    assert_eq!(body, "50\n");
+    let (status, _) = admin_request(&admin_address, reqwest::Method::PUT, "/canary", "150").await;
+    assert_eq!(status, 400);
+
+    send_requests(&balancebeam, 20).await;
+
+    let metrics = get_metrics(&admin_address).await;
+    assert!(metrics.contains("balancebeam_canary_requests_total{pool=\"canary\"} 20\n"));
+    assert!(metrics.contains("balancebeam_canary_requests_total{pool=\"stable\"} 50\n"));
+
+    assert_eq!(Box::new(stable).stop().await, 40 + 10);
+    assert_eq!(Box::new(canary).stop().await, 10 + 10);
+
+    log::info!("All done :)");
+}
+
+/// When split by header, each caller should consistently land in the same pool.
+#[tokio::test]
+async fn test_header_split() {
+    let stable = Upstream::new().await;
+    // Responses from the canary are told apart by their status
+    let canary = Upstream::with_behavior(Behavior::fixed(500, "")).await;
+    let (balancebeam, _) = setup(
+        &stable.address,
+        &canary.address,
+        &["--canary-percent", "50", "--canary-header", "x-user"],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let mut canary_users = 0;
+    for user in 0..40 {
+        let mut statuses = Vec::new();
+        for _ in 0..3 {
+            let response = client
+                .get(format!("http://{}/", balancebeam.address))
+                .header("x-user", format!("user-{}", user))
+                .send()
+                .await
+                .expect("Error sending request to balancebeam");
+            statuses.push(response.status().as_u16());
+        }
+        log::info!("user-{} got {:?}", user, statuses);
+        assert!(statuses.iter().all(|status| *status == statuses[0]));
+        if statuses[0] == 500 {
+            canary_users += 1;
+        }
+    }
+    assert!(
+        (1..40).contains(&canary_users),
+        "{} of 40 users were sent to the canary",
+        canary_users
+    );
+
+    assert_eq!(
+        Box::new(stable).stop().await + Box::new(canary).stop().await,
+        120
+    );
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,179 @@
+mod common;
+
+use common::{
+    get_metrics, init_logging, random_address, start_balancebeam, BalanceBeam, Behavior, Server,
+    Upstream,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Upstreams with fewer recent response times than this aren't judged (see outlier.rs)
+const MIN_SAMPLES: usize = 10;
+
+/// An upstream that answers with the given behavior, along with a count of the requests it has
+/// had from balancebeam (health checks aside).
+async fn counting_upstream(behavior: Behavior) -> (Upstream, Arc<AtomicUsize>) {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+    let upstream = Upstream::builder()
+        .scenario(move |request| {
+            if request.uri().path() != "/health" {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            behavior.clone()
+        })
+        .start()
+        .await;
+    (upstream, requests)
+}
+
+/// An upstream that answers every request successfully, but only after a delay.
+async fn slow_upstream() -> (Upstream, Arc<AtomicUsize>) {
+    counting_upstream(Behavior::echo().with_latency(Duration::from_millis(200))).await
+}
+
+async fn fast_upstream() -> (Upstream, Arc<AtomicUsize>) {
+    counting_upstream(Behavior::echo()).await
+}
+
+fn count(counters: &[Arc<AtomicUsize>]) -> usize {
+    counters
+        .iter()
+        .map(|counter| counter.load(Ordering::SeqCst))
+        .sum()
+}
+
+async fn setup(upstreams: &[&Upstream], extra_args: &[&str]) -> (BalanceBeam, String) {
+    init_logging();
+    let admin_address = random_address();
+    let mut args = vec![
+        // Well clear of the jitter in the fast upstreams' response times
+        "--outlier-latency-multiple",
+        "20",
+        "--admin-bind",
+        &admin_address,
+        "--active-health-check-path",
+        "/health",
+    ];
+    for upstream in upstreams {
+        args.push("--upstream");
+        args.push(&upstream.address);
+    }
+    args.extend_from_slice(extra_args);
+    let balancebeam = start_balancebeam(&args).await;
+    (balancebeam, admin_address)
+}
+
+/// Sends requests one at a time until `done` returns true. Which upstream each one goes to is up
+/// to balancebeam, so this keeps going for as long as it takes rather than sending a fixed number.
+async fn send_until(balancebeam: &BalanceBeam, done: impl Fn() -> bool) {
+    for _ in 0..1000 {
+        if done() {
+            return;
+        }
+        balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    panic!("Upstreams never got the requests they were meant to");
+}
+
+/// Sends requests until every upstream has answered enough of them to be judged.
+async fn send_until_judged(balancebeam: &BalanceBeam, counters: &[Arc<AtomicUsize>]) {
+    send_until(balancebeam, || {
+        counters
+            .iter()
+            .all(|counter| counter.load(Ordering::SeqCst) >= MIN_SAMPLES)
+    })
+    .await;
+}
+
+/// An upstream that is much slower than the others should stop getting traffic.
+#[tokio::test]
+async fn test_slow_upstream_ejected() {
+    let mut upstreams = Vec::new();
+    let mut counters = Vec::new();
+    for _ in 0..3 {
+        let (upstream, counter) = fast_upstream().await;
+        upstreams.push(upstream);
+        counters.push(counter);
+    }
+    let (slow_upstream, slow_counter) = slow_upstream().await;
+    upstreams.push(slow_upstream);
+    counters.push(slow_counter.clone());
+    let (balancebeam, admin_address) = setup(&upstreams.iter().collect::<Vec<_>>(), &[]).await;
+
+    // The slow upstream may be ejected as soon as it has been judged against any one of the fast
+    // ones, in which case it stops getting requests, but by then it has had all it needs
+    send_until_judged(&balancebeam, &counters).await;
+    sleep(Duration::from_millis(1500)).await;
+
+    let metrics = get_metrics(&admin_address).await;
+    assert!(metrics.contains(&format!(
+        "balancebeam_outlier_ejections_total{{upstream=\"{}\"}} 1\n",
+        upstreams[3].address
+    )));
+    let slow_requests = slow_counter.load(Ordering::SeqCst);
+    for _ in 0..30 {
+        balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    assert_eq!(slow_counter.load(Ordering::SeqCst), slow_requests);
+
+    for upstream in upstreams {
+        Box::new(upstream).stop().await;
+    }
+
+    log::info!("All done :)");
+}
+
+/// No more than the configured share of upstreams should be ejected, even if more are slow.
+#[tokio::test]
+async fn test_max_ejection_percent() {
+    let mut upstreams = Vec::new();
+    let mut counters = Vec::new();
+    let mut slow_counters = Vec::new();
+    for _ in 0..3 {
+        let (upstream, counter) = fast_upstream().await;
+        upstreams.push(upstream);
+        counters.push(counter);
+    }
+    for _ in 0..2 {
+        let (upstream, counter) = slow_upstream().await;
+        upstreams.push(upstream);
+        counters.push(counter.clone());
+        slow_counters.push(counter);
+    }
+    let (balancebeam, admin_address) = setup(
+        &upstreams.iter().collect::<Vec<_>>(),
+        &["--outlier-max-ejection-percent", "20"],
+    )
+    .await;
+
+    send_until_judged(&balancebeam, &counters).await;
+    sleep(Duration::from_millis(1500)).await;
+
+    let metrics = get_metrics(&admin_address).await;
+    assert_eq!(
+        metrics
+            .lines()
+            .filter(|line| line.starts_with("balancebeam_outlier_ejections_total"))
+            .collect::<Vec<_>>()
+            .len(),
+        1
+    );
+    // The slow upstream that wasn't ejected should still be getting its share
+    let slow_requests = count(&slow_counters);
+    send_until(&balancebeam, || count(&slow_counters) > slow_requests).await;
+
+    for upstream in upstreams {
+        Box::new(upstream).stop().await;
+    }
+
+    log::info!("All done :)");
+}
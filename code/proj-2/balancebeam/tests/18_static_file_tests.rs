@@ -0,0 +1,286 @@
+mod common;
+
+use common::{
+    init_logging, random_address, send_raw, start_balancebeam, BalanceBeam, Server, TempDir,
+    TempFile, Upstream,
+};
+
+const STYLESHEET: &str = "body { color: #123456; }\n";
+
+async fn setup(upstream: &str, config: &str, extra_args: &[&str]) -> (BalanceBeam, TempFile) {
+    init_logging();
+    let config_file = TempFile::new("config.json", config);
+    let mut args = vec!["--upstream", upstream, "--config", config_file.path_str()];
+    args.extend_from_slice(extra_args);
+    let balancebeam = start_balancebeam(&args).await;
+    (balancebeam, config_file)
+}
+
+async fn get(balancebeam: &BalanceBeam, path: &str, headers: &[(&str, &str)]) -> reqwest::Response {
+    let mut request = reqwest::Client::new().get(format!("http://{}{}", balancebeam.address, path));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+}
+
+fn header<'a>(response: &'a reqwest::Response, name: &str) -> &'a str {
+    response
+        .headers()
+        .get(name)
+        .unwrap_or_else(|| panic!("Response has no {} header", name))
+        .to_str()
+        .unwrap()
+}
+
+/// Files under a static route should be served straight from disk, with ranges and conditional
+/// requests honored, while everything else still goes to the upstream.
+#[tokio::test]
+async fn test_static_files() {
+    let upstream = Upstream::new().await;
+    let site = TempDir::new("site");
+    site.write("index.html", b"<h1>Back soon</h1>");
+    site.write("css/style.css", STYLESHEET.as_bytes());
+    let config = format!(
+        r#"{{"routes": [{{"path_prefix": "/static", "static_dir": "{}"}}]}}"#,
+        site.path_str()
+    );
+    let (balancebeam, _config_file) = setup(&upstream.address, &config, &[]).await;
+
+    let response = get(&balancebeam, "/static/css/style.css", &[]).await;
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(header(&response, "content-type"), "text/css; charset=utf-8");
+    assert_eq!(header(&response, "accept-ranges"), "bytes");
+    let last_modified = header(&response, "last-modified").to_string();
+    assert_eq!(response.text().await.unwrap(), STYLESHEET);
+
+    log::info!("Directories are answered with their index.html");
+    let response = get(&balancebeam, "/static/", &[]).await;
+    assert_eq!(
+        header(&response, "content-type"),
+        "text/html; charset=utf-8"
+    );
+    assert_eq!(response.text().await.unwrap(), "<h1>Back soon</h1>");
+
+    log::info!("Requesting byte ranges");
+    let response = get(
+        &balancebeam,
+        "/static/css/style.css",
+        &[("Range", "bytes=0-3")],
+    )
+    .await;
+    assert_eq!(response.status().as_u16(), 206);
+    assert_eq!(
+        header(&response, "content-range"),
+        format!("bytes 0-3/{}", STYLESHEET.len())
+    );
+    assert_eq!(response.text().await.unwrap(), "body");
+    let response = get(
+        &balancebeam,
+        "/static/css/style.css",
+        &[("Range", "bytes=-3")],
+    )
+    .await;
+    assert_eq!(response.status().as_u16(), 206);
+    assert_eq!(response.text().await.unwrap(), " }\n");
+    let response = get(
+        &balancebeam,
+        "/static/css/style.css",
+        &[("Range", "bytes=500-")],
+    )
+    .await;
+    assert_eq!(response.status().as_u16(), 416);
+    assert_eq!(
+        header(&response, "content-range"),
+        format!("bytes */{}", STYLESHEET.len())
+    );
+
+    log::info!("Making conditional requests");
+    let response = get(
+        &balancebeam,
+        "/static/css/style.css",
+        &[("If-Modified-Since", &last_modified)],
+    )
+    .await;
+    assert_eq!(response.status().as_u16(), 304);
+    assert_eq!(response.text().await.unwrap(), "");
+    let response = get(
+        &balancebeam,
+        "/static/css/style.css",
+        &[("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT")],
+    )
+    .await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    log::info!("Requesting files that aren't there");
+    assert_eq!(
+        get(&balancebeam, "/static/missing.js", &[]).await.status(),
+        404
+    );
+    // Climbing out of the route's prefix also takes the request out of the static directory, so
+    // it goes to the upstream instead of being answered from disk
+    let escape = "GET /static/%2e%2e/%2e%2e/etc/passwd HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    assert_eq!(send_raw(&balancebeam, escape).await, 200);
+    let inside = "GET /static//css/../css/style.css HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    assert_eq!(send_raw(&balancebeam, inside).await, 200);
+    let post = "POST /static/ HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n";
+    assert_eq!(send_raw(&balancebeam, post).await, 405);
+
+    let response_text = balancebeam
+        .get("/api")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /api HTTP/1.1"));
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// Symlinks inside the static directory may only lead to files that are also inside it.
+#[tokio::test]
+async fn test_static_symlinks() {
+    let upstream = Upstream::new().await;
+    let site = TempDir::new("site");
+    let outside = TempDir::new("outside");
+    site.write("style.css", STYLESHEET.as_bytes());
+    outside.write("secret.txt", b"top secret");
+    let link = |target: &std::path::Path, name: &str| {
+        std::os::unix::fs::symlink(target, site.path.join(name)).unwrap();
+    };
+    link(&site.path.join("style.css"), "theme.css");
+    link(&outside.path.join("secret.txt"), "secret.txt");
+    link(&outside.path, "outside");
+    let config = format!(
+        r#"{{"routes": [{{"path_prefix": "/static", "static_dir": "{}"}}]}}"#,
+        site.path_str()
+    );
+    let (balancebeam, _config_file) = setup(&upstream.address, &config, &[]).await;
+
+    let response = get(&balancebeam, "/static/theme.css", &[]).await;
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.text().await.unwrap(), STYLESHEET);
+    for path in ["/static/secret.txt", "/static/outside/secret.txt"] {
+        let response = get(&balancebeam, path, &[]).await;
+        assert_eq!(response.status().as_u16(), 404);
+        assert_ne!(response.text().await.unwrap(), "top secret");
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+
+    log::info!("All done :)");
+}
+
+/// Files bigger than --max-response-body-size shouldn't be sent whole, though ranges of them that
+/// fit under the limit still can be.
+#[tokio::test]
+async fn test_static_body_size_limit() {
+    let upstream = Upstream::new().await;
+    let site = TempDir::new("site");
+    let contents: Vec<u8> = (0..1000).map(|i| b'a' + (i % 26) as u8).collect();
+    site.write("big.bin", &contents);
+    let config = format!(
+        r#"{{"routes": [{{"path_prefix": "/static", "static_dir": "{}"}}]}}"#,
+        site.path_str()
+    );
+    let (balancebeam, _config_file) = setup(
+        &upstream.address,
+        &config,
+        &["--max-response-body-size", "100"],
+    )
+    .await;
+
+    assert_eq!(
+        get(&balancebeam, "/static/big.bin", &[]).await.status(),
+        500
+    );
+    let response = get(
+        &balancebeam,
+        "/static/big.bin",
+        &[("Range", "bytes=900-949")],
+    )
+    .await;
+    assert_eq!(response.status().as_u16(), 206);
+    assert_eq!(header(&response, "content-range"), "bytes 900-949/1000");
+    assert_eq!(response.bytes().await.unwrap(), &contents[900..950]);
+    let response = get(&balancebeam, "/static/big.bin", &[("Range", "bytes=-100")]).await;
+    assert_eq!(response.status().as_u16(), 206);
+    assert_eq!(response.bytes().await.unwrap(), &contents[900..]);
+    assert_eq!(
+        get(&balancebeam, "/static/big.bin", &[("Range", "bytes=0-100")])
+            .await
+            .status(),
+        500
+    );
+    assert_eq!(Box::new(upstream).stop().await, 0);
+
+    log::info!("All done :)");
+}
+
+/// Errors balancebeam sends itself should use the configured pages, filled in with the request ID
+/// that is also returned in X-Request-Id.
+#[tokio::test]
+async fn test_custom_error_pages() {
+    let pages = TempDir::new("pages");
+    pages.write(
+        "502.html",
+        b"<h1>{{status}} {{reason}}</h1><p>Quote {{request_id}} when asking for help</p>",
+    );
+    pages.write(
+        "404.json",
+        b"{\"status\": {{status}}, \"request_id\": \"{{request_id}}\"}",
+    );
+    let empty_dir = TempDir::new("empty");
+    let config = format!(
+        r#"{{"routes": [{{"path_prefix": "/files", "static_dir": "{}"}},
+                       {{"path_prefix": "/private", "allow": ["10.0.0.0/8"]}}],
+            "error_pages": {{"502": "{}/502.html", "404": "{}/404.json"}}}}"#,
+        empty_dir.path_str(),
+        pages.path_str(),
+        pages.path_str()
+    );
+    // Nothing is listening on the upstream's port, so every forwarded request fails
+    let dead_upstream = random_address();
+    let (balancebeam, _config_file) = setup(&dead_upstream, &config, &[]).await;
+
+    let response = get(
+        &balancebeam,
+        "/files/missing",
+        &[("X-Request-Id", "abc-123")],
+    )
+    .await;
+    assert_eq!(response.status().as_u16(), 404);
+    assert_eq!(header(&response, "content-type"), "application/json");
+    assert_eq!(header(&response, "x-request-id"), "abc-123");
+    assert_eq!(
+        response.text().await.unwrap(),
+        "{\"status\": 404, \"request_id\": \"abc-123\"}"
+    );
+
+    log::info!("Statuses without a page get the plain one, but still carry the request ID");
+    let response = get(&balancebeam, "/private", &[]).await;
+    assert_eq!(response.status().as_u16(), 403);
+    assert_eq!(header(&response, "content-type"), "text/plain");
+    assert_eq!(header(&response, "x-request-id").len(), 16);
+
+    log::info!("IDs that aren't safe to put in a page are replaced");
+    let response = get(&balancebeam, "/", &[("X-Request-Id", "<script>")]).await;
+    assert_eq!(response.status().as_u16(), 502);
+    assert_eq!(
+        header(&response, "content-type"),
+        "text/html; charset=utf-8"
+    );
+    let request_id = header(&response, "x-request-id").to_string();
+    assert_ne!(request_id, "<script>");
+    assert_eq!(
+        response.text().await.unwrap(),
+        format!(
+            "<h1>502 Bad Gateway</h1><p>Quote {} when asking for help</p>",
+            request_id
+        )
+    );
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,252 @@
+mod common;
+
+use common::{init_logging, start_balancebeam, BalanceBeam, ScriptedServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const MULTIPART_BODY: &str = "--SEPARATOR\r\n\
+    Content-Type: text/plain\r\n\
+    Content-Range: bytes 0-4/20\r\n\
+    \r\n\
+    hello\r\n\
+    --SEPARATOR\r\n\
+    Content-Type: text/plain\r\n\
+    Content-Range: bytes 15-19/20\r\n\
+    \r\n\
+    world\r\n\
+    --SEPARATOR--\r\n";
+
+/// Answers each path with a response that is framed in a way balancebeam has to get exactly right
+/// to find where it ends. The server keeps the connection open afterwards, so reading until the
+/// connection closes would hang.
+fn respond(head: &str) -> Vec<u8> {
+    let request_line = head.lines().next().unwrap();
+    let range = head
+        .lines()
+        .filter_map(|line| line.split_once(": "))
+        .find(|(name, _)| name.eq_ignore_ascii_case("range"))
+        .map(|(_, value)| value)
+        .unwrap_or("none");
+    let response = match request_line {
+        "GET /multipart HTTP/1.1" => format!(
+            "HTTP/1.1 206 Partial Content\r\n\
+             Content-Type: multipart/byteranges; boundary=\"SEPARATOR\"\r\n\r\n{}",
+            MULTIPART_BODY
+        ),
+        // The server doesn't wait to be asked before sending the next response
+        "GET /multipart-then-more HTTP/1.1" => format!(
+            "HTTP/1.1 206 Partial Content\r\n\
+             Content-Type: multipart/byteranges; boundary=\"SEPARATOR\"\r\n\r\n{}\
+             HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nmore",
+            MULTIPART_BODY
+        ),
+        "GET /more HTTP/1.1" => String::new(),
+        "GET /range HTTP/1.1" => format!(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-4/20\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            range.len(),
+            range
+        ),
+        "GET /interim HTTP/1.1" => "HTTP/1.1 100 Continue\r\n\r\n\
+             HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload\r\n\r\n\
+             HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nfinal"
+            .to_string(),
+        "GET /not-modified HTTP/1.1" => {
+            "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nContent-Length: 1000\r\n\r\n".to_string()
+        }
+        "HEAD /head HTTP/1.1" => {
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 1000\r\n\r\n"
+                .to_string()
+        }
+        "DELETE /no-content HTTP/1.1" => "HTTP/1.1 204 No Content\r\n\r\n".to_string(),
+        _ => "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndone".to_string(),
+    };
+    response.into_bytes()
+}
+
+async fn setup() -> (BalanceBeam, ScriptedServer) {
+    init_logging();
+    let upstream = ScriptedServer::new(respond).await;
+    let balancebeam = start_balancebeam(&["--upstream", &upstream.address]).await;
+    (balancebeam, upstream)
+}
+
+/// Range requests should reach the upstream untouched, and partial responses, multipart or not,
+/// should come back whole without holding up the responses after them.
+#[tokio::test]
+async fn test_partial_content() {
+    let (balancebeam, upstream) = setup().await;
+    // Reusing one client sends every request over the same connection
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .unwrap();
+    let url = |path: &str| format!("http://{}{}", balancebeam.address, path);
+
+    let response = client
+        .get(url("/range"))
+        .header("Range", "bytes=0-4")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 206);
+    assert_eq!(
+        response.headers().get("content-range").unwrap(),
+        "bytes 0-4/20"
+    );
+    assert_eq!(response.text().await.unwrap(), "bytes=0-4");
+
+    for _ in 0..2 {
+        let response = client
+            .get(url("/multipart"))
+            .header("Range", "bytes=0-4,15-19")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 206);
+        assert_eq!(response.text().await.unwrap(), MULTIPART_BODY);
+    }
+
+    let response = client
+        .get(url("/after"))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.text().await.unwrap(), "done");
+    assert_eq!(Box::new(upstream).stop().await, 4);
+
+    log::info!("All done :)");
+}
+
+/// Bytes the upstream sends after a multipart body's closing delimiter belong to its next
+/// response, not to the multipart body.
+#[tokio::test]
+async fn test_response_after_multipart_body() {
+    let (balancebeam, upstream) = setup().await;
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .unwrap();
+    let url = |path: &str| format!("http://{}{}", balancebeam.address, path);
+
+    let response = client
+        .get(url("/multipart-then-more"))
+        .header("Range", "bytes=0-4,15-19")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 206);
+    assert_eq!(response.text().await.unwrap(), MULTIPART_BODY);
+
+    // Answered with the response the upstream already sent on the same connection
+    let response = client
+        .get(url("/more"))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.text().await.unwrap(), "more");
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// Responses that never have a body should be passed on without waiting for the body their
+/// Content-Length describes.
+#[tokio::test]
+async fn test_bodyless_responses() {
+    let (balancebeam, upstream) = setup().await;
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .unwrap();
+    let url = |path: &str| format!("http://{}{}", balancebeam.address, path);
+
+    let response = client
+        .get(url("/not-modified"))
+        .header("If-None-Match", "\"v1\"")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 304);
+    assert_eq!(response.headers().get("etag").unwrap(), "\"v1\"");
+
+    let response = client
+        .head(url("/head"))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers().get("content-length").unwrap(), "1000");
+    assert_eq!(response.text().await.unwrap(), "");
+
+    let response = client
+        .delete(url("/no-content"))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 204);
+
+    let response = client
+        .get(url("/after"))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.text().await.unwrap(), "done");
+    assert_eq!(Box::new(upstream).stop().await, 4);
+
+    log::info!("All done :)");
+}
+
+/// Interim responses should be passed on ahead of the final response, except for 100 Continue,
+/// which balancebeam has no use for once it has the whole request.
+#[tokio::test]
+async fn test_interim_responses() {
+    let (balancebeam, upstream) = setup().await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(b"GET /interim HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !String::from_utf8_lossy(&response).ends_with("\r\n\r\nfinal") {
+            let mut buffer = [0_u8; 1024];
+            let bytes_read = stream.read(&mut buffer).await.unwrap();
+            assert!(bytes_read > 0, "balancebeam hung up early");
+            response.extend_from_slice(&buffer[..bytes_read]);
+        }
+    })
+    .await
+    .expect("Timed out waiting for the final response");
+
+    // The connection should be ready for the next request
+    stream
+        .write_all(b"GET /after HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    stream.shutdown().await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("Timed out waiting for balancebeam to respond")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response);
+    log::info!("Received {:?}", response);
+
+    assert!(!response.contains("100 Continue"));
+    let early_hints = response
+        .find("HTTP/1.1 103 \r\nlink: </style.css>; rel=preload\r\n\r\n")
+        .expect("103 Early Hints wasn't passed on");
+    let final_response = response
+        .find("HTTP/1.1 200 OK\r\n")
+        .expect("Final response wasn't passed on");
+    assert!(early_hints < final_response);
+    assert!(response[final_response..].contains("\r\n\r\nfinal"));
+    assert!(response.ends_with("\r\n\r\ndone"));
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
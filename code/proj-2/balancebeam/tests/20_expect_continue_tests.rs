@@ -0,0 +1,158 @@
+mod common;
+
+use common::{init_logging, start_balancebeam, BalanceBeam, Server, TempFile, Upstream};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn setup(extra_args: &[&str]) -> (BalanceBeam, Upstream) {
+    init_logging();
+    let upstream = Upstream::new().await;
+    let mut args = vec!["--upstream", &upstream.address, "--max-body-size", "100"];
+    args.extend_from_slice(extra_args);
+    let balancebeam = start_balancebeam(&args).await;
+    (balancebeam, upstream)
+}
+
+/// Sends request headers without a body, and returns the connection along with what balancebeam
+/// sent back before it stopped sending (or a second passed).
+async fn send_headers(balancebeam: &BalanceBeam, headers: &str) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream.write_all(headers.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    let mut buffer = [0_u8; 1024];
+    while let Ok(Ok(bytes_read)) =
+        tokio::time::timeout(Duration::from_secs(1), stream.read(&mut buffer)).await
+    {
+        if bytes_read == 0 {
+            break;
+        }
+        response.extend_from_slice(&buffer[..bytes_read]);
+    }
+    (stream, String::from_utf8_lossy(&response).to_string())
+}
+
+/// A client that waits for 100 Continue before sending its body should get it, and the request
+/// should then go through as usual.
+#[tokio::test]
+async fn test_continue() {
+    let (balancebeam, upstream) = setup(&[]).await;
+
+    let (mut stream, response) = send_headers(
+        &balancebeam,
+        "POST /upload HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\n\
+         Content-Length: 11\r\n\r\n",
+    )
+    .await;
+    assert_eq!(response, "HTTP/1.1 100 Continue\r\n\r\n");
+
+    stream.write_all(b"hello world").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    log::info!("Received {:?}", response);
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.contains("POST /upload HTTP/1.1"));
+    assert!(response.ends_with("hello world"));
+    // The expectation has been met, so the upstream shouldn't be asked to meet it again
+    assert!(!response.to_lowercase().contains("expect:"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Bodies that are too big and expectations we can't meet should be refused without waiting for
+/// the body.
+#[tokio::test]
+async fn test_early_rejection() {
+    let (balancebeam, upstream) = setup(&[]).await;
+
+    let (_, response) = send_headers(
+        &balancebeam,
+        "POST /upload HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\n\
+         Content-Length: 1000\r\n\r\n",
+    )
+    .await;
+    log::info!("Received {:?}", response);
+    assert!(response.starts_with("HTTP/1.1 413"));
+    assert!(!response.contains("100 Continue"));
+
+    let (_, response) = send_headers(
+        &balancebeam,
+        "POST /upload HTTP/1.1\r\nHost: localhost\r\nExpect: a-miracle\r\n\
+         Content-Length: 10\r\n\r\n",
+    )
+    .await;
+    log::info!("Received {:?}", response);
+    assert!(response.starts_with("HTTP/1.1 417"));
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+
+    log::info!("All done :)");
+}
+
+/// Requests that are going to be refused anyway should be refused before the client is told to
+/// send the body.
+#[tokio::test]
+async fn test_refused_before_continue() {
+    let config_file = TempFile::new(
+        "config.json",
+        r#"{"routes": [
+            {"path_prefix": "/private", "deny": ["127.0.0.1"]},
+            {"path_prefix": "/auth", "auth": {"bearer_tokens": ["secret"]}}
+        ]}"#,
+    );
+    let (balancebeam, upstream) = setup(&["--config", config_file.path_str()]).await;
+
+    for (path, status) in [("/private", "403"), ("/auth", "401")] {
+        let (_, response) = send_headers(
+            &balancebeam,
+            &format!(
+                "POST {} HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\n\
+                 Content-Length: 11\r\n\r\n",
+                path
+            ),
+        )
+        .await;
+        log::info!("Received {:?}", response);
+        assert!(response.starts_with(&format!("HTTP/1.1 {}", status)));
+        assert!(!response.contains("100 Continue"));
+        // The client may still send the body, so the connection can't be reused
+        assert!(response.to_lowercase().contains("connection: close"));
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+
+    log::info!("All done :)");
+}
+
+/// HTTP/1.0 clients don't know about expectations, so an Expect header from one should be
+/// ignored rather than answered.
+#[tokio::test]
+async fn test_http10_expect_ignored() {
+    let (balancebeam, upstream) = setup(&[]).await;
+
+    for expectation in ["100-continue", "a-miracle"] {
+        let (_, response) = send_headers(
+            &balancebeam,
+            &format!(
+                "POST /upload HTTP/1.0\r\nHost: localhost\r\nExpect: {}\r\n\
+                 Content-Length: 11\r\n\r\nhello world",
+                expectation
+            ),
+        )
+        .await;
+        log::info!("Received {:?}", response);
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(!response.contains("100 Continue"));
+        assert!(!response.to_lowercase().contains("expect:"));
+        assert!(response.ends_with("hello world"));
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,194 @@
+mod common;
+
+use common::{init_logging, start_balancebeam, BalanceBeam, ScriptedServer, Server, Upstream};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn setup(upstream: &str, extra_args: &[&str]) -> BalanceBeam {
+    init_logging();
+    let mut args = vec!["--upstream", upstream];
+    args.extend_from_slice(extra_args);
+    start_balancebeam(&args).await
+}
+
+/// Sends raw requests in one go, without shutting down our side of the connection, and returns
+/// everything balancebeam sent back before it closed the connection. Each response is split into
+/// its status line and headers, and its body.
+async fn exchange(balancebeam: &BalanceBeam, requests: &str) -> Vec<(String, String)> {
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream.write_all(requests.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam didn't close the connection")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response);
+    log::info!("Received {:?}", response);
+    response
+        .split("HTTP/1.1 200 OK\r\n")
+        .skip(1)
+        .map(|response| {
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+            (head.to_lowercase(), body.to_string())
+        })
+        .collect()
+}
+
+/// Requests sent back to back without waiting for responses should each be answered, in order,
+/// and the connection closed after the one that asked for it.
+#[tokio::test]
+async fn test_pipelining() {
+    let upstream = Upstream::new().await;
+    let balancebeam = setup(&upstream.address, &[]).await;
+
+    let responses = exchange(
+        &balancebeam,
+        "POST /first HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nfirst\
+         GET /second HTTP/1.1\r\nHost: localhost\r\n\r\n\
+         POST /third HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\
+         Connection: close\r\n\r\nthird\
+         GET /unanswered HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    )
+    .await;
+    assert_eq!(responses.len(), 3);
+    assert!(responses[0].1.starts_with("POST /first HTTP/1.1"));
+    assert!(responses[0].1.ends_with("\n\nfirst"));
+    assert!(!responses[0].0.contains("connection:"));
+    assert!(responses[1].1.starts_with("GET /second HTTP/1.1"));
+    assert!(responses[1].1.ends_with("\n\n"));
+    assert!(responses[2].1.starts_with("POST /third HTTP/1.1"));
+    assert!(responses[2].1.ends_with("\n\nthird"));
+    assert!(responses[2].0.contains("connection: close"));
+    assert_eq!(Box::new(upstream).stop().await, 3);
+
+    log::info!("All done :)");
+}
+
+/// Requests with a transfer-coded body should be refused, and the connection closed, rather than
+/// the body being read as more pipelined requests.
+#[tokio::test]
+async fn test_pipelined_transfer_encoding() {
+    let upstream = Upstream::new().await;
+    let balancebeam = setup(&upstream.address, &[]).await;
+
+    let smuggled = "GET /smuggled HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    for framing in [
+        "Transfer-Encoding: chunked\r\n",
+        "Transfer-Encoding: chunked\r\nContent-Length: 3\r\n",
+    ] {
+        let mut stream = TcpStream::connect(&balancebeam.address)
+            .await
+            .expect("Could not connect to balancebeam");
+        let requests = format!(
+            "GET /first HTTP/1.1\r\nHost: localhost\r\n\r\n\
+             POST /chunked HTTP/1.1\r\nHost: localhost\r\n{}\r\n\
+             {:x}\r\n{}\r\n0\r\n\r\n",
+            framing,
+            smuggled.len(),
+            smuggled
+        );
+        stream.write_all(requests.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+            .await
+            .expect("balancebeam didn't close the connection")
+            .unwrap();
+        let response = String::from_utf8_lossy(&response);
+        log::info!("Received {:?}", response);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("GET /first HTTP/1.1"));
+        assert!(response.contains("HTTP/1.1 501 "));
+        assert!(!response.contains("/smuggled"));
+    }
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// HTTP/1.0 connections should only be kept open if the client asks for it, and should be
+/// forwarded as HTTP/1.1 either way.
+#[tokio::test]
+async fn test_http10() {
+    let upstream = Upstream::new().await;
+    let balancebeam = setup(&upstream.address, &[]).await;
+
+    let responses = exchange(
+        &balancebeam,
+        "GET /once HTTP/1.0\r\n\r\nGET /unanswered HTTP/1.0\r\n\r\n",
+    )
+    .await;
+    assert_eq!(responses.len(), 1);
+    assert!(responses[0].0.contains("connection: close"));
+    assert!(responses[0].1.starts_with("GET /once HTTP/1.1"));
+
+    let responses = exchange(
+        &balancebeam,
+        "GET /kept HTTP/1.0\r\nConnection: keep-alive\r\n\r\n\
+         GET /last HTTP/1.0\r\n\r\n",
+    )
+    .await;
+    assert_eq!(responses.len(), 2);
+    assert!(responses[0].0.contains("connection: keep-alive"));
+    assert!(responses[0].1.starts_with("GET /kept HTTP/1.1"));
+    assert!(responses[1].0.contains("connection: close"));
+    assert!(responses[1].1.starts_with("GET /last HTTP/1.1"));
+    assert_eq!(Box::new(upstream).stop().await, 3);
+
+    log::info!("All done :)");
+}
+
+/// Connections should be closed once they have carried the most requests allowed.
+#[tokio::test]
+async fn test_max_requests_per_connection() {
+    let upstream = Upstream::new().await;
+    let balancebeam = setup(&upstream.address, &["--max-requests-per-connection", "2"]).await;
+
+    let responses = exchange(
+        &balancebeam,
+        "GET /1 HTTP/1.1\r\nHost: localhost\r\n\r\n\
+         GET /2 HTTP/1.1\r\nHost: localhost\r\n\r\n\
+         GET /3 HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    )
+    .await;
+    assert_eq!(responses.len(), 2);
+    assert!(!responses[0].0.contains("connection:"));
+    assert!(responses[1].0.contains("connection: close"));
+    assert!(responses[1].1.starts_with("GET /2 HTTP/1.1"));
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// Answers /close with `Connection: close`, and everything else with a plain response.
+fn respond(head: &str) -> Vec<u8> {
+    if head.starts_with("GET /close ") {
+        b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 6\r\n\r\nclosed".to_vec()
+    } else {
+        b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_vec()
+    }
+}
+
+/// An upstream closing its connection shouldn't close the client's; the next request should go
+/// over a new upstream connection instead.
+#[tokio::test]
+async fn test_upstream_close() {
+    let upstream = ScriptedServer::new(respond).await;
+    let balancebeam = setup(&upstream.address, &[]).await;
+
+    let responses = exchange(
+        &balancebeam,
+        "GET /close HTTP/1.1\r\nHost: localhost\r\n\r\n\
+         GET /after HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert_eq!(responses.len(), 2);
+    assert!(!responses[0].0.contains("connection:"));
+    assert_eq!(responses[0].1, "closed");
+    assert_eq!(responses[1].1, "ok");
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,109 @@
+mod common;
+
+use common::{
+    get_metrics, init_logging, random_address, start_balancebeam, BalanceBeam, Behavior, Server,
+    Upstream,
+};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+async fn setup(upstream: &str, extra_args: &[&str]) -> (BalanceBeam, String) {
+    init_logging();
+    let admin_address = random_address();
+    let mut args = vec![
+        "--upstream",
+        upstream,
+        "--max-in-flight-per-upstream",
+        "1",
+        "--admin-bind",
+        &admin_address,
+    ];
+    args.extend_from_slice(extra_args);
+    let balancebeam = start_balancebeam(&args).await;
+    (balancebeam, admin_address)
+}
+
+/// Sends a request over a connection of its own, returning its status code.
+fn spawn_request(balancebeam: &BalanceBeam) -> tokio::task::JoinHandle<u16> {
+    let url = format!("http://{}/", balancebeam.address);
+    tokio::spawn(async move {
+        reqwest::get(url)
+            .await
+            .expect("Error sending request to balancebeam")
+            .status()
+            .as_u16()
+    })
+}
+
+/// Requests over an upstream's limit should wait their turn, and those that don't fit in the
+/// queue should be refused straight away.
+#[tokio::test]
+async fn test_queue_full() {
+    let upstream =
+        Upstream::with_behavior(Behavior::echo().with_latency(Duration::from_secs(1))).await;
+    let (balancebeam, admin_address) =
+        setup(&upstream.address, &["--max-queue-per-upstream", "1"]).await;
+
+    let first = spawn_request(&balancebeam);
+    sleep(Duration::from_millis(200)).await;
+    let second = spawn_request(&balancebeam);
+    sleep(Duration::from_millis(200)).await;
+
+    let metrics = get_metrics(&admin_address).await;
+    let upstream_label = format!("{{upstream=\"{}\"}}", upstream.address);
+    assert!(metrics.contains(&format!(
+        "balancebeam_upstream_in_flight{} 1\n",
+        upstream_label
+    )));
+    assert!(metrics.contains(&format!(
+        "balancebeam_upstream_queue_depth{} 1\n",
+        upstream_label
+    )));
+
+    log::info!("Sending a request that doesn't fit in the queue");
+    let started = Instant::now();
+    assert_eq!(spawn_request(&balancebeam).await.unwrap(), 503);
+    assert!(started.elapsed() < Duration::from_millis(500));
+
+    assert_eq!(first.await.unwrap(), 200);
+    assert_eq!(second.await.unwrap(), 200);
+    let metrics = get_metrics(&admin_address).await;
+    assert!(metrics.contains(&format!(
+        "balancebeam_upstream_queue_depth{} 0\n",
+        upstream_label
+    )));
+    assert!(metrics.contains(&format!(
+        "balancebeam_upstream_queue_rejections_total{{upstream=\"{}\",reason=\"full\"}} 1\n",
+        upstream.address
+    )));
+    // The upstream never had more than one request to deal with at a time
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// Requests that wait in the queue for too long should be given up on.
+#[tokio::test]
+async fn test_queue_timeout() {
+    let upstream =
+        Upstream::with_behavior(Behavior::echo().with_latency(Duration::from_secs(1))).await;
+    let (balancebeam, admin_address) = setup(&upstream.address, &["--queue-timeout", "200"]).await;
+
+    let first = spawn_request(&balancebeam);
+    sleep(Duration::from_millis(200)).await;
+    let started = Instant::now();
+    assert_eq!(spawn_request(&balancebeam).await.unwrap(), 503);
+    let waited = started.elapsed();
+    assert!(waited >= Duration::from_millis(200));
+    assert!(waited < Duration::from_millis(700));
+    assert_eq!(first.await.unwrap(), 200);
+
+    let metrics = get_metrics(&admin_address).await;
+    assert!(metrics.contains(&format!(
+        "balancebeam_upstream_queue_rejections_total{{upstream=\"{}\",reason=\"timeout\"}} 1\n",
+        upstream.address
+    )));
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
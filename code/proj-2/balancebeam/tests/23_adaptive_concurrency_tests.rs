@@ -0,0 +1,174 @@
+mod common;
+
+use common::{
+    get_metrics, init_logging, random_address, start_balancebeam, BalanceBeam, Behavior,
+    ScriptedServer, Server, Upstream,
+};
+use std::time::{Duration, Instant};
+
+async fn setup(upstream: &str, max_in_flight: &str) -> (BalanceBeam, String) {
+    init_logging();
+    let admin_address = random_address();
+    let balancebeam = start_balancebeam(&[
+        "--upstream",
+        upstream,
+        "--max-in-flight-per-upstream",
+        max_in_flight,
+        "--adaptive-concurrency",
+        // Nothing should be turned away while the limit settles
+        "--max-queue-per-upstream",
+        "1000",
+        "--queue-timeout",
+        "10000",
+        "--admin-bind",
+        &admin_address,
+    ])
+    .await;
+    (balancebeam, admin_address)
+}
+
+/// Keeps `clients` requests going at once for the given time, returning how many were answered
+/// successfully.
+async fn load(balancebeam: &BalanceBeam, clients: usize, duration: Duration) -> usize {
+    let deadline = Instant::now() + duration;
+    let tasks: Vec<_> = (0..clients)
+        .map(|_| {
+            let url = format!("http://{}/", balancebeam.address);
+            tokio::spawn(async move {
+                let client = reqwest::Client::new();
+                let mut successes = 0;
+                while Instant::now() < deadline {
+                    let response = client
+                        .get(&url)
+                        .send()
+                        .await
+                        .expect("Error sending request to balancebeam");
+                    assert_eq!(response.status().as_u16(), 200);
+                    successes += 1;
+                }
+                successes
+            })
+        })
+        .collect();
+    let mut successes = 0;
+    for task in tasks {
+        successes += task.await.unwrap();
+    }
+    successes
+}
+
+/// Reads the upstream's current concurrency limit off the admin interface.
+async fn concurrency_limit(admin_address: &str, upstream: &str) -> f64 {
+    let metrics = get_metrics(admin_address).await;
+    let series = format!(
+        "balancebeam_upstream_concurrency_limit{{upstream=\"{}\"}} ",
+        upstream
+    );
+    let limit = metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(&series))
+        .unwrap_or_else(|| panic!("No concurrency limit in metrics:\n{}", metrics))
+        .parse()
+        .unwrap();
+    log::info!("Concurrency limit for {} is {}", upstream, limit);
+    limit
+}
+
+/// An upstream that can only work on a few requests at once should have its limit brought down
+/// from the (much too high) starting point to around what it can handle, and kept there.
+#[tokio::test]
+async fn test_limit_converges() {
+    // Requests over the upstream's capacity wait inside it, so their response times go up
+    let upstream = Upstream::builder()
+        .behavior(Behavior::echo().with_latency(Duration::from_millis(50)))
+        .workers(4)
+        .start()
+        .await;
+    let (balancebeam, admin_address) = setup(&upstream.address, "40").await;
+
+    assert!(load(&balancebeam, 20, Duration::from_secs(4)).await > 0);
+    let limit = concurrency_limit(&admin_address, &upstream.address).await;
+    assert!(
+        (2.0..=12.0).contains(&limit),
+        "Limit {} isn't near the upstream's capacity",
+        limit
+    );
+
+    log::info!("The limit should stay in the same range under continued load");
+    assert!(load(&balancebeam, 20, Duration::from_secs(2)).await > 0);
+    let limit = concurrency_limit(&admin_address, &upstream.address).await;
+    assert!(
+        (2.0..=12.0).contains(&limit),
+        "Limit {} drifted away from the upstream's capacity",
+        limit
+    );
+
+    log::info!("All done :)");
+}
+
+/// An upstream that keeps up with its load shouldn't have its limit cut much.
+#[tokio::test]
+async fn test_limit_holds_for_healthy_upstream() {
+    let upstream =
+        Upstream::with_behavior(Behavior::echo().with_latency(Duration::from_millis(50))).await;
+    let (balancebeam, admin_address) = setup(&upstream.address, "20").await;
+
+    assert!(load(&balancebeam, 10, Duration::from_secs(3)).await > 0);
+    let limit = concurrency_limit(&admin_address, &upstream.address).await;
+    assert!(limit >= 10.0, "Limit {} was cut for no reason", limit);
+
+    log::info!("All done :)");
+}
+
+fn overloaded(_head: &str) -> Vec<u8> {
+    b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n".to_vec()
+}
+
+/// An upstream that says it is overloaded should have its limit cut to the minimum.
+#[tokio::test]
+async fn test_limit_cut_for_overloaded_upstream() {
+    let upstream = ScriptedServer::new(overloaded).await;
+    let (balancebeam, admin_address) = setup(&upstream.address, "10").await;
+
+    let client = reqwest::Client::new();
+    for _ in 0..40 {
+        let response = client
+            .get(format!("http://{}/", balancebeam.address))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 503);
+    }
+    assert_eq!(
+        concurrency_limit(&admin_address, &upstream.address).await,
+        1.0
+    );
+    assert_eq!(Box::new(upstream).stop().await, 40);
+
+    log::info!("All done :)");
+}
+
+/// A request the upstream fails without answering should cut the limit once, and the limit should
+/// grow back once the upstream answers normally again.
+#[tokio::test]
+async fn test_limit_recovers_after_failure() {
+    let upstream =
+        Upstream::with_behavior(Behavior::echo().with_latency(Duration::from_millis(50))).await;
+    let (balancebeam, admin_address) = setup(&upstream.address, "10").await;
+
+    assert!(load(&balancebeam, 2, Duration::from_secs(1)).await > 0);
+    upstream.set_behavior(Behavior::reset());
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    upstream.set_behavior(Behavior::echo().with_latency(Duration::from_millis(50)));
+    assert!(concurrency_limit(&admin_address, &upstream.address).await < 10.0);
+
+    log::info!("The limit should grow back to where it was");
+    assert!(load(&balancebeam, 2, Duration::from_secs(2)).await > 0);
+    let limit = concurrency_limit(&admin_address, &upstream.address).await;
+    assert!(limit >= 9.0, "Limit {} didn't recover", limit);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,119 @@
+mod common;
+
+use common::{init_logging, start_balancebeam, BalanceBeam, Server, TempFile, Upstream};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+
+async fn setup(upstream: &str, extra_args: &[&str]) -> (BalanceBeam, TempFile) {
+    init_logging();
+    let config_file = TempFile::new(
+        "config.json",
+        r#"{"routes": [{"path_prefix": "/reports", "priority": "low"},
+                       {"path_prefix": "/health", "priority": "critical"}]}"#,
+    );
+    let mut args = vec![
+        "--upstream",
+        upstream,
+        "--config",
+        config_file.path_str(),
+        "--shed-max-connections",
+        "5",
+        "--priority-header",
+        "X-Priority",
+    ];
+    args.extend_from_slice(extra_args);
+    let balancebeam = start_balancebeam(&args).await;
+    (balancebeam, config_file)
+}
+
+/// Sends a request over a new connection (which counts towards the open connections) and returns
+/// its status code.
+async fn get(balancebeam: &BalanceBeam, path: &str, priority: Option<&str>) -> u16 {
+    let mut request = reqwest::Client::new().get(format!("http://{}{}", balancebeam.address, path));
+    if let Some(priority) = priority {
+        request = request.header("X-Priority", priority);
+    }
+    let status = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16();
+    // Give balancebeam a moment to notice the connection has closed
+    sleep(Duration::from_millis(100)).await;
+    status
+}
+
+/// Opens connections that just sit there, keeping balancebeam busy.
+async fn open_idle_connections(balancebeam: &BalanceBeam, count: usize) -> Vec<TcpStream> {
+    let mut connections = Vec::new();
+    for _ in 0..count {
+        connections.push(
+            TcpStream::connect(&balancebeam.address)
+                .await
+                .expect("Could not connect to balancebeam"),
+        );
+    }
+    sleep(Duration::from_millis(200)).await;
+    connections
+}
+
+/// As connections pile up, low priority requests should be shed first, then normal ones, while
+/// critical ones keep getting through.
+#[tokio::test]
+async fn test_shed_by_priority() {
+    let upstream = Upstream::new().await;
+    let (balancebeam, _config_file) =
+        setup(&upstream.address, &["--priority-header-allow", "127.0.0.1"]).await;
+
+    assert_eq!(get(&balancebeam, "/reports", None).await, 200);
+
+    log::info!("Nearly at the limit: only low priority requests are shed");
+    let mut idle = open_idle_connections(&balancebeam, 3).await;
+    assert_eq!(get(&balancebeam, "/reports", None).await, 503);
+    assert_eq!(get(&balancebeam, "/", None).await, 200);
+    assert_eq!(get(&balancebeam, "/reports", Some("normal")).await, 200);
+
+    log::info!("At the limit: only critical requests get through");
+    idle.extend(open_idle_connections(&balancebeam, 1).await);
+    assert_eq!(get(&balancebeam, "/", None).await, 503);
+    assert_eq!(get(&balancebeam, "/health", None).await, 200);
+    assert_eq!(get(&balancebeam, "/", Some("critical")).await, 200);
+    // Priorities that aren't known fall back to the route's
+    assert_eq!(get(&balancebeam, "/health", Some("urgent")).await, 200);
+    assert_eq!(get(&balancebeam, "/", Some("urgent")).await, 503);
+
+    log::info!("Back under the limit");
+    drop(idle);
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(get(&balancebeam, "/reports", None).await, 200);
+    assert_eq!(Box::new(upstream).stop().await, 7);
+
+    log::info!("All done :)");
+}
+
+/// Clients that aren't allowed to raise their requests' priority should still be shed when they
+/// claim to be critical, though they can lower their priority.
+#[tokio::test]
+async fn test_untrusted_priority_header() {
+    let upstream = Upstream::new().await;
+    let (balancebeam, _config_file) = setup(
+        &upstream.address,
+        &["--priority-header-allow", "10.0.0.0/8"],
+    )
+    .await;
+
+    let mut idle = open_idle_connections(&balancebeam, 3).await;
+    assert_eq!(get(&balancebeam, "/reports", Some("normal")).await, 503);
+    assert_eq!(get(&balancebeam, "/health", Some("low")).await, 503);
+    assert_eq!(get(&balancebeam, "/health", None).await, 200);
+
+    idle.extend(open_idle_connections(&balancebeam, 1).await);
+    assert_eq!(get(&balancebeam, "/", Some("critical")).await, 503);
+    assert_eq!(get(&balancebeam, "/health", Some("critical")).await, 200);
+    drop(idle);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
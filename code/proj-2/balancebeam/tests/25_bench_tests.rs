@@ -0,0 +1,113 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, Server, Upstream};
+use tokio::process::Command;
+
+/// Runs `balancebeam bench` with the given arguments, returning its exit status and output.
+async fn run_bench(args: &[&str]) -> std::process::Output {
+    let mut path = std::env::current_exe().expect("Could not get current test executable path");
+    path.pop();
+    path.pop();
+    path.push("balancebeam");
+    Command::new(path)
+        .arg("bench")
+        .args(args)
+        .output()
+        .await
+        .expect("Could not run balancebeam bench")
+}
+
+/// Runs `balancebeam bench` with the given arguments, returning what it printed.
+async fn bench(args: &[&str]) -> String {
+    let output = run_bench(args).await;
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    log::info!("balancebeam bench printed:\n{}", stdout);
+    assert!(
+        output.status.success(),
+        "balancebeam bench failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    stdout
+}
+
+/// Returns the value printed on the report line with the given label.
+fn report_line<'a>(report: &'a str, label: &str) -> &'a str {
+    report
+        .lines()
+        .find_map(|line| line.strip_prefix(label))
+        .unwrap_or_else(|| panic!("No {:?} line in report:\n{}", label, report))
+        .trim()
+}
+
+/// A fixed number of requests should all be sent, over no more connections than the concurrency.
+#[tokio::test]
+async fn test_bench_requests() {
+    init_logging();
+    let upstream = Upstream::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let report = bench(&[
+        "--target",
+        &balancebeam.address,
+        "--requests",
+        "50",
+        "--concurrency",
+        "5",
+        "--method",
+        "POST",
+        "--body-size",
+        "16",
+    ])
+    .await;
+    assert_eq!(report_line(&report, "Requests:"), "50 (0 errors)");
+    assert_eq!(report_line(&report, "Statuses:"), "200: 50");
+    let connections: usize = report_line(&report, "Connections:").parse().unwrap();
+    assert!(
+        (1..=5).contains(&connections),
+        "Connections weren't kept alive: {}",
+        connections
+    );
+    assert_eq!(Box::new(upstream).stop().await, 50);
+
+    log::info!("All done :)");
+}
+
+/// Bench should be able to start its own upstreams and balancebeam, and send load at them for the
+/// given time.
+#[tokio::test]
+async fn test_bench_echo_upstreams() {
+    init_logging();
+    let report = bench(&[
+        "--echo-upstreams",
+        "2",
+        "--concurrency",
+        "4",
+        "--duration",
+        "1",
+    ])
+    .await;
+    assert!(report.starts_with("Started balancebeam on "));
+    let requests = report_line(&report, "Requests:");
+    let answered: usize = requests.split(' ').next().unwrap().parse().unwrap();
+    assert!(answered > 0);
+    assert!(requests.ends_with("(0 errors)"));
+    assert_eq!(
+        report_line(&report, "Statuses:"),
+        format!("200: {}", answered)
+    );
+    assert!(report_line(&report, "Latency:").starts_with("p50 "));
+
+    log::info!("All done :)");
+}
+
+/// Durations that aren't a positive number of seconds should be refused as a usage error.
+#[tokio::test]
+async fn test_bench_invalid_duration() {
+    init_logging();
+    for duration in ["-1", "0", "NaN", "inf", "1e30", "soon"] {
+        let output = run_bench(&["--target", "127.0.0.1:1", "--duration", duration]).await;
+        assert_eq!(output.status.code(), Some(2), "--duration {}", duration);
+    }
+
+    log::info!("All done :)");
+}
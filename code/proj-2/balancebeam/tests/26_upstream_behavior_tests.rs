@@ -0,0 +1,211 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, Behavior, ScriptedServer, Server, Upstream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Sends a request straight to the upstream, returning everything it sent back before hanging up.
+async fn exchange(upstream: &Upstream) -> std::io::Result<String> {
+    let mut stream = TcpStream::connect(&upstream.address).await?;
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    Ok(String::from_utf8_lossy(&response).to_string())
+}
+
+/// Latency, fixed status codes and trickled responses should all make it through balancebeam
+/// intact, just slowly.
+#[tokio::test]
+async fn test_slow_responses() {
+    init_logging();
+    let upstream = Upstream::with_behavior(
+        Behavior::fixed(503, "Come back later")
+            .with_latency(Duration::from_millis(300))
+            .with_trickle(20, Duration::from_millis(50)),
+    )
+    .await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let started = Instant::now();
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(response.text().await.unwrap(), "Come back later");
+    // The latency, then at least three more pieces of the response after the first
+    assert!(started.elapsed() >= Duration::from_millis(450));
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Connections to an upstream that resets them should be cut off, and balancebeam should report
+/// the failure to the client.
+#[tokio::test]
+async fn test_reset() {
+    init_logging();
+    let upstream = Upstream::with_behavior(Behavior::reset()).await;
+    let error = exchange(&upstream)
+        .await
+        .expect_err("Upstream answered rather than resetting the connection");
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionReset);
+
+    log::info!("Resetting partway through the body");
+    upstream.set_behavior(Behavior::fixed(200, "x".repeat(100)).with_reset_after(60));
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// Chunked bodies should be framed properly, and balancebeam should pass them on whole without
+/// waiting for the upstream to hang up.
+#[tokio::test]
+async fn test_chunked_body() {
+    init_logging();
+    let upstream =
+        Upstream::with_behavior(Behavior::fixed(200, "Hello, world!").with_chunked_body(5)).await;
+    let response = exchange(&upstream)
+        .await
+        .expect("Error sending request to upstream");
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+    assert!(!head.contains("Content-Length"));
+    assert_eq!(body, "5\r\nHello\r\n5\r\n, wor\r\n3\r\nld!\r\n0\r\n\r\n");
+
+    log::info!("Sending chunked responses through balancebeam");
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let mut connection = balancebeam
+        .connect()
+        .await
+        .expect("Could not connect to balancebeam");
+    for _ in 0..2 {
+        let body = tokio::time::timeout(Duration::from_secs(5), connection.get("/"))
+            .await
+            .expect("Timed out waiting for the chunked response")
+            .expect("Error sending request to balancebeam");
+        assert_eq!(body, "Hello, world!");
+    }
+
+    log::info!("All done :)");
+}
+
+fn oversized_chunk(_head: &str) -> Vec<u8> {
+    b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\n".to_vec()
+}
+
+/// A chunk too big to count should be refused like any other oversized body, without taking
+/// balancebeam down with it.
+#[tokio::test]
+async fn test_oversized_chunk() {
+    init_logging();
+    let upstream = ScriptedServer::new(oversized_chunk).await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    for _ in 0..2 {
+        let response = reqwest::get(format!("http://{}/", balancebeam.address))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 502);
+    }
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+fn misframed(head: &str) -> Vec<u8> {
+    if head.starts_with("GET /gzip ") {
+        b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\nnot really gzip".to_vec()
+    } else {
+        b"HTTP/1.1 204 No Content\r\n\r\nstray bytes".to_vec()
+    }
+}
+
+/// Bytes an upstream sends after a bodyless response shouldn't reach the client, and bodies with
+/// a transfer coding balancebeam can't frame should be refused rather than passed on unframed.
+#[tokio::test]
+async fn test_misframed_responses() {
+    init_logging();
+    let upstream = ScriptedServer::new(misframed).await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(b"GET /empty HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam didn't close the connection")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response);
+    log::info!("Received {:?}", response);
+    assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+    assert!(response.ends_with("\r\n\r\n"));
+    assert!(!response.contains("stray bytes"));
+
+    let response = tokio::time::timeout(
+        Duration::from_secs(5),
+        reqwest::get(format!("http://{}/gzip", balancebeam.address)),
+    )
+    .await
+    .expect("balancebeam waited for the upstream to close the connection")
+    .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// A scenario should be able to pick a different behavior for each request.
+#[tokio::test]
+async fn test_scenario() {
+    init_logging();
+    let requests = AtomicUsize::new(0);
+    let upstream = Upstream::builder()
+        .scenario(move |request| {
+            if request.uri().path() == "/health" {
+                Behavior::echo()
+            } else if requests.fetch_add(1, Ordering::SeqCst) % 2 == 1 {
+                Behavior::fixed(500, "")
+            } else {
+                Behavior::echo()
+            }
+        })
+        .start()
+        .await;
+    // Keep health checks out of the count
+    let balancebeam = BalanceBeam::new_with_args(&[
+        "--upstream",
+        &upstream.address,
+        "--active-health-check-path",
+        "/health",
+    ])
+    .await;
+
+    let client = reqwest::Client::new();
+    let mut statuses = Vec::new();
+    for _ in 0..4 {
+        let response = client
+            .get(format!("http://{}/", balancebeam.address))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        statuses.push(response.status().as_u16());
+    }
+    assert_eq!(statuses, vec![200, 500, 200, 500]);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,248 @@
+mod common;
+
+use common::{
+    admin_request, get_metrics, init_logging, random_address, start_balancebeam, BalanceBeam,
+    Server, TempFile, Upstream,
+};
+use std::time::{Duration, Instant};
+
+async fn setup(upstream: &str, extra_args: &[&str]) -> (BalanceBeam, TempFile, String) {
+    init_logging();
+    let config_file = TempFile::new(
+        "config.json",
+        r#"{"routes": [
+            {"path_prefix": "/flaky", "faults": {"abort": {"percent": 100, "status": 503}}},
+            {"path_prefix": "/slow", "faults": {"delay": {"percent": 100, "ms": 500}}},
+            {"path_prefix": "/api"}
+        ]}"#,
+    );
+    let admin_address = random_address();
+    let mut args = vec![
+        "--upstream",
+        upstream,
+        "--config",
+        config_file.path_str(),
+        "--admin-bind",
+        &admin_address,
+    ];
+    args.extend_from_slice(extra_args);
+    let balancebeam = start_balancebeam(&args).await;
+    (balancebeam, config_file, admin_address)
+}
+
+async fn get(
+    balancebeam: &BalanceBeam,
+    path: &str,
+    headers: &[(&str, &str)],
+) -> Result<reqwest::Response, reqwest::Error> {
+    let mut request = reqwest::Client::new().get(format!("http://{}{}", balancebeam.address, path));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request.send().await
+}
+
+/// Requests on routes with faults should be delayed or aborted as configured.
+#[tokio::test]
+async fn test_route_faults() {
+    let upstream = Upstream::new().await;
+    let (balancebeam, _config_file, admin_address) = setup(&upstream.address, &[]).await;
+
+    let response = get(&balancebeam, "/flaky", &[])
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 503);
+
+    let started = Instant::now();
+    let response = get(&balancebeam, "/slow", &[])
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(started.elapsed() >= Duration::from_millis(500));
+
+    let response = get(&balancebeam, "/api", &[])
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+
+    let metrics = get_metrics(&admin_address).await;
+    assert!(
+        metrics.contains("balancebeam_faults_injected_total{route=\"/flaky\",fault=\"abort\"} 1\n")
+    );
+    assert!(
+        metrics.contains("balancebeam_faults_injected_total{route=\"/slow\",fault=\"delay\"} 1\n")
+    );
+    // The aborted request never reached the upstream
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// Faults should be able to be changed through the admin interface, and put back again.
+#[tokio::test]
+async fn test_admin_faults() {
+    let upstream = Upstream::new().await;
+    let (balancebeam, _config_file, admin_address) = setup(&upstream.address, &[]).await;
+
+    let (status, faults) = admin_request(&admin_address, reqwest::Method::GET, "/faults", "").await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        faults,
+        "{\"/flaky\":{\"abort\":{\"percent\":100.0,\"status\":503}},\
+         \"/slow\":{\"delay\":{\"percent\":100.0,\"ms\":500}}}\n"
+    );
+
+    log::info!("Resetting connections on /api");
+    let (status, _) = admin_request(
+        &admin_address,
+        reqwest::Method::PUT,
+        "/faults?route=/api",
+        r#"{"abort": {"percent": 100, "reset": true}}"#,
+    )
+    .await;
+    assert_eq!(status, 200);
+    let error = get(&balancebeam, "/api", &[])
+        .await
+        .expect_err("Connection wasn't reset");
+    log::info!("Request failed as expected: {:?}", error);
+
+    log::info!("Turning faults off for /flaky");
+    assert_eq!(
+        admin_request(
+            &admin_address,
+            reqwest::Method::PUT,
+            "/faults?route=%2Fflaky",
+            "{}"
+        )
+        .await
+        .0,
+        200
+    );
+    let response = get(&balancebeam, "/flaky", &[])
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+
+    log::info!("Going back to the config file's faults");
+    assert_eq!(
+        admin_request(
+            &admin_address,
+            reqwest::Method::DELETE,
+            "/faults?route=/api",
+            ""
+        )
+        .await
+        .0,
+        200
+    );
+    let response = get(&balancebeam, "/api", &[])
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+
+    log::info!("Refusing bad changes");
+    assert_eq!(
+        admin_request(
+            &admin_address,
+            reqwest::Method::PUT,
+            "/faults?route=/api",
+            r#"{"abort": {"percent": 150, "status": 503}}"#
+        )
+        .await
+        .0,
+        400
+    );
+    for status in [101, 204, 304, 600] {
+        assert_eq!(
+            admin_request(
+                &admin_address,
+                reqwest::Method::PUT,
+                "/faults?route=/api",
+                &format!(r#"{{"abort": {{"percent": 100, "status": {}}}}}"#, status)
+            )
+            .await
+            .0,
+            400,
+            "{}",
+            status
+        );
+    }
+    assert_eq!(
+        admin_request(
+            &admin_address,
+            reqwest::Method::PUT,
+            "/faults?route=/nope",
+            "{}"
+        )
+        .await
+        .0,
+        404
+    );
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// Clients in the allowlist should be able to ask for faults with headers. Nobody else should,
+/// and the headers shouldn't reach the upstream either way.
+#[tokio::test]
+async fn test_fault_headers() {
+    let upstream = Upstream::new().await;
+    let (balancebeam, _config_file, _) =
+        setup(&upstream.address, &["--fault-header-allow", "127.0.0.0/8"]).await;
+    let response = get(
+        &balancebeam,
+        "/api",
+        &[("x-balancebeam-fault-abort", "418")],
+    )
+    .await
+    .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 418);
+
+    let started = Instant::now();
+    let response = get(
+        &balancebeam,
+        "/api",
+        &[("x-balancebeam-fault-delay", "300")],
+    )
+    .await
+    .expect("Error sending request to balancebeam");
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert!(!response
+        .text()
+        .await
+        .unwrap()
+        .contains("x-balancebeam-fault"));
+
+    log::info!("Asking for aborts that aren't errors");
+    for status in ["101", "204", "teapot"] {
+        let response = get(
+            &balancebeam,
+            "/api",
+            &[("x-balancebeam-fault-abort", status)],
+        )
+        .await
+        .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 400, "{}", status);
+    }
+
+    log::info!("Trying from outside the allowlist");
+    let (balancebeam, _config_file, _) =
+        setup(&upstream.address, &["--fault-header-allow", "10.0.0.0/8"]).await;
+    let response = get(
+        &balancebeam,
+        "/api",
+        &[("x-balancebeam-fault-abort", "418")],
+    )
+    .await
+    .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(!response
+        .text()
+        .await
+        .unwrap()
+        .contains("x-balancebeam-fault"));
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
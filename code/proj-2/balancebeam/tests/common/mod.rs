@@ -1,14 +1,62 @@
-mod balancebeam;
-mod echo_server;
-mod error_server;
+// Each integration test crate compiles its own copy of this module and only uses some of the
+// helpers in it, so don't warn about the ones a particular test file leaves unused.
+#![allow(dead_code, unused_imports)]
+
+mod scripted_server;
 mod server;
+mod tcp_echo_server;
+mod temp_dir;
+mod temp_file;
+mod udp_echo_server;
+mod websocket_server;
 
 use std::sync;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
 
-pub use balancebeam::BalanceBeam;
-pub use echo_server::EchoServer;
-pub use error_server::ErrorServer;
+pub use balancebeam::testing::{random_address, BalanceBeam, Behavior, Upstream};
+pub use scripted_server::ScriptedServer;
 pub use server::Server;
+pub use tcp_echo_server::TcpEchoServer;
+pub use temp_dir::TempDir;
+pub use temp_file::TempFile;
+pub use udp_echo_server::UdpEchoServer;
+pub use websocket_server::WebSocketServer;
+
+/// Starts balancebeam with the given arguments, spacing its active health checks out far enough
+/// that they don't show up in the upstreams' request counts.
+pub async fn start_balancebeam(args: &[&str]) -> BalanceBeam {
+    let mut args = args.to_vec();
+    args.extend_from_slice(&["--active-health-check-interval", "1000"]);
+    BalanceBeam::new_with_args(&args).await
+}
+
+/// Sends a request to the admin interface, returning the status code and body.
+pub async fn admin_request(
+    admin_address: &str,
+    method: reqwest::Method,
+    path: &str,
+    body: &str,
+) -> (u16, String) {
+    let response = reqwest::Client::new()
+        .request(method, format!("http://{}{}", admin_address, path))
+        .body(body.to_string())
+        .send()
+        .await
+        .expect("Error sending request to the admin interface");
+    let status = response.status().as_u16();
+    let body = response.text().await.unwrap();
+    log::info!("Admin interface answered {} {}", status, body);
+    (status, body)
+}
+
+/// Fetches the metrics page from the admin interface.
+pub async fn get_metrics(admin_address: &str) -> String {
+    let (status, metrics) =
+        admin_request(admin_address, reqwest::Method::GET, "/metrics", "").await;
+    assert_eq!(status, 200);
+    metrics
+}
 
 static INIT_TESTS: sync::Once = sync::Once::new();
 
@@ -20,3 +68,21 @@ pub fn init_logging() {
             .init();
     });
 }
+
+/// Sends a raw request to balancebeam over a connection of its own and returns the status code it
+/// answers with.
+pub async fn send_raw(balancebeam: &BalanceBeam, request: &str) -> u16 {
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8_lossy(&response);
+    response
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .unwrap_or_else(|| panic!("Invalid response {:?}", response))
+}
@@ -0,0 +1,128 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use balancebeam::testing::random_address;
+use std::sync::{atomic, Arc};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+#[derive(Debug)]
+struct ServerState {
+    pub requests_received: atomic::AtomicUsize,
+}
+
+/// Reads requests off the connection until the client hangs up, answering each one with whatever
+/// bytes `respond` returns for its request line and headers.
+async fn serve_connection(
+    stream: TcpStream,
+    respond: fn(&str) -> Vec<u8>,
+    state: Arc<ServerState>,
+) {
+    let mut stream = BufReader::new(stream);
+    loop {
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            match stream.read_line(&mut line).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+            if line == "\r\n" {
+                break;
+            }
+            head.push_str(&line);
+        }
+        let content_length = head
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+        let mut body = vec![0_u8; content_length];
+        if stream.read_exact(&mut body).await.is_err() {
+            return;
+        }
+        state
+            .requests_received
+            .fetch_add(1, atomic::Ordering::SeqCst);
+        if let Err(e) = stream.get_mut().write_all(&respond(&head)).await {
+            log::error!("Error in ScriptedServer: {}", e);
+            return;
+        }
+    }
+}
+
+/// A server that answers requests with raw bytes picked by a function of the request's line and
+/// headers. This makes it possible to test responses an Upstream won't produce, like interim
+/// responses or bodies framed in unusual ways. Connections are kept open between requests.
+pub struct ScriptedServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl ScriptedServer {
+    pub async fn new(respond: fn(&str) -> Vec<u8>) -> ScriptedServer {
+        ScriptedServer::new_at_address(random_address(), respond).await
+    }
+
+    pub async fn new_at_address(
+        bind_addr_string: String,
+        respond: fn(&str) -> Vec<u8>,
+    ) -> ScriptedServer {
+        let listener = TcpListener::bind(&bind_addr_string)
+            .await
+            .expect("ScriptedServer could not bind to address");
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => {
+                            tokio::spawn(serve_connection(
+                                stream,
+                                respond,
+                                server_task_state.clone(),
+                            ));
+                        }
+                        Err(e) => log::error!("Error in ScriptedServer: {}", e),
+                    },
+                    _ = &mut shutdown_rx => break,
+                }
+            }
+        });
+
+        ScriptedServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for ScriptedServer {
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the accept loop to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("ScriptedServer server task panicked");
+
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
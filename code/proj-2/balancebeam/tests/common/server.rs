@@ -1,7 +1,19 @@
 use async_trait::async_trait;
+use balancebeam::testing::Upstream;
 
 #[async_trait]
 pub trait Server {
     async fn stop(self: Box<Self>) -> usize;
     fn address(&self) -> String;
 }
+
+#[async_trait]
+impl Server for Upstream {
+    async fn stop(self: Box<Self>) -> usize {
+        Upstream::stop(*self).await
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
@@ -0,0 +1,92 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use balancebeam::testing::random_address;
+use std::sync::{atomic, Arc};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+#[derive(Debug)]
+struct ServerState {
+    pub connections_received: atomic::AtomicUsize,
+}
+
+/// Writes every byte received on the connection straight back to the client until the client
+/// stops sending.
+async fn echo_bytes(mut stream: TcpStream) {
+    let (mut reader, mut writer) = stream.split();
+    if let Err(e) = tokio::io::copy(&mut reader, &mut writer).await {
+        log::error!("Error in TcpEchoServer: {}", e);
+    }
+}
+
+/// A server that speaks no protocol at all: it just echoes raw bytes. Unlike the other test
+/// servers, it counts connections rather than requests.
+pub struct TcpEchoServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl TcpEchoServer {
+    pub async fn new() -> TcpEchoServer {
+        TcpEchoServer::new_at_address(random_address()).await
+    }
+
+    pub async fn new_at_address(bind_addr_string: String) -> TcpEchoServer {
+        let listener = TcpListener::bind(&bind_addr_string)
+            .await
+            .expect("TcpEchoServer could not bind to address");
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            connections_received: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => {
+                            server_task_state
+                                .connections_received
+                                .fetch_add(1, atomic::Ordering::SeqCst);
+                            tokio::spawn(echo_bytes(stream));
+                        }
+                        Err(e) => log::error!("Error in TcpEchoServer: {}", e),
+                    },
+                    _ = &mut shutdown_rx => break,
+                }
+            }
+        });
+
+        TcpEchoServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for TcpEchoServer {
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the accept loop to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("TcpEchoServer server task panicked");
+
+        self.state
+            .connections_received
+            .load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
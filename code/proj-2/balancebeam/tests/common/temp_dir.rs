@@ -0,0 +1,35 @@
+use rand::Rng;
+
+/// A directory in the temporary directory (e.g. for balancebeam to serve files from), removed
+/// along with its contents when dropped.
+pub struct TempDir {
+    pub path: std::path::PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let id: u32 = rand::thread_rng().gen();
+        let dir = TempDir {
+            path: std::env::temp_dir().join(format!("balancebeam-test-{}-{}", id, name)),
+        };
+        std::fs::create_dir(&dir.path).unwrap();
+        dir
+    }
+
+    pub fn path_str(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+
+    /// Creates a file in the directory (and any directories leading up to it).
+    pub fn write(&self, relative_path: &str, contents: &[u8]) {
+        let path = self.path.join(relative_path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,35 @@
+use rand::Rng;
+
+/// A file in the temporary directory (e.g. a config file for balancebeam to load), removed when
+/// dropped.
+pub struct TempFile {
+    pub path: std::path::PathBuf,
+}
+
+impl TempFile {
+    pub fn new(name: &str, contents: &str) -> TempFile {
+        let id: u32 = rand::thread_rng().gen();
+        let file = TempFile {
+            path: std::env::temp_dir().join(format!("balancebeam-test-{}-{}", id, name)),
+        };
+        file.write(contents);
+        file
+    }
+
+    pub fn path_str(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+
+    /// Replaces the file contents atomically, so balancebeam never sees a half-written file.
+    pub fn write(&self, contents: &str) {
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, contents).unwrap();
+        std::fs::rename(&tmp_path, &self.path).unwrap();
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
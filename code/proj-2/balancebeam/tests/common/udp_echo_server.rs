@@ -0,0 +1,89 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use balancebeam::testing::random_address;
+use std::sync::{atomic, Arc};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+
+#[derive(Debug)]
+struct ServerState {
+    pub datagrams_received: atomic::AtomicUsize,
+}
+
+/// A datagram server that replies to every non-empty datagram with its own address, a "|"
+/// separator, and then the datagram it received. Including the address lets tests tell which
+/// upstream answered. Empty datagrams (such as balancebeam's health check probes) are ignored.
+pub struct UdpEchoServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl UdpEchoServer {
+    pub async fn new() -> UdpEchoServer {
+        UdpEchoServer::new_at_address(random_address()).await
+    }
+
+    pub async fn new_at_address(bind_addr_string: String) -> UdpEchoServer {
+        let socket = UdpSocket::bind(&bind_addr_string)
+            .await
+            .expect("UdpEchoServer could not bind to address");
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            datagrams_received: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let reply_prefix = format!("{}|", bind_addr_string);
+        let server_task = tokio::spawn(async move {
+            let mut buffer = [0_u8; 65535];
+            loop {
+                tokio::select! {
+                    received = socket.recv_from(&mut buffer) => match received {
+                        Ok((0, _)) => {}
+                        Ok((len, client_addr)) => {
+                            server_task_state
+                                .datagrams_received
+                                .fetch_add(1, atomic::Ordering::SeqCst);
+                            let mut reply = reply_prefix.as_bytes().to_vec();
+                            reply.extend_from_slice(&buffer[..len]);
+                            if let Err(e) = socket.send_to(&reply, client_addr).await {
+                                log::error!("Error in UdpEchoServer: {}", e);
+                            }
+                        }
+                        Err(e) => log::error!("Error in UdpEchoServer: {}", e),
+                    },
+                    _ = &mut shutdown_rx => break,
+                }
+            }
+        });
+
+        UdpEchoServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for UdpEchoServer {
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the receive loop to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("UdpEchoServer server task panicked");
+
+        self.state.datagrams_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
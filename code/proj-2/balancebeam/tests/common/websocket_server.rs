@@ -0,0 +1,103 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use balancebeam::testing::random_address;
+use futures_util::{SinkExt, StreamExt};
+use std::sync::{atomic, Arc};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+#[derive(Debug)]
+struct ServerState {
+    pub requests_received: atomic::AtomicUsize,
+}
+
+/// Completes the WebSocket handshake on the given connection, then sends every data message it
+/// receives straight back to the client until the client closes the connection.
+async fn echo_messages(server_state: Arc<ServerState>, stream: TcpStream) {
+    let mut websocket = match tokio_tungstenite::accept_async(stream).await {
+        Ok(websocket) => websocket,
+        Err(e) => {
+            log::error!("WebSocket handshake failed in WebSocketServer: {}", e);
+            return;
+        }
+    };
+    server_state
+        .requests_received
+        .fetch_add(1, atomic::Ordering::SeqCst);
+    while let Some(Ok(message)) = websocket.next().await {
+        if message.is_text() || message.is_binary() {
+            if websocket.send(message).await.is_err() {
+                break;
+            }
+        } else if message.is_close() {
+            break;
+        }
+    }
+}
+
+pub struct WebSocketServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl WebSocketServer {
+    #[allow(dead_code)]
+    pub async fn new() -> WebSocketServer {
+        WebSocketServer::new_at_address(random_address()).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn new_at_address(bind_addr_string: String) -> WebSocketServer {
+        let listener = TcpListener::bind(&bind_addr_string)
+            .await
+            .expect("WebSocketServer could not bind to address");
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => {
+                            tokio::spawn(echo_messages(server_task_state.clone(), stream));
+                        }
+                        Err(e) => log::error!("Error in WebSocketServer: {}", e),
+                    },
+                    _ = &mut shutdown_rx => break,
+                }
+            }
+        });
+
+        WebSocketServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for WebSocketServer {
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the accept loop to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("WebSocketServer server task panicked");
+
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}